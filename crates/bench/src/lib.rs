@@ -0,0 +1,532 @@
+use clap::Parser;
+use hdrhistogram::Histogram;
+use load_balancer::{LoadBalancerBuilder, Policy};
+use loadgen::report::Report;
+use prequal::prober;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tokio::time::{interval, Instant, MissedTickBehavior};
+use tonic::transport::{Channel, Endpoint};
+
+/// The scenario run when none is given
+pub const DEFAULT_SCENARIO: &str = include_str!("../scenario.example.toml");
+
+/// How often the RIF of every backend is sampled
+pub const RIF_SAMPLE_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Antagonists run until the workload is done, then they are stopped
+const ANTAGONIST_DURATION: Duration = Duration::from_secs(24 * 3600);
+
+#[derive(Error, Debug)]
+pub enum BenchError {
+    #[error("Invalid scenario `{0}`")]
+    InvalidScenario(String),
+    #[error("Unable to start the backends `{0}`")]
+    Backend(String),
+    #[error("Unable to start the load balancer `{0}`")]
+    LoadBalancer(String),
+    #[error("The load generator failed `{0}`")]
+    Loadgen(#[from] loadgen::LoadgenError),
+    #[error("Unable to write the results `{0}`")]
+    Output(String),
+}
+
+fn duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    humantime::parse_duration(&value).map_err(serde::de::Error::custom)
+}
+
+fn optional_duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+    duration(deserializer).map(Some)
+}
+
+fn default_policies() -> Vec<Policy> {
+    vec![Policy::Prequal, Policy::RoundRobin, Policy::Random]
+}
+fn default_seed() -> u64 {
+    1
+}
+fn default_warmup() -> Duration {
+    Duration::from_secs(5)
+}
+fn default_duration() -> Duration {
+    Duration::from_secs(30)
+}
+fn default_concurrency() -> u32 {
+    512
+}
+fn default_count() -> usize {
+    1
+}
+
+/**
+Backends started with the same backend-sim flags, see its `--help`
+*/
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct BackendGroup {
+    #[serde(default = "default_count")]
+    pub count: usize,
+    pub latency: Option<String>,
+    pub capacity: Option<String>,
+    pub speed: Option<f64>,
+}
+
+/**
+Load sent straight to a backend for the whole run, bypassing the load balancer
+*/
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Antagonist {
+    /// Index of the backend, counting through the groups in order
+    pub backend: usize,
+    pub qps: f64,
+}
+
+/**
+Backends, workload and policies of a benchmark, see `scenario.example.toml`
+*/
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Scenario {
+    #[serde(default = "default_policies")]
+    pub policies: Vec<Policy>,
+    /// Seeds the workload and the antagonists, which are the same for every policy
+    #[serde(default = "default_seed")]
+    pub seed: u64,
+    #[serde(default = "default_warmup", deserialize_with = "duration")]
+    pub warmup: Duration,
+    #[serde(default = "default_duration", deserialize_with = "duration")]
+    pub duration: Duration,
+    /// Open loop rate of the workload, unless a profile is given
+    #[serde(default)]
+    pub qps: f64,
+    /// The most requests of the workload in flight
+    #[serde(default = "default_concurrency")]
+    pub concurrency: u32,
+    /// Workload profile of loadgen, relative to the scenario file
+    pub profile: Option<PathBuf>,
+    pub q_rif: Option<f32>,
+    #[serde(default, deserialize_with = "optional_duration")]
+    pub probe_interval: Option<Duration>,
+    pub probe_pool_size: Option<usize>,
+    pub backends: Vec<BackendGroup>,
+    #[serde(default)]
+    pub antagonists: Vec<Antagonist>,
+}
+
+impl Scenario {
+    pub fn from_file(path: &Path) -> Result<Self, BenchError> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|error| BenchError::InvalidScenario(format!("{}: {error}", path.display())))?;
+        Self::parse(&contents, path.parent().unwrap_or(Path::new(".")))
+            .map_err(|error| BenchError::InvalidScenario(format!("{}: {error}", path.display())))
+    }
+    /**
+    Parses and validates a scenario, a relative profile path is resolved from `dir`
+    */
+    pub fn parse(contents: &str, dir: &Path) -> Result<Self, String> {
+        let mut scenario: Scenario = toml::from_str(contents).map_err(|error| error.to_string())?;
+        scenario.profile = scenario.profile.map(|profile| dir.join(profile));
+        scenario.validate()?;
+        Ok(scenario)
+    }
+    fn validate(&self) -> Result<(), String> {
+        if self.policies.is_empty() {
+            return Err("no policy to compare".to_string());
+        }
+        if self.profile.is_none() && !(self.qps.is_finite() && self.qps > 0.0) {
+            return Err("the workload needs a positive qps or a profile".to_string());
+        }
+        let backends = self.backend_args()?;
+        if backends.is_empty() {
+            return Err("no backends".to_string());
+        }
+        for antagonist in &self.antagonists {
+            if antagonist.backend >= backends.len() {
+                return Err(format!("the antagonist of backend {} which does not exist", antagonist.backend));
+            }
+            if !(antagonist.qps.is_finite() && antagonist.qps > 0.0) {
+                return Err(format!("the antagonist of backend {} needs a positive qps", antagonist.backend));
+            }
+        }
+        Ok(())
+    }
+    /**
+    The flags of every backend, named `backend-<index>`
+    */
+    pub fn backend_args(&self) -> Result<Vec<backend_sim::Args>, String> {
+        let mut backends = vec![];
+        for group in &self.backends {
+            for _ in 0..group.count {
+                let mut flags = vec!["backend-sim".to_string(), "--id".to_string(), format!("backend-{}", backends.len())];
+                let optional = [
+                    ("--latency", group.latency.clone()),
+                    ("--capacity", group.capacity.clone()),
+                    ("--speed", group.speed.map(|speed| speed.to_string())),
+                ];
+                for (flag, value) in optional {
+                    if let Some(value) = value {
+                        flags.extend([flag.to_string(), value]);
+                    }
+                }
+                let args = backend_sim::Args::try_parse_from(&flags).map_err(|error| error.to_string())?;
+                backends.push(args);
+            }
+        }
+        Ok(backends)
+    }
+    pub fn antagonist_qps(&self, backend: usize) -> f64 {
+        self.antagonists
+            .iter()
+            .filter(|antagonist| antagonist.backend == backend)
+            .fold(0.0, |qps, antagonist| qps + antagonist.qps)
+    }
+}
+
+/**
+Requests in flight on a backend, sampled every 10ms while the workload is measured.
+Antagonist requests count too, they take the capacity of the backend as well.
+*/
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RifStats {
+    pub mean: f64,
+    pub p50: u64,
+    pub p99: u64,
+    pub max: u64,
+}
+
+impl RifStats {
+    pub fn from_samples(samples: &Histogram<u64>) -> Self {
+        Self {
+            mean: samples.mean(),
+            p50: samples.value_at_quantile(0.5),
+            p99: samples.value_at_quantile(0.99),
+            max: samples.max(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BackendRun {
+    pub id: String,
+    pub speed: f64,
+    pub antagonist_qps: f64,
+    /// Successful requests of the workload it served
+    pub requests: u64,
+    pub rif: RifStats,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PolicyRun {
+    pub policy: Policy,
+    pub report: Report,
+    pub backends: Vec<BackendRun>,
+}
+
+/**
+Results of every policy under the same workload
+*/
+#[derive(Debug, Clone, Serialize)]
+pub struct Comparison {
+    pub seed: u64,
+    pub runs: Vec<PolicyRun>,
+}
+
+pub fn policy_name(policy: Policy) -> String {
+    serde_json::to_value(policy)
+        .ok()
+        .and_then(|value| value.as_str().map(str::to_string))
+        .unwrap_or_else(|| format!("{policy:?}"))
+}
+
+/**
+Runs the scenario once per policy, one after another
+*/
+pub async fn run(scenario: &Scenario) -> Result<Comparison, BenchError> {
+    let mut runs = vec![];
+    for policy in &scenario.policies {
+        tracing::warn!("Benchmarking {}", policy_name(*policy));
+        runs.push(run_policy(scenario, *policy).await?);
+    }
+    Ok(Comparison {
+        seed: scenario.seed,
+        runs,
+    })
+}
+
+/**
+Starts fresh backends and a load balancer with the policy, drives the workload through it
+and samples the RIF of the backends meanwhile
+*/
+pub async fn run_policy(scenario: &Scenario, policy: Policy) -> Result<PolicyRun, BenchError> {
+    let backend_args = scenario.backend_args().map_err(BenchError::InvalidScenario)?;
+    let mut backends = vec![];
+    let mut urls = vec![];
+    for args in &backend_args {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .map_err(|error| BenchError::Backend(error.to_string()))?;
+        let addr = listener.local_addr().map_err(|error| BenchError::Backend(error.to_string()))?;
+        urls.push(format!("http://{addr}"));
+        backends.push(tokio::spawn(backend_sim::serve(args.clone(), listener)));
+    }
+
+    let mut builder = LoadBalancerBuilder::new()
+        .backends(urls.clone())
+        .policy(policy)
+        .listen_addr(([127, 0, 0, 1], 0).into())
+        .grace_period(Duration::from_secs(1));
+    if let Some(q_rif) = scenario.q_rif {
+        builder = builder.q_rif(q_rif);
+    }
+    if let Some(probe_interval) = scenario.probe_interval {
+        builder = builder.probe_interval(probe_interval);
+    }
+    if let Some(probe_pool_size) = scenario.probe_pool_size {
+        builder = builder.probe_pool_size(probe_pool_size);
+    }
+    let load_balancer = builder
+        .build()
+        .await
+        .map_err(|error| BenchError::LoadBalancer(error.to_string()))?;
+    let serving = tokio::spawn({
+        let load_balancer = load_balancer.clone();
+        async move { load_balancer.serve().await }
+    });
+
+    let antagonists = scenario
+        .antagonists
+        .iter()
+        .enumerate()
+        .map(|(index, antagonist)| {
+            let mut args = loadgen::Args::parse_from(["loadgen"]);
+            args.target = urls[antagonist.backend].clone();
+            args.qps = antagonist.qps;
+            args.concurrency = scenario.concurrency;
+            args.warmup = Duration::ZERO;
+            args.duration = ANTAGONIST_DURATION;
+            args.seed = Some(scenario.seed.wrapping_add(1 + index as u64));
+            tokio::spawn(async move { loadgen::run(&args).await })
+        })
+        .collect::<Vec<_>>();
+
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let channels = urls
+        .iter()
+        .map(|url| Endpoint::from_shared(url.clone()).map(|endpoint| endpoint.connect_lazy()))
+        .collect::<Result<Vec<Channel>, _>>()
+        .map_err(|error| BenchError::Backend(error.to_string()))?;
+    let sampler = tokio::spawn(sample_rif(channels, Instant::now() + scenario.warmup, stop_rx));
+
+    let mut args = loadgen::Args::parse_from(["loadgen"]);
+    args.target = format!("http://{}", load_balancer.local_addr());
+    args.qps = scenario.qps;
+    args.concurrency = scenario.concurrency;
+    args.warmup = scenario.warmup;
+    args.duration = scenario.duration;
+    args.profile = scenario.profile.clone();
+    args.seed = Some(scenario.seed);
+    args.label = policy_name(policy);
+    let report = loadgen::run(&args).await;
+
+    stop_tx.send_replace(true);
+    let samples = sampler.await.unwrap_or_default();
+    for antagonist in antagonists {
+        antagonist.abort();
+    }
+    load_balancer.shutdown();
+    let _ = serving.await;
+    for backend in backends {
+        backend.abort();
+    }
+    let report = report?;
+
+    let backends = backend_args
+        .iter()
+        .zip(samples)
+        .enumerate()
+        .map(|(index, (args, samples))| BackendRun {
+            id: args.id.clone(),
+            speed: args.speed,
+            antagonist_qps: scenario.antagonist_qps(index),
+            requests: report.backends.get(&args.id).copied().unwrap_or_default(),
+            rif: RifStats::from_samples(&samples),
+        })
+        .collect();
+    Ok(PolicyRun { policy, report, backends })
+}
+
+/**
+Probes every backend until stopped, samples taken before `from` are left out
+*/
+async fn sample_rif(channels: Vec<Channel>, from: Instant, mut stop: watch::Receiver<bool>) -> Vec<Histogram<u64>> {
+    let mut samples = channels
+        .iter()
+        .map(|_| Histogram::new(3).expect("3 significant figures are supported"))
+        .collect::<Vec<Histogram<u64>>>();
+    let mut ticks = interval(RIF_SAMPLE_INTERVAL);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    loop {
+        tokio::select! {
+            _ = stop.wait_for(|stop| *stop) => break,
+            _ = ticks.tick() => {}
+        }
+        if Instant::now() < from {
+            continue;
+        }
+        for (index, channel) in channels.iter().enumerate() {
+            if let Ok(metric) = prober::probe(channel.clone()).await {
+                let _ = samples[index].record(metric.rif as u64);
+            }
+        }
+    }
+    samples
+}
+
+impl Comparison {
+    pub fn write_json(&self, path: &Path) -> Result<(), BenchError> {
+        let file = std::fs::File::create(path).map_err(|error| BenchError::Output(format!("{}: {error}", path.display())))?;
+        serde_json::to_writer_pretty(file, self).map_err(|error| BenchError::Output(format!("{}: {error}", path.display())))
+    }
+    /**
+    Appends the workload results of every policy, labelled with the policy
+    */
+    pub fn append_csv(&self, path: &Path) -> Result<(), BenchError> {
+        for run in &self.runs {
+            run.report
+                .append_csv(path)
+                .map_err(|error| BenchError::Output(error.to_string()))?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<12} {:>8} {:>9} {:>9} {:>9} {:>9} {:>9} {:>7} {:>8}",
+            "POLICY", "QPS", "P50_MS", "P90_MS", "P99_MS", "P99.9_MS", "MAX_MS", "FAILED", "DROPPED"
+        )?;
+        for run in &self.runs {
+            let (report, latency) = (&run.report, &run.report.latency_ms);
+            writeln!(
+                f,
+                "{:<12} {:>8.1} {:>9.3} {:>9.3} {:>9.3} {:>9.3} {:>9.3} {:>7} {:>8}",
+                policy_name(run.policy),
+                report.achieved_qps,
+                latency.p50,
+                latency.p90,
+                latency.p99,
+                latency.p999,
+                latency.max,
+                report.failed(),
+                report.dropped
+            )?;
+        }
+        for run in &self.runs {
+            writeln!(f)?;
+            writeln!(f, "{}", policy_name(run.policy))?;
+            writeln!(
+                f,
+                "  {:<12} {:>6} {:>11} {:>9} {:>7} {:>9} {:>8} {:>8} {:>8}",
+                "BACKEND", "SPEED", "ANTAGONIST", "REQUESTS", "SHARE", "RIF_MEAN", "RIF_P50", "RIF_P99", "RIF_MAX"
+            )?;
+            for backend in &run.backends {
+                let share = backend.requests as f64 * 100.0 / run.report.succeeded.max(1) as f64;
+                writeln!(
+                    f,
+                    "  {:<12} {:>6.2} {:>11.1} {:>9} {:>6.1}% {:>9.2} {:>8} {:>8} {:>8}",
+                    backend.id,
+                    backend.speed,
+                    backend.antagonist_qps,
+                    backend.requests,
+                    share,
+                    backend.rif.mean,
+                    backend.rif.p50,
+                    backend.rif.p99,
+                    backend.rif.max
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_scenario() {
+        let scenario = Scenario::parse(DEFAULT_SCENARIO, Path::new(".")).unwrap();
+        assert_eq!(scenario.policies, default_policies());
+        let backends = scenario.backend_args().unwrap();
+        assert_eq!(backends.len(), 4);
+        assert_eq!(backends[3].id, "backend-3");
+        assert_eq!(backends[3].speed, 0.4);
+        assert_eq!(scenario.antagonist_qps(0), 450.0);
+        assert_eq!(scenario.antagonist_qps(1), 0.0);
+    }
+
+    #[test]
+    fn test_invalid_scenarios() {
+        let invalid = |scenario: &str| Scenario::parse(scenario, Path::new(".")).is_err();
+        assert!(invalid("qps = 10\nbackends = []"));
+        assert!(invalid("backends = [{ count = 2 }]"));
+        assert!(invalid("qps = 10\npolicies = []\nbackends = [{ count = 2 }]"));
+        assert!(invalid("qps = 10\npolicies = [\"fastest\"]\nbackends = [{ count = 2 }]"));
+        assert!(invalid("qps = 10\nbackends = [{ count = 2, latency = \"gamma:3\" }]"));
+        assert!(invalid("qps = 10\nbackends = [{ count = 2 }]\nantagonists = [{ backend = 2, qps = 5 }]"));
+        assert!(!invalid("qps = 10\nbackends = [{ count = 2 }]\nantagonists = [{ backend = 1, qps = 5 }]"));
+    }
+
+    #[tokio::test]
+    async fn test_compare_policies() {
+        let scenario = r#"
+            policies = ["prequal", "round_robin"]
+            warmup = "300ms"
+            duration = "500ms"
+            qps = 200
+            concurrency = 64
+            probe_interval = "20ms"
+
+            [[backends]]
+            count = 2
+            latency = "fixed:2"
+            capacity = "workers:2"
+
+            [[antagonists]]
+            backend = 1
+            qps = 100
+        "#;
+        let scenario = Scenario::parse(scenario, Path::new(".")).unwrap();
+        let comparison = run(&scenario).await.unwrap();
+        assert_eq!(comparison.runs.len(), 2);
+        for run in &comparison.runs {
+            assert!(run.report.succeeded > 50, "{comparison}");
+            assert_eq!(run.backends.len(), 2);
+            assert_eq!(run.backends.iter().map(|backend| backend.requests).sum::<u64>(), run.report.succeeded);
+            assert_eq!(run.backends[1].antagonist_qps, 100.0);
+        }
+        // Round robin splits the workload evenly whatever the antagonist does
+        let round_robin = &comparison.runs[1].backends;
+        assert!(round_robin[0].requests.abs_diff(round_robin[1].requests) <= 2, "{comparison}");
+
+        let dir = tempfile::tempdir().unwrap();
+        comparison.write_json(&dir.path().join("bench.json")).unwrap();
+        comparison.append_csv(&dir.path().join("bench.csv")).unwrap();
+        let csv = std::fs::read_to_string(dir.path().join("bench.csv")).unwrap();
+        assert_eq!(csv.lines().count(), 3);
+        assert!(csv.lines().nth(2).unwrap().starts_with("round_robin,"));
+        let table = comparison.to_string();
+        assert!(table.lines().nth(1).unwrap().starts_with("prequal"));
+        assert!(table.contains("  backend-1"));
+    }
+}
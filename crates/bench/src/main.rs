@@ -0,0 +1,43 @@
+use bench::{Scenario, DEFAULT_SCENARIO};
+use clap::Parser;
+use std::path::{Path, PathBuf};
+use tracing::Level;
+
+/**
+Compares the policies of the load balancer on simulated backends, all in this process
+*/
+#[derive(Parser, Debug)]
+#[command(name = "bench", version)]
+struct Cli {
+    /// Scenario file, scenario.example.toml when not given
+    #[arg(long)]
+    scenario: Option<PathBuf>,
+    /// Writes the comparison as JSON
+    #[arg(long)]
+    json: Option<PathBuf>,
+    /// Appends the workload results of every policy as CSV rows
+    #[arg(long)]
+    csv: Option<PathBuf>,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let cli = Cli::parse();
+    // The load balancer and the backends log every request at the info level
+    let subscriber = tracing_subscriber::FmtSubscriber::builder().with_max_level(Level::WARN).finish();
+    tracing::subscriber::set_global_default(subscriber)?;
+
+    let scenario = match &cli.scenario {
+        Some(path) => Scenario::from_file(path)?,
+        None => Scenario::parse(DEFAULT_SCENARIO, Path::new(env!("CARGO_MANIFEST_DIR")))?,
+    };
+    let comparison = bench::run(&scenario).await?;
+    print!("{comparison}");
+    if let Some(path) = &cli.json {
+        comparison.write_json(path)?;
+    }
+    if let Some(path) = &cli.csv {
+        comparison.append_csv(path)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,356 @@
+pub mod profile;
+pub mod report;
+
+use clap::Parser;
+use hello_world::greeter_client::GreeterClient;
+use hello_world::HelloRequest;
+use profile::{Profile, RequestShape, SizeModel};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rand_distr::{Distribution, Exp};
+use report::{Recorder, Report};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tokio::time::{sleep_until, timeout, Instant};
+use tonic::transport::{Channel, Endpoint};
+use tonic::Code;
+
+pub mod hello_world {
+    tonic::include_proto!("helloworld");
+}
+
+#[derive(Error, Debug)]
+pub enum LoadgenError {
+    #[error("Invalid target `{0}`")]
+    InvalidTarget(String),
+    #[error("Unable to connect to the target `{0}`")]
+    Unreachable(String),
+    #[error("Unable to write the results `{0}`")]
+    Output(String),
+    #[error("Invalid workload profile `{0}`")]
+    InvalidProfile(String),
+}
+
+/**
+Sends SayHello requests to the load balancer, or to a single backend, and reports their latency
+*/
+#[derive(Parser, Debug, Clone)]
+#[command(name = "loadgen", version)]
+pub struct Args {
+    #[arg(long, env = "LOADGEN_TARGET", default_value = "http://[::1]:50051")]
+    pub target: String,
+    /// Requests per second with Poisson arrivals. 0 runs a closed loop where every worker sends its next request once the previous one completed
+    #[arg(long, default_value_t = 0.0, value_parser = parse_qps)]
+    pub qps: f64,
+    /// Workers of the closed loop, or the most requests in flight of the open loop. Arrivals beyond it are dropped and counted
+    #[arg(long, default_value_t = 5, value_parser = clap::value_parser!(u32).range(1..))]
+    pub concurrency: u32,
+    /// Measured time, after the warmup
+    #[arg(long, default_value = "30s", value_parser = humantime::parse_duration)]
+    pub duration: Duration,
+    /// Requests sent during the warmup are not recorded
+    #[arg(long, default_value = "5s", value_parser = humantime::parse_duration)]
+    pub warmup: Duration,
+    /// Requests taking longer fail with DeadlineExceeded
+    #[arg(long, default_value = "1s", value_parser = humantime::parse_duration)]
+    pub timeout: Duration,
+    /// Sends a workload profile instead of a constant rate, see [`profile::Profile`]. The warmup is its beginning
+    #[arg(long, conflicts_with_all = ["qps", "duration"])]
+    pub profile: Option<PathBuf>,
+    /// Sent in every request, unless the profile names the requests
+    #[arg(long, default_value = "Rustacean")]
+    pub name: String,
+    /// Pads the name to a size in bytes: fixed:<bytes>, uniform:<min>-<max> or exp:<mean>
+    #[arg(long, default_value_t = SizeModel::default())]
+    pub payload_size: SizeModel,
+    /// Seeds the arrivals, runs with the same seed send on the same schedule
+    #[arg(long)]
+    pub seed: Option<u64>,
+    /// Names the run in the results
+    #[arg(long, default_value = "run")]
+    pub label: String,
+    /// Writes the results as JSON
+    #[arg(long)]
+    pub json: Option<PathBuf>,
+    /// Appends the results as a CSV row, the header is written when the file is new
+    #[arg(long)]
+    pub csv: Option<PathBuf>,
+}
+
+fn parse_qps(value: &str) -> Result<f64, String> {
+    value
+        .parse::<f64>()
+        .ok()
+        .filter(|qps| qps.is_finite() && *qps >= 0.0)
+        .ok_or_else(|| format!("`{value}` should be a number of requests per second"))
+}
+
+fn seeded(seed: Option<u64>) -> StdRng {
+    match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    }
+}
+
+/**
+Offsets from the start of the run at which the requests of a Poisson process arrive
+*/
+pub struct Arrivals {
+    gaps: Exp<f64>,
+    rng: StdRng,
+    offset: Duration,
+}
+
+impl Arrivals {
+    /**
+    The rate must be positive
+    */
+    pub fn new(qps: f64, seed: Option<u64>) -> Self {
+        Self::from_rng(qps, seeded(seed))
+    }
+    pub fn from_rng(qps: f64, rng: StdRng) -> Self {
+        Self {
+            gaps: Exp::new(qps).expect("the rate is positive"),
+            rng,
+            offset: Duration::ZERO,
+        }
+    }
+}
+
+impl Iterator for Arrivals {
+    type Item = Duration;
+
+    fn next(&mut self) -> Option<Duration> {
+        self.offset += Duration::from_secs_f64(self.gaps.sample(&mut self.rng));
+        Some(self.offset)
+    }
+}
+
+/**
+The backend named at the end of a reply, backend-sim answers "Hello <name>! from <id>"
+*/
+fn backend(message: &str) -> &str {
+    message.rsplit_once(" from ").map(|(_, backend)| backend).unwrap_or("unknown")
+}
+
+async fn send(mut client: GreeterClient<Channel>, name: String, limit: Duration, recorder: Option<Arc<Mutex<Recorder>>>) {
+    let mut request = tonic::Request::new(HelloRequest { name });
+    request.set_timeout(limit);
+    let start = Instant::now();
+    let outcome = timeout(limit, client.say_hello(request)).await;
+    let latency = start.elapsed();
+    let Some(recorder) = recorder else {
+        return;
+    };
+    let mut recorder = recorder.lock().unwrap();
+    match outcome {
+        Ok(Ok(response)) => recorder.record(latency, Ok(backend(&response.into_inner().message))),
+        Ok(Err(status)) => recorder.record(latency, Err(status.code())),
+        Err(_) => recorder.record(latency, Err(Code::DeadlineExceeded)),
+    }
+}
+
+/**
+Runs the warmup and the measured duration, then waits for the requests in flight.
+With a profile, or a rate, the requests arrive on their schedule whether the previous ones completed or not.
+*/
+pub async fn run(args: &Args) -> Result<Report, LoadgenError> {
+    let profile = match &args.profile {
+        Some(path) => Some(Profile::from_file(path)?),
+        None if args.qps > 0.0 => Some(Profile::constant(args.qps, args.warmup + args.duration)),
+        None => None,
+    };
+    let (duration, target_qps) = match &profile {
+        Some(profile) if profile.duration() <= args.warmup => {
+            return Err(LoadgenError::InvalidProfile(format!(
+                "the profile lasts {:?}, not longer than the warmup",
+                profile.duration()
+            )))
+        }
+        Some(profile) => (profile.duration() - args.warmup, profile.mean_rate(args.warmup)),
+        None => (args.duration, 0.0),
+    };
+    let shape = profile
+        .as_ref()
+        .and_then(|profile| profile.request.clone())
+        .unwrap_or_else(|| RequestShape::new(&args.name, args.payload_size));
+
+    let endpoint = Endpoint::from_shared(args.target.clone())
+        .map_err(|error| LoadgenError::InvalidTarget(format!("{}: {error}", args.target)))?;
+    let channel = endpoint
+        .connect()
+        .await
+        .map_err(|error| LoadgenError::Unreachable(format!("{}: {error}", args.target)))?;
+    let client = GreeterClient::new(channel);
+    let recorder = Arc::new(Mutex::new(Recorder::default()));
+    let start = Instant::now();
+    let measured_from = start + args.warmup;
+    let end = measured_from + duration;
+    // Only the requests sent after the warmup are recorded
+    let recorder_at = |sent: Instant| (sent >= measured_from).then(|| recorder.clone());
+    // Names are drawn apart from the arrivals so the schedule only depends on the seed
+    let mut names = seeded(args.seed.map(|seed| seed.wrapping_add(1)));
+
+    let mut requests = JoinSet::new();
+    if let Some(profile) = &profile {
+        tracing::info!("Sending {:.1} requests per second on average to {} for {:?}", target_qps, args.target, duration);
+        let in_flight = Arc::new(Semaphore::new(args.concurrency as usize));
+        for offset in profile.arrivals(args.seed) {
+            let arrival = start + offset;
+            if arrival >= end {
+                break;
+            }
+            sleep_until(arrival).await;
+            // Reap the completed requests so the set only holds the ones in flight
+            while requests.try_join_next().is_some() {}
+            let Ok(permit) = in_flight.clone().try_acquire_owned() else {
+                if let Some(recorder) = recorder_at(arrival) {
+                    recorder.lock().unwrap().record_dropped();
+                }
+                continue;
+            };
+            let request = send(client.clone(), shape.sample(&mut names), args.timeout, recorder_at(arrival));
+            requests.spawn(async move {
+                request.await;
+                drop(permit);
+            });
+        }
+    } else {
+        tracing::info!("Sending from {} workers to {} for {:?}", args.concurrency, args.target, duration);
+        for _ in 0..args.concurrency {
+            let (client, shape, limit, recorder) = (client.clone(), shape.clone(), args.timeout, recorder.clone());
+            let mut names = StdRng::seed_from_u64(names.gen());
+            requests.spawn(async move {
+                loop {
+                    let sent = Instant::now();
+                    if sent >= end {
+                        break;
+                    }
+                    let recorder = (sent >= measured_from).then(|| recorder.clone());
+                    send(client.clone(), shape.sample(&mut names), limit, recorder).await;
+                }
+            });
+        }
+    }
+    while requests.join_next().await.is_some() {}
+
+    let report = recorder.lock().unwrap().report(&args.label, target_qps, duration);
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// Serves a backend-sim on an ephemeral port and returns its address
+    async fn backend_sim(flags: &[&str]) -> String {
+        let args = backend_sim::Args::parse_from(["backend-sim"].iter().chain(flags));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(backend_sim::serve(args, listener));
+        addr
+    }
+
+    fn args(target: &str, flags: &[&str]) -> Args {
+        let mut args = Args::parse_from(["loadgen", "--warmup", "100ms", "--duration", "500ms"].iter().chain(flags));
+        args.target = target.to_string();
+        args
+    }
+
+    #[test]
+    fn test_poisson_arrivals() {
+        let offsets = Arrivals::new(1000.0, Some(7)).take(10_000).collect::<Vec<Duration>>();
+        assert!(offsets.windows(2).all(|pair| pair[0] <= pair[1]));
+        // 10,000 arrivals at 1,000 qps take about 10 seconds
+        let total = offsets.last().unwrap().as_secs_f64();
+        assert!((9.5..10.5).contains(&total), "{total}");
+        // The same seed gives the same schedule
+        assert_eq!(Arrivals::new(1000.0, Some(7)).take(100).collect::<Vec<Duration>>(), offsets[..100]);
+    }
+
+    #[test]
+    fn test_backend_is_parsed_from_the_reply() {
+        assert_eq!(backend("Hello Rustacean! from server 2"), "server 2");
+        assert_eq!(backend("Hello from a friend! from fast"), "fast");
+        assert_eq!(backend("Hi"), "unknown");
+    }
+
+    #[test]
+    fn test_args_are_validated() {
+        assert!(Args::try_parse_from(["loadgen", "--qps", "-1"]).is_err());
+        assert!(Args::try_parse_from(["loadgen", "--concurrency", "0"]).is_err());
+        let args = Args::parse_from(["loadgen", "--duration", "2m", "--warmup", "500ms"]);
+        assert_eq!(args.duration, Duration::from_secs(120));
+        assert_eq!(args.warmup, Duration::from_millis(500));
+    }
+
+    #[tokio::test]
+    async fn test_open_loop_run() {
+        let target = backend_sim(&["--id", "server 7", "--latency", "fixed:1"]).await;
+        let report = run(&args(&target, &["--qps", "200", "--seed", "1"])).await.unwrap();
+        // About 100 requests after the warmup
+        assert!((60..140).contains(&report.requests), "{report}");
+        assert_eq!(report.succeeded, report.requests);
+        assert_eq!(report.dropped, 0);
+        assert_eq!(report.backends.keys().collect::<Vec<_>>(), ["server 7"]);
+        assert!(report.latency_ms.p50 >= 1.0, "{report}");
+    }
+
+    #[tokio::test]
+    async fn test_open_loop_drops_arrivals_beyond_the_concurrency() {
+        let target = backend_sim(&["--latency", "fixed:100"]).await;
+        let report = run(&args(&target, &["--qps", "200", "--concurrency", "1"])).await.unwrap();
+        assert!(report.requests <= 6, "{report}");
+        assert!(report.dropped > 50, "{report}");
+    }
+
+    #[tokio::test]
+    async fn test_profile_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("profile.toml");
+        let profile = r#"
+            [[phases]]
+            kind = "constant"
+            qps = 50
+            duration = "200ms"
+
+            [[phases]]
+            kind = "step"
+            levels = [0, 400]
+            every = "250ms"
+
+            [request]
+            names = ["alice", "bob"]
+            size = "fixed:6"
+        "#;
+        std::fs::write(&path, profile).unwrap();
+        let target = backend_sim(&["--latency", "fixed:1"]).await;
+        let mut args = Args::parse_from(["loadgen", "--warmup", "200ms", "--seed", "4"]);
+        args.target = target;
+        args.profile = Some(path);
+        let report = run(&args).await.unwrap();
+        // Nothing during the first step, about 100 requests during the second
+        assert_eq!(report.duration_secs, 0.5);
+        assert!((60..140).contains(&report.requests), "{report}");
+        assert!((report.target_qps - 200.0).abs() < 1.0, "{report}");
+        assert!(Args::try_parse_from(["loadgen", "--profile", "profile.toml", "--qps", "5"]).is_err());
+
+        args.warmup = Duration::from_secs(1);
+        assert!(matches!(run(&args).await, Err(LoadgenError::InvalidProfile(_))));
+    }
+
+    #[tokio::test]
+    async fn test_closed_loop_run_records_errors() {
+        let target = backend_sim(&["--latency", "fixed:1", "--error-rate", "14=0.5"]).await;
+        let report = run(&args(&target, &["--concurrency", "2"])).await.unwrap();
+        assert_eq!(report.target_qps, 0.0);
+        assert!(report.errors["Unavailable"] > 0, "{report}");
+        assert!(report.succeeded > 0, "{report}");
+        assert_eq!(report.requests, report.succeeded + report.errors["Unavailable"]);
+    }
+}
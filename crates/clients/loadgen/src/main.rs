@@ -0,0 +1,19 @@
+use clap::Parser;
+use loadgen::Args;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let args = Args::parse();
+    let subscriber = tracing_subscriber::FmtSubscriber::new();
+    tracing::subscriber::set_global_default(subscriber)?;
+
+    let report = loadgen::run(&args).await?;
+    print!("{report}");
+    if let Some(path) = &args.json {
+        report.write_json(path)?;
+    }
+    if let Some(path) = &args.csv {
+        report.append_csv(path)?;
+    }
+    Ok(())
+}
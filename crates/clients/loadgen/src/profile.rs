@@ -0,0 +1,625 @@
+use crate::{seeded, Arrivals, LoadgenError};
+use rand::distributions::WeightedIndex;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Deserializer};
+use std::f64::consts::TAU;
+use std::fmt;
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Step of the numerical integration of the rate, for the mean rate of a profile
+const RATE_STEP: Duration = Duration::from_millis(10);
+
+fn duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    humantime::parse_duration(&value).map_err(serde::de::Error::custom)
+}
+
+fn default_speed() -> f64 {
+    1.0
+}
+
+/**
+How the arrival rate changes during a phase of a workload profile, durations are written like `30s` or `2m`
+*/
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Phase {
+    Constant {
+        qps: f64,
+        #[serde(deserialize_with = "duration")]
+        duration: Duration,
+    },
+    /// Holds every level for `every`, one after another
+    Step {
+        levels: Vec<f64>,
+        #[serde(deserialize_with = "duration")]
+        every: Duration,
+    },
+    /// Goes linearly from one rate to the other
+    Ramp {
+        from: f64,
+        to: f64,
+        #[serde(deserialize_with = "duration")]
+        duration: Duration,
+    },
+    /// Oscillates around the mean, a diurnal curve with a period of a day
+    Sine {
+        mean: f64,
+        amplitude: f64,
+        #[serde(deserialize_with = "duration")]
+        period: Duration,
+        #[serde(deserialize_with = "duration")]
+        duration: Duration,
+    },
+    /// Runs at `burst` for `length` at the start of every `every`, at `base` otherwise
+    Bursts {
+        base: f64,
+        burst: f64,
+        #[serde(deserialize_with = "duration")]
+        every: Duration,
+        #[serde(deserialize_with = "duration")]
+        length: Duration,
+        #[serde(deserialize_with = "duration")]
+        duration: Duration,
+    },
+    /// Replays the timestamps of a recorded trace, one per line in milliseconds.
+    /// A relative path is resolved from the directory of the profile, `speed` 2 replays twice as fast.
+    Trace {
+        path: PathBuf,
+        #[serde(default = "default_speed")]
+        speed: f64,
+        /// Read from the trace when the profile is loaded
+        #[serde(skip)]
+        offsets: Vec<Duration>,
+    },
+}
+
+impl Phase {
+    pub fn duration(&self) -> Duration {
+        match self {
+            Phase::Constant { duration, .. }
+            | Phase::Ramp { duration, .. }
+            | Phase::Sine { duration, .. }
+            | Phase::Bursts { duration, .. } => *duration,
+            Phase::Step { levels, every } => *every * levels.len() as u32,
+            Phase::Trace { offsets, .. } => offsets.last().copied().unwrap_or_default(),
+        }
+    }
+    /**
+    Target arrival rate at `offset` from the start of the phase, a trace has none
+    */
+    pub fn rate_at(&self, offset: Duration) -> f64 {
+        match self {
+            Phase::Constant { qps, .. } => *qps,
+            Phase::Step { levels, every } => {
+                let index = (offset.as_secs_f64() / every.as_secs_f64()) as usize;
+                levels[index.min(levels.len() - 1)]
+            }
+            Phase::Ramp { from, to, duration } => from + (to - from) * (offset.as_secs_f64() / duration.as_secs_f64()).min(1.0),
+            Phase::Sine {
+                mean, amplitude, period, ..
+            } => (mean + amplitude * (TAU * offset.as_secs_f64() / period.as_secs_f64()).sin()).max(0.0),
+            Phase::Bursts {
+                base, burst, every, length, ..
+            } => {
+                let within = offset.as_secs_f64() % every.as_secs_f64();
+                if within < length.as_secs_f64() {
+                    *burst
+                } else {
+                    *base
+                }
+            }
+            Phase::Trace { .. } => 0.0,
+        }
+    }
+    fn peak_rate(&self) -> f64 {
+        match self {
+            Phase::Constant { qps, .. } => *qps,
+            Phase::Step { levels, .. } => levels.iter().copied().fold(0.0, f64::max),
+            Phase::Ramp { from, to, .. } => from.max(*to),
+            Phase::Sine { mean, amplitude, .. } => mean + amplitude.abs(),
+            Phase::Bursts { base, burst, .. } => base.max(*burst),
+            Phase::Trace { .. } => 0.0,
+        }
+    }
+    /**
+    Expected number of arrivals from `from` to the end of the phase
+    */
+    fn expected_arrivals(&self, from: Duration) -> f64 {
+        if let Phase::Trace { offsets, .. } = self {
+            return offsets.iter().filter(|offset| **offset >= from).count() as f64;
+        }
+        let mut offset = from;
+        let mut total = 0.0;
+        while offset < self.duration() {
+            total += self.rate_at(offset) * RATE_STEP.as_secs_f64();
+            offset += RATE_STEP;
+        }
+        total
+    }
+    fn validate(&self) -> Result<(), String> {
+        let rate = |name: &str, rate: f64| match rate.is_finite() && rate >= 0.0 {
+            true => Ok(()),
+            false => Err(format!("{name} should be a number of requests per second, not {rate}")),
+        };
+        let positive = |name: &str, duration: Duration| match duration.is_zero() {
+            true => Err(format!("{name} should be longer than 0s")),
+            false => Ok(()),
+        };
+        match self {
+            Phase::Constant { qps, duration } => rate("qps", *qps).and(positive("duration", *duration)),
+            Phase::Step { levels, every } => {
+                if levels.is_empty() {
+                    return Err("a step phase needs levels".to_string());
+                }
+                levels.iter().try_for_each(|level| rate("levels", *level))?;
+                positive("every", *every)
+            }
+            Phase::Ramp { from, to, duration } => rate("from", *from)
+                .and(rate("to", *to))
+                .and(positive("duration", *duration)),
+            Phase::Sine {
+                mean,
+                amplitude,
+                period,
+                duration,
+            } => rate("mean", *mean)
+                .and(rate("amplitude", *amplitude))
+                .and(positive("period", *period))
+                .and(positive("duration", *duration)),
+            Phase::Bursts {
+                base,
+                burst,
+                every,
+                length,
+                duration,
+            } => {
+                rate("base", *base)?;
+                rate("burst", *burst)?;
+                positive("every", *every)?;
+                positive("duration", *duration)?;
+                match length <= every {
+                    true => Ok(()),
+                    false => Err(format!("the burst length {length:?} is longer than every {every:?}")),
+                }
+            }
+            Phase::Trace { speed, .. } => match speed.is_finite() && *speed > 0.0 {
+                true => Ok(()),
+                false => Err(format!("the trace speed should be positive, not {speed}")),
+            },
+        }
+    }
+    /**
+    Reads the timestamps of a trace phase, relative to the directory of the profile
+    */
+    fn load_trace(&mut self, dir: &Path) -> Result<(), String> {
+        let Phase::Trace { path, speed, offsets } = self else {
+            return Ok(());
+        };
+        let path = dir.join(&*path);
+        let contents = std::fs::read_to_string(&path).map_err(|error| format!("{}: {error}", path.display()))?;
+        let mut timestamps = contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                line.parse::<f64>()
+                    .ok()
+                    .filter(|millis| millis.is_finite())
+                    .ok_or_else(|| format!("{}: `{line}` is not a timestamp in milliseconds", path.display()))
+            })
+            .collect::<Result<Vec<f64>, String>>()?;
+        timestamps.sort_by(f64::total_cmp);
+        let first = timestamps.first().copied().unwrap_or_default();
+        *offsets = timestamps
+            .into_iter()
+            .map(|millis| Duration::from_secs_f64((millis - first) / 1000.0 / *speed))
+            .collect();
+        Ok(())
+    }
+    /**
+    Offsets of the arrivals from the start of the phase.
+    The rate is followed by thinning a Poisson process at the peak rate.
+    */
+    fn arrivals(&self, mut rng: StdRng) -> Box<dyn Iterator<Item = Duration> + Send + '_> {
+        if let Phase::Trace { offsets, .. } = self {
+            return Box::new(offsets.iter().copied());
+        }
+        let (peak, end) = (self.peak_rate(), self.duration());
+        if peak <= 0.0 {
+            return Box::new(iter::empty());
+        }
+        let mut thinning = StdRng::seed_from_u64(rng.gen());
+        Box::new(
+            Arrivals::from_rng(peak, rng)
+                .take_while(move |offset| *offset < end)
+                .filter(move |offset| thinning.gen::<f64>() * peak < self.rate_at(*offset)),
+        )
+    }
+}
+
+/**
+Size in bytes of the name sent in requests, padded when the name is shorter:
+
+- `fixed:16` always 16 bytes
+- `uniform:0-1024` uniformly between 0 and 1024 bytes
+- `exp:256` exponentially distributed with a mean of 256 bytes
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum SizeModel {
+    Fixed(usize),
+    Uniform { min: usize, max: usize },
+    Exponential { mean: f64 },
+}
+
+impl Default for SizeModel {
+    fn default() -> Self {
+        Self::Fixed(0)
+    }
+}
+
+impl SizeModel {
+    pub fn sample(&self, rng: &mut impl Rng) -> usize {
+        match *self {
+            SizeModel::Fixed(size) => size,
+            SizeModel::Uniform { min, max } => rng.gen_range(min..=max),
+            // Inverse transform, 1 - u is never 0 so the logarithm stays finite
+            SizeModel::Exponential { mean } => (mean * -(1.0 - rng.gen::<f64>()).ln()) as usize,
+        }
+    }
+}
+
+impl FromStr for SizeModel {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let bytes = |value: &str| {
+            value
+                .trim()
+                .parse::<usize>()
+                .map_err(|_| format!("`{value}` is not a number of bytes"))
+        };
+        let (kind, params) = value
+            .split_once(':')
+            .ok_or_else(|| format!("`{value}` should look like fixed:16, uniform:0-1024 or exp:256"))?;
+        match kind {
+            "fixed" => Ok(SizeModel::Fixed(bytes(params)?)),
+            "uniform" => {
+                let (min, max) = params
+                    .split_once('-')
+                    .ok_or_else(|| format!("`{params}` should be a range like 0-1024"))?;
+                let (min, max) = (bytes(min)?, bytes(max)?);
+                if min > max {
+                    return Err(format!("the range `{params}` is empty"));
+                }
+                Ok(SizeModel::Uniform { min, max })
+            }
+            "exp" => Ok(SizeModel::Exponential {
+                mean: bytes(params)? as f64,
+            }),
+            _ => Err(format!("unknown size model `{kind}`, expected fixed, uniform or exp")),
+        }
+    }
+}
+
+impl TryFrom<String> for SizeModel {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for SizeModel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            SizeModel::Fixed(size) => write!(f, "fixed:{size}"),
+            SizeModel::Uniform { min, max } => write!(f, "uniform:{min}-{max}"),
+            SizeModel::Exponential { mean } => write!(f, "exp:{mean}"),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct RequestSpec {
+    names: Vec<String>,
+    /// One per name, every name is equally likely without
+    #[serde(default)]
+    weights: Vec<f64>,
+    #[serde(default)]
+    size: SizeModel,
+}
+
+/**
+Names sent in requests, picked by weight and padded to a size drawn from the size model
+*/
+#[derive(Deserialize, Debug, Clone)]
+#[serde(try_from = "RequestSpec")]
+pub struct RequestShape {
+    names: Vec<String>,
+    picker: WeightedIndex<f64>,
+    size: SizeModel,
+}
+
+impl TryFrom<RequestSpec> for RequestShape {
+    type Error = String;
+
+    fn try_from(spec: RequestSpec) -> Result<Self, Self::Error> {
+        let weights = match spec.weights.is_empty() {
+            true => vec![1.0; spec.names.len()],
+            false if spec.weights.len() == spec.names.len() => spec.weights,
+            false => return Err(format!("{} weights for {} names", spec.weights.len(), spec.names.len())),
+        };
+        let picker = WeightedIndex::new(weights).map_err(|error| format!("invalid names or weights: {error}"))?;
+        Ok(Self {
+            names: spec.names,
+            picker,
+            size: spec.size,
+        })
+    }
+}
+
+impl RequestShape {
+    pub fn new(name: &str, size: SizeModel) -> Self {
+        Self {
+            names: vec![name.to_string()],
+            picker: WeightedIndex::new([1.0]).expect("a single positive weight"),
+            size,
+        }
+    }
+    pub fn sample(&self, rng: &mut impl Rng) -> String {
+        let name = &self.names[rng.sample(&self.picker)];
+        let size = self.size.sample(rng);
+        format!("{name}{}", "x".repeat(size.saturating_sub(name.len())))
+    }
+}
+
+/**
+A workload profile file, the phases run one after another:
+
+```toml
+[[phases]]
+kind = "ramp"
+from = 50
+to = 500
+duration = "1m"
+
+[[phases]]
+kind = "bursts"
+base = 200
+burst = 1000
+every = "10s"
+length = "500ms"
+duration = "2m"
+
+[request]
+names = ["alice", "bob"]
+weights = [3, 1]
+size = "uniform:16-1024"
+```
+*/
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub phases: Vec<Phase>,
+    /// The names of the command line are sent without
+    pub request: Option<RequestShape>,
+}
+
+impl Profile {
+    pub fn from_file(path: &Path) -> Result<Self, LoadgenError> {
+        let invalid = |error: &dyn fmt::Display| LoadgenError::InvalidProfile(format!("{}: {error}", path.display()));
+        let contents = std::fs::read_to_string(path).map_err(|error| invalid(&error))?;
+        let mut profile: Profile = toml::from_str(&contents).map_err(|error| invalid(&error))?;
+        let dir = path.parent().unwrap_or(Path::new("."));
+        for phase in &mut profile.phases {
+            phase.load_trace(dir).map_err(|error| invalid(&error))?;
+        }
+        profile.validate().map_err(|error| invalid(&error))?;
+        Ok(profile)
+    }
+    /**
+    A single phase at a constant rate
+    */
+    pub fn constant(qps: f64, duration: Duration) -> Self {
+        Self {
+            phases: vec![Phase::Constant { qps, duration }],
+            request: None,
+        }
+    }
+    fn validate(&self) -> Result<(), String> {
+        if self.phases.is_empty() {
+            return Err("a profile needs phases".to_string());
+        }
+        self.phases.iter().try_for_each(Phase::validate)
+    }
+    pub fn duration(&self) -> Duration {
+        self.phases.iter().map(Phase::duration).sum()
+    }
+    /**
+    Target arrival rate at `offset` from the start, 0 during a trace and after the end
+    */
+    pub fn rate_at(&self, mut offset: Duration) -> f64 {
+        for phase in &self.phases {
+            if offset < phase.duration() {
+                return phase.rate_at(offset);
+            }
+            offset -= phase.duration();
+        }
+        0.0
+    }
+    /**
+    Average arrival rate from `from` to the end of the profile
+    */
+    pub fn mean_rate(&self, from: Duration) -> f64 {
+        let mut phase_start = Duration::ZERO;
+        let mut arrivals = 0.0;
+        for phase in &self.phases {
+            arrivals += phase.expected_arrivals(from.saturating_sub(phase_start));
+            phase_start += phase.duration();
+        }
+        arrivals / self.duration().saturating_sub(from).as_secs_f64().max(f64::MIN_POSITIVE)
+    }
+    /**
+    Offsets of the arrivals from the start of the run, the same seed gives the same arrivals
+    */
+    pub fn arrivals(&self, seed: Option<u64>) -> impl Iterator<Item = Duration> + '_ {
+        let mut rng = seeded(seed);
+        let mut phase_start = Duration::ZERO;
+        self.phases.iter().flat_map(move |phase| {
+            let start = phase_start;
+            phase_start += phase.duration();
+            phase
+                .arrivals(StdRng::seed_from_u64(rng.gen()))
+                .map(move |offset| start + offset)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROFILE: &str = r#"
+        [[phases]]
+        kind = "constant"
+        qps = 100
+        duration = "10s"
+
+        [[phases]]
+        kind = "step"
+        levels = [100, 400]
+        every = "5s"
+
+        [[phases]]
+        kind = "ramp"
+        from = 0
+        to = 200
+        duration = "10s"
+
+        [[phases]]
+        kind = "sine"
+        mean = 100
+        amplitude = 50
+        period = "20s"
+        duration = "20s"
+
+        [[phases]]
+        kind = "bursts"
+        base = 10
+        burst = 1000
+        every = "10s"
+        length = "1s"
+        duration = "20s"
+
+        [request]
+        names = ["alice", "bob"]
+        weights = [3, 1]
+        size = "fixed:8"
+    "#;
+
+    fn count(arrivals: &[Duration], from: u64, to: u64) -> usize {
+        let (from, to) = (Duration::from_secs(from), Duration::from_secs(to));
+        arrivals.iter().filter(|offset| (from..to).contains(offset)).count()
+    }
+
+    #[test]
+    fn test_rates_follow_the_phases() {
+        let profile: Profile = toml::from_str(PROFILE).unwrap();
+        profile.validate().unwrap();
+        assert_eq!(profile.duration(), Duration::from_secs(70));
+        let rate = |millis: u64| profile.rate_at(Duration::from_millis(millis));
+        assert_eq!(rate(9_999), 100.0);
+        assert_eq!(rate(12_000), 100.0);
+        assert_eq!(rate(17_000), 400.0);
+        assert_eq!(rate(25_000), 100.0);
+        assert_eq!(rate(35_000), 150.0);
+        assert!((rate(45_000) - 50.0).abs() < 1e-9);
+        assert_eq!(rate(50_500), 1000.0);
+        assert_eq!(rate(61_500), 10.0);
+        assert_eq!(rate(70_000), 0.0);
+        // 1000 + 2500 + 1000 + 2000 + 2 * (1000 + 90)
+        assert!((profile.mean_rate(Duration::ZERO) * 70.0 - 8680.0).abs() < 20.0);
+        // The bursts only
+        assert!((profile.mean_rate(Duration::from_secs(50)) - 109.0).abs() < 1.0);
+    }
+
+    #[test]
+    fn test_arrivals_follow_the_rate() {
+        let profile: Profile = toml::from_str(PROFILE).unwrap();
+        let arrivals = profile.arrivals(Some(3)).collect::<Vec<Duration>>();
+        assert!(arrivals.windows(2).all(|pair| pair[0] <= pair[1]));
+        let within = |count: usize, expected: f64| (count as f64 - expected).abs() < expected * 0.15;
+        assert!(within(count(&arrivals, 0, 10), 1000.0));
+        assert!(within(count(&arrivals, 15, 20), 2000.0));
+        // The ramp, a quarter of the arrivals in its first half
+        assert!(within(count(&arrivals, 20, 25), 250.0));
+        assert!(within(count(&arrivals, 25, 30), 750.0));
+        assert!(within(count(&arrivals, 50, 51), 1000.0));
+        assert!(within(count(&arrivals, 51, 60), 90.0));
+        assert_eq!(arrivals, profile.arrivals(Some(3)).collect::<Vec<Duration>>());
+    }
+
+    #[test]
+    fn test_trace_replay() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("trace.txt"), "# recorded\n1700000001000\n1700000000000\n\n1700000000500\n").unwrap();
+        let path = dir.path().join("profile.toml");
+        std::fs::write(&path, "[[phases]]\nkind = \"trace\"\npath = \"trace.txt\"\nspeed = 2\n").unwrap();
+        let profile = Profile::from_file(&path).unwrap();
+        assert_eq!(profile.duration(), Duration::from_millis(500));
+        let arrivals = profile.arrivals(None).collect::<Vec<Duration>>();
+        assert_eq!(arrivals, [Duration::ZERO, Duration::from_millis(250), Duration::from_millis(500)]);
+        assert!((profile.mean_rate(Duration::ZERO) - 6.0).abs() < 1e-9);
+        assert!((profile.mean_rate(Duration::from_millis(250)) - 8.0).abs() < 1e-9);
+
+        std::fs::write(dir.path().join("trace.txt"), "yesterday\n").unwrap();
+        assert!(matches!(Profile::from_file(&path), Err(LoadgenError::InvalidProfile(_))));
+    }
+
+    #[test]
+    fn test_example_profile() {
+        let profile = Profile::from_file(Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/profile.example.toml"))).unwrap();
+        assert_eq!(profile.duration(), Duration::from_secs(300));
+        assert!(profile.request.is_some());
+    }
+
+    #[test]
+    fn test_invalid_profiles() {
+        let invalid = |profile: &str| toml::from_str::<Profile>(profile).map_err(|error| error.to_string()).and_then(|profile| profile.validate());
+        assert!(invalid("phases = []").is_err());
+        assert!(invalid("[[phases]]\nkind = \"constant\"\nqps = -1\nduration = \"1s\"").is_err());
+        assert!(invalid("[[phases]]\nkind = \"step\"\nlevels = []\nevery = \"1s\"").is_err());
+        assert!(invalid("[[phases]]\nkind = \"bursts\"\nbase = 1\nburst = 5\nevery = \"1s\"\nlength = \"2s\"\nduration = \"5s\"").is_err());
+        assert!(invalid("[[phases]]\nkind = \"constant\"\nqps = 1\nduration = \"soon\"").is_err());
+        assert!(invalid("[[phases]]\nkind = \"diurnal\"").is_err());
+        assert!(invalid("[[phases]]\nkind = \"constant\"\nqps = 1\nduration = \"1s\"\n[request]\nnames = [\"a\"]\nweights = [1, 2]").is_err());
+        assert!(invalid("[[phases]]\nkind = \"constant\"\nqps = 1\nduration = \"1s\"").is_ok());
+    }
+
+    #[test]
+    fn test_request_names_and_sizes() {
+        assert_eq!("fixed:16".parse(), Ok(SizeModel::Fixed(16)));
+        assert_eq!("uniform:0-1024".parse(), Ok(SizeModel::Uniform { min: 0, max: 1024 }));
+        assert_eq!("exp:256".parse(), Ok(SizeModel::Exponential { mean: 256.0 }));
+        assert!("uniform:9-1".parse::<SizeModel>().is_err());
+        assert_eq!(SizeModel::Uniform { min: 1, max: 2 }.to_string(), "uniform:1-2");
+
+        let profile: Profile = toml::from_str(PROFILE).unwrap();
+        let request = profile.request.unwrap();
+        let mut rng = StdRng::seed_from_u64(5);
+        let names = (0..4000).map(|_| request.sample(&mut rng)).collect::<Vec<String>>();
+        assert!(names.iter().all(|name| name == "alicexxx" || name == "bobxxxxx"));
+        let alice = names.iter().filter(|name| name.starts_with("alice")).count();
+        assert!((2800..3200).contains(&alice), "{alice}");
+
+        let request = RequestShape::new("Rustacean", SizeModel::Fixed(4));
+        assert_eq!(request.sample(&mut rng), "Rustacean");
+    }
+}
@@ -0,0 +1,283 @@
+use crate::LoadgenError;
+use hdrhistogram::Histogram;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+use std::time::Duration;
+use tonic::Code;
+
+/**
+Outcomes of the measured requests, the ones sent after the warmup
+*/
+#[derive(Debug)]
+pub struct Recorder {
+    /// Latency of the successful requests in microseconds
+    latencies: Histogram<u64>,
+    errors: BTreeMap<String, u64>,
+    backends: BTreeMap<String, u64>,
+    dropped: u64,
+}
+
+impl Default for Recorder {
+    fn default() -> Self {
+        Self {
+            // Up to an hour with 3 significant figures, longer requests are recorded as an hour
+            latencies: Histogram::new_with_bounds(1, 3_600_000_000, 3).expect("the bounds are valid"),
+            errors: BTreeMap::new(),
+            backends: BTreeMap::new(),
+            dropped: 0,
+        }
+    }
+}
+
+impl Recorder {
+    /**
+    Records a completed request, `Ok` holds the backend which served it
+    */
+    pub fn record(&mut self, latency: Duration, outcome: Result<&str, Code>) {
+        match outcome {
+            Ok(backend) => {
+                self.latencies.saturating_record((latency.as_micros() as u64).max(1));
+                *self.backends.entry(backend.to_string()).or_insert(0) += 1;
+            }
+            Err(code) => *self.errors.entry(format!("{code:?}")).or_insert(0) += 1,
+        }
+    }
+    /**
+    Records an arrival which was not sent because too many requests were in flight
+    */
+    pub fn record_dropped(&mut self) {
+        self.dropped += 1;
+    }
+    pub fn report(&self, label: &str, target_qps: f64, duration: Duration) -> Report {
+        let succeeded = self.latencies.len();
+        let failed = self.errors.values().sum::<u64>();
+        let millis = |micros: u64| micros as f64 / 1000.0;
+        Report {
+            label: label.to_string(),
+            target_qps,
+            duration_secs: duration.as_secs_f64(),
+            requests: succeeded + failed,
+            succeeded,
+            dropped: self.dropped,
+            achieved_qps: (succeeded + failed) as f64 / duration.as_secs_f64(),
+            latency_ms: Percentiles {
+                p50: millis(self.latencies.value_at_quantile(0.5)),
+                p90: millis(self.latencies.value_at_quantile(0.9)),
+                p99: millis(self.latencies.value_at_quantile(0.99)),
+                p999: millis(self.latencies.value_at_quantile(0.999)),
+                max: millis(self.latencies.max()),
+                mean: self.latencies.mean() / 1000.0,
+            },
+            errors: self.errors.clone(),
+            backends: self.backends.clone(),
+        }
+    }
+}
+
+/**
+Latency of the successful requests in milliseconds
+*/
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Percentiles {
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+    pub p999: f64,
+    pub max: f64,
+    pub mean: f64,
+}
+
+/**
+Result of a run, written as JSON or appended as a CSV row to compare runs
+*/
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Report {
+    pub label: String,
+    /// 0 for a closed loop run
+    pub target_qps: f64,
+    /// Measured time, without the warmup
+    pub duration_secs: f64,
+    /// Sent and completed, successfully or not
+    pub requests: u64,
+    pub succeeded: u64,
+    pub dropped: u64,
+    pub achieved_qps: f64,
+    pub latency_ms: Percentiles,
+    /// Failed requests by gRPC code
+    pub errors: BTreeMap<String, u64>,
+    /// Successful requests by the backend which served them
+    pub backends: BTreeMap<String, u64>,
+}
+
+/**
+A report flattened into one CSV row, the maps become `key=count` lists
+*/
+#[derive(Debug, Serialize)]
+struct CsvRow<'a> {
+    label: &'a str,
+    target_qps: f64,
+    duration_secs: f64,
+    requests: u64,
+    succeeded: u64,
+    failed: u64,
+    dropped: u64,
+    achieved_qps: f64,
+    p50_ms: f64,
+    p90_ms: f64,
+    p99_ms: f64,
+    p999_ms: f64,
+    max_ms: f64,
+    mean_ms: f64,
+    errors: String,
+    backends: String,
+}
+
+fn join_counts(counts: &BTreeMap<String, u64>) -> String {
+    counts
+        .iter()
+        .map(|(key, count)| format!("{key}={count}"))
+        .collect::<Vec<String>>()
+        .join(";")
+}
+
+impl Report {
+    pub fn failed(&self) -> u64 {
+        self.requests - self.succeeded
+    }
+    pub fn write_json(&self, path: &Path) -> Result<(), LoadgenError> {
+        let file = File::create(path).map_err(|error| LoadgenError::Output(format!("{}: {error}", path.display())))?;
+        serde_json::to_writer_pretty(file, self).map_err(|error| LoadgenError::Output(format!("{}: {error}", path.display())))
+    }
+    /**
+    Appends the report to the CSV file, with a header when the file is new or empty
+    */
+    pub fn append_csv(&self, path: &Path) -> Result<(), LoadgenError> {
+        let output_error = |error: &dyn fmt::Display| LoadgenError::Output(format!("{}: {error}", path.display()));
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|error| output_error(&error))?;
+        let is_empty = file.metadata().map_err(|error| output_error(&error))?.len() == 0;
+        let mut writer = csv::WriterBuilder::new().has_headers(is_empty).from_writer(file);
+        writer
+            .serialize(CsvRow {
+                label: &self.label,
+                target_qps: self.target_qps,
+                duration_secs: self.duration_secs,
+                requests: self.requests,
+                succeeded: self.succeeded,
+                failed: self.failed(),
+                dropped: self.dropped,
+                achieved_qps: self.achieved_qps,
+                p50_ms: self.latency_ms.p50,
+                p90_ms: self.latency_ms.p90,
+                p99_ms: self.latency_ms.p99,
+                p999_ms: self.latency_ms.p999,
+                max_ms: self.latency_ms.max,
+                mean_ms: self.latency_ms.mean,
+                errors: join_counts(&self.errors),
+                backends: join_counts(&self.backends),
+            })
+            .map_err(|error| output_error(&error))?;
+        writer.flush().map_err(|error| output_error(&error))
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{}: {} requests in {:.1}s, {:.1} qps (target {}), {} failed, {} dropped",
+            self.label,
+            self.requests,
+            self.duration_secs,
+            self.achieved_qps,
+            if self.target_qps > 0.0 { format!("{:.1}", self.target_qps) } else { "closed loop".to_string() },
+            self.failed(),
+            self.dropped
+        )?;
+        let latency = &self.latency_ms;
+        writeln!(
+            f,
+            "latency ms: p50 {:.3} p90 {:.3} p99 {:.3} p99.9 {:.3} max {:.3} mean {:.3}",
+            latency.p50, latency.p90, latency.p99, latency.p999, latency.max, latency.mean
+        )?;
+        for (code, count) in &self.errors {
+            writeln!(f, "error {code}: {count}")?;
+        }
+        for (backend, count) in &self.backends {
+            let share = *count as f64 * 100.0 / self.succeeded.max(1) as f64;
+            writeln!(f, "backend {backend}: {count} ({share:.1}%)")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recorder() -> Recorder {
+        let mut recorder = Recorder::default();
+        for millis in 1..=1000 {
+            let backend = if millis % 4 == 0 { "server 2" } else { "server 1" };
+            recorder.record(Duration::from_millis(millis), Ok(backend));
+        }
+        recorder.record(Duration::from_millis(3), Err(Code::Unavailable));
+        recorder.record(Duration::from_millis(3), Err(Code::Unavailable));
+        recorder.record(Duration::from_secs(1), Err(Code::DeadlineExceeded));
+        recorder.record_dropped();
+        recorder
+    }
+
+    #[test]
+    fn test_report_percentiles_and_counts() {
+        let report = recorder().report("prequal", 100.0, Duration::from_secs(10));
+        assert_eq!(report.requests, 1003);
+        assert_eq!(report.succeeded, 1000);
+        assert_eq!(report.failed(), 3);
+        assert_eq!(report.dropped, 1);
+        assert!((report.achieved_qps - 100.3).abs() < 1e-9);
+        // 3 significant figures
+        let close = |value: f64, expected: f64| (value - expected).abs() <= expected / 100.0;
+        assert!(close(report.latency_ms.p50, 500.0), "{:?}", report.latency_ms);
+        assert!(close(report.latency_ms.p90, 900.0), "{:?}", report.latency_ms);
+        assert!(close(report.latency_ms.p99, 990.0), "{:?}", report.latency_ms);
+        assert!(close(report.latency_ms.p999, 999.0), "{:?}", report.latency_ms);
+        assert!(close(report.latency_ms.max, 1000.0), "{:?}", report.latency_ms);
+        assert_eq!(report.errors, BTreeMap::from([("DeadlineExceeded".to_string(), 1), ("Unavailable".to_string(), 2)]));
+        assert_eq!(report.backends, BTreeMap::from([("server 1".to_string(), 750), ("server 2".to_string(), 250)]));
+        assert!(report.to_string().contains("backend server 2: 250 (25.0%)"));
+    }
+
+    #[test]
+    fn test_json_and_csv_outputs() {
+        let dir = tempfile::tempdir().unwrap();
+        let report = recorder().report("prequal", 100.0, Duration::from_secs(10));
+
+        let json = dir.path().join("report.json");
+        report.write_json(&json).unwrap();
+        let read: Report = serde_json::from_reader(File::open(&json).unwrap()).unwrap();
+        assert_eq!((&read.label, read.requests, read.latency_ms.p99), (&report.label, report.requests, report.latency_ms.p99));
+        assert_eq!((read.errors, read.backends), (report.errors.clone(), report.backends.clone()));
+
+        // Runs append their row under a single header
+        let csv = dir.path().join("runs.csv");
+        report.append_csv(&csv).unwrap();
+        Report { label: "round_robin".to_string(), ..report }.append_csv(&csv).unwrap();
+        let mut reader = csv::Reader::from_path(&csv).unwrap();
+        let headers = reader.headers().unwrap().clone();
+        assert_eq!(&headers[0], "label");
+        assert_eq!(&headers[10], "p99_ms");
+        let rows = reader.records().map(Result::unwrap).collect::<Vec<_>>();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(&rows[0][0], "prequal");
+        assert_eq!(&rows[1][0], "round_robin");
+        assert_eq!(&rows[0][14], "DeadlineExceeded=1;Unavailable=2");
+        assert_eq!(&rows[0][15], "server 1=750;server 2=250");
+    }
+}
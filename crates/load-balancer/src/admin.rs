@@ -0,0 +1,436 @@
+use crate::routing::Router;
+use crate::{connect_client, Decision, LoadBalancer, LoadBalancerError, Policy, SelectionReason, TuningUpdate};
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::{Html, IntoResponse, Response};
+use axum::routing::{get, post, put};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::Ordering::Acquire;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+/// Polls `/api/state` and renders it, kept in a separate file so it can be edited as HTML
+const DASHBOARD: &str = include_str!("dashboard.html");
+
+#[derive(Serialize, Debug)]
+pub struct ClusterState {
+    pub name: String,
+    pub policy: Policy,
+    pub q_rif: f32,
+    pub probe_interval_ms: u64,
+    /// The interval the probes currently run at, it differs from `probe_interval_ms` when the probe rate adapts
+    pub adapted_probe_interval_ms: u64,
+    pub probe_pool_size: usize,
+    pub max_rif: u32,
+    /// Probes with at least this RIF are hot
+    pub hot_rif_threshold: f32,
+    /// Number of routing decisions the distribution is taken over
+    pub recent_decisions: usize,
+    pub backends: Vec<BackendState>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct BackendState {
+    pub address: String,
+    pub active: bool,
+    pub draining: bool,
+    pub in_flight: u32,
+    pub probe: Option<ProbeState>,
+    /// Requests sent to this backend among the recent decisions
+    pub routed: usize,
+    pub routed_share: f32,
+    pub routed_by: HashMap<SelectionReason, usize>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ProbeState {
+    pub server_id: String,
+    pub rif: u32,
+    pub latency: u64,
+    pub normalized_rif: f32,
+    pub hot: bool,
+    pub times_used: u32,
+    /// Milliseconds since the probe was received
+    pub age_ms: Option<u64>,
+    pub draining: bool,
+    /// RIF summed over the backends when the backend is a load balancer itself
+    pub fleet_rif: u32,
+    /// Microseconds the last answered probe took
+    pub rtt_us: Option<u64>,
+    /// The last probe was not answered within the probe timeout
+    pub timed_out: bool,
+}
+
+impl ClusterState {
+    pub fn new(
+        name: &str,
+        load_balancer: &LoadBalancer,
+        probe_interval: Duration,
+        adapted_probe_interval: Duration,
+    ) -> Self {
+        let pool = &load_balancer.probe_pool;
+        let recent_decisions = load_balancer.decisions.len();
+        let backends = load_balancer
+            .clients
+            .iter()
+            .map(|client| {
+                let probe = pool.get(&client.client_add).map(|probe| ProbeState {
+                    server_id: probe.server_id.clone(),
+                    rif: probe.rif,
+                    latency: probe.latency,
+                    normalized_rif: probe.normalized_rif,
+                    hot: pool.is_hot(probe),
+                    times_used: probe.times_used.load(Acquire),
+                    age_ms: probe.received_at.map(|received_at| received_at.elapsed().as_millis() as u64),
+                    draining: probe.draining,
+                    fleet_rif: probe.fleet_rif,
+                    rtt_us: probe.rtt.map(|rtt| rtt.as_micros() as u64),
+                    timed_out: probe.timed_out,
+                });
+                let mut routed_by = HashMap::new();
+                for decision in load_balancer.decisions.iter().filter(|decision| decision.server.eq(&client.client_add)) {
+                    *routed_by.entry(decision.reason).or_insert(0) += 1;
+                }
+                let routed = routed_by.values().sum::<usize>();
+                BackendState {
+                    address: client.client_add.clone(),
+                    active: client.is_active.load(Acquire),
+                    draining: client.draining.load(Acquire),
+                    in_flight: client.in_flight.load(Acquire),
+                    probe,
+                    routed,
+                    routed_share: if recent_decisions == 0 { 0.0 } else { routed as f32 / recent_decisions as f32 },
+                    routed_by,
+                }
+            })
+            .collect();
+        Self {
+            name: name.to_string(),
+            policy: load_balancer.config.policy,
+            q_rif: pool.q_rif,
+            probe_interval_ms: probe_interval.as_millis() as u64,
+            adapted_probe_interval_ms: adapted_probe_interval.as_millis() as u64,
+            probe_pool_size: load_balancer.config.probe_pool_size,
+            max_rif: pool.max_rif,
+            hot_rif_threshold: pool.q_rif * pool.max_rif as f32,
+            recent_decisions,
+            backends,
+        }
+    }
+}
+
+/**
+State of every cluster, sorted by name
+*/
+pub async fn cluster_states(router: &Router) -> Vec<ClusterState> {
+    let mut names = router.clusters.keys().collect::<Vec<&String>>();
+    names.sort();
+    let mut states = Vec::with_capacity(names.len());
+    for name in names {
+        states.push(ClusterState::new(
+            name,
+            &*router.clusters[name].lock().await,
+            router.probe_interval(),
+            router.adapted_probe_interval(),
+        ));
+    }
+    states
+}
+
+/**
+A backend to add, remove or drain
+*/
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BackendRequest {
+    pub address: String,
+}
+
+/**
+Tuning to change at runtime, applied to the given cluster or to every cluster when none is given
+*/
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct SettingsRequest {
+    pub cluster: Option<String>,
+    #[serde(flatten)]
+    pub tuning: TuningUpdate,
+}
+
+#[derive(Deserialize, Debug)]
+struct DecisionsQuery {
+    /// Only decisions with a greater sequence number
+    after: Option<u64>,
+}
+
+/**
+Failure of an admin request, answered with a JSON body `{"error": "..."}`
+*/
+struct ApiError(LoadBalancerError);
+
+impl From<LoadBalancerError> for ApiError {
+    fn from(error: LoadBalancerError) -> Self {
+        Self(error)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = match self.0 {
+            LoadBalancerError::UnknownCluster(_) | LoadBalancerError::UnknownBackend(_) => StatusCode::NOT_FOUND,
+            LoadBalancerError::UnableToEstablishConnectivity(_) => StatusCode::BAD_GATEWAY,
+            _ => StatusCode::BAD_REQUEST,
+        };
+        (status, Json(serde_json::json!({ "error": self.0.to_string() }))).into_response()
+    }
+}
+
+/**
+Dashboard at `/` and the same data as JSON at `/api/state`, plus the operations of `prequalctl`:
+
+- `GET /api/clusters/{cluster}/decisions?after={seq}` the decision log
+- `POST`/`DELETE /api/clusters/{cluster}/backends` adds or removes a backend
+- `POST /api/clusters/{cluster}/backends/drain` stops sending new requests to a backend
+- `PUT /api/settings` changes the policy and probing settings
+*/
+pub fn app(router: Arc<Router>) -> axum::Router {
+    axum::Router::new()
+        .route("/", get(dashboard))
+        .route("/api/state", get(state))
+        .route("/api/clusters/:cluster/decisions", get(decisions))
+        .route("/api/clusters/:cluster/backends", post(add_backend).delete(remove_backend))
+        .route("/api/clusters/:cluster/backends/drain", post(drain_backend))
+        .route("/api/settings", put(update_settings))
+        .with_state(router)
+}
+
+fn cluster<'a>(router: &'a Router, name: &str) -> Result<&'a Arc<Mutex<LoadBalancer>>, ApiError> {
+    Ok(router
+        .clusters
+        .get(name)
+        .ok_or_else(|| LoadBalancerError::UnknownCluster(name.to_string()))?)
+}
+
+async fn dashboard() -> Html<&'static str> {
+    Html(DASHBOARD)
+}
+
+async fn state(State(router): State<Arc<Router>>) -> Json<Vec<ClusterState>> {
+    Json(cluster_states(&router).await)
+}
+
+async fn decisions(
+    State(router): State<Arc<Router>>,
+    Path(name): Path<String>,
+    Query(query): Query<DecisionsQuery>,
+) -> Result<Json<Vec<Decision>>, ApiError> {
+    let load_balancer = cluster(&router, &name)?.lock().await;
+    let decisions = load_balancer
+        .decisions
+        .iter()
+        .filter(|decision| query.after.is_none_or(|after| decision.seq > after))
+        .cloned()
+        .collect();
+    Ok(Json(decisions))
+}
+
+async fn add_backend(
+    State(router): State<Arc<Router>>,
+    Path(name): Path<String>,
+    Json(request): Json<BackendRequest>,
+) -> Result<StatusCode, ApiError> {
+    let load_balancer = cluster(&router, &name)?;
+    let (mode, backend_tls) = {
+        let load_balancer = load_balancer.lock().await;
+        load_balancer.check_new_client(&request.address)?;
+        (load_balancer.config.mode, load_balancer.backend_tls.clone())
+    };
+    // Connecting can take a while, requests keep being routed meanwhile
+    let channel = connect_client(mode, &request.address, backend_tls.as_ref()).await?;
+    load_balancer.lock().await.insert_client(request.address.clone(), channel)?;
+    tracing::info!("Added the backend {} to the cluster {}", request.address, name);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn remove_backend(
+    State(router): State<Arc<Router>>,
+    Path(name): Path<String>,
+    Json(request): Json<BackendRequest>,
+) -> Result<StatusCode, ApiError> {
+    let mut load_balancer = cluster(&router, &name)?.lock().await;
+    if !load_balancer.clients.iter().any(|client| client.client_add.eq(&request.address)) {
+        return Err(LoadBalancerError::UnknownBackend(request.address).into());
+    }
+    load_balancer.remove_client(request.address.clone()).await?;
+    load_balancer.probe_pool.remove(&request.address);
+    tracing::info!("Removed the backend {} from the cluster {}", request.address, name);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn drain_backend(
+    State(router): State<Arc<Router>>,
+    Path(name): Path<String>,
+    Json(request): Json<BackendRequest>,
+) -> Result<StatusCode, ApiError> {
+    cluster(&router, &name)?.lock().await.drain_client(&request.address)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn update_settings(
+    State(router): State<Arc<Router>>,
+    Json(request): Json<SettingsRequest>,
+) -> Result<Json<Vec<ClusterState>>, ApiError> {
+    router.update_tuning(request.cluster.as_deref(), &request.tuning).await?;
+    Ok(Json(cluster_states(&router).await))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hello_world::Metric;
+    use crate::routing::RoutingConfig;
+    use crate::tests::lazy_client;
+    use crate::Config;
+    use hyper::body::Bytes;
+    use http::Method;
+    use http_body_util::{BodyExt, Empty, Full};
+    use std::net::SocketAddr;
+
+    async fn router() -> Arc<Router> {
+        let config = Config {
+            server_urls: "http://[::1]:50052,http://[::1]:50053".to_string(),
+            ..Default::default()
+        };
+        let router = Router::new(&RoutingConfig::from_env(&config), &config).unwrap();
+        {
+            let mut lb = router.clusters["default"].lock().await;
+            lb.clients = vec![lazy_client("http://[::1]:50052"), lazy_client("http://[::1]:50053")];
+            lb.record_probe("http://[::1]:50052", &Metric { rif: 10, latency: 5, ..Default::default() });
+            lb.record_probe("http://[::1]:50053", &Metric { rif: 2, latency: 9, ..Default::default() });
+            for _ in 0..3 {
+                lb.record_decision("http://[::1]:50053", SelectionReason::Cold);
+            }
+            lb.record_decision("http://[::1]:50052", SelectionReason::Affinity);
+        }
+        Arc::new(router)
+    }
+
+    #[tokio::test]
+    async fn test_cluster_state() {
+        let states = cluster_states(&*router().await).await;
+        assert_eq!(states.len(), 1);
+        let state = &states[0];
+        assert_eq!((state.max_rif, state.hot_rif_threshold, state.recent_decisions), (10, 7.0, 4));
+        let hot = &state.backends[0];
+        let cold = &state.backends[1];
+        assert!(hot.probe.as_ref().unwrap().hot);
+        assert!(!cold.probe.as_ref().unwrap().hot);
+        assert_eq!(cold.probe.as_ref().unwrap().normalized_rif, 0.2);
+        assert!(cold.probe.as_ref().unwrap().age_ms.is_some());
+        assert_eq!((cold.routed, cold.routed_share), (3, 0.75));
+        assert_eq!(hot.routed_by[&SelectionReason::Affinity], 1);
+    }
+
+    async fn serve(router: Arc<Router>) -> SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app(router)).await });
+        addr
+    }
+
+    /**
+    Sends a JSON request and returns the status with the JSON response, null when there is no body
+    */
+    async fn send(method: Method, url: String, body: Option<serde_json::Value>) -> (StatusCode, serde_json::Value) {
+        let body = body.map(|body| body.to_string()).unwrap_or_default();
+        let request = http::Request::builder()
+            .method(method)
+            .uri(url)
+            .header("content-type", "application/json")
+            .body(Full::new(Bytes::from(body)).map_err(|never| match never {}).boxed())
+            .unwrap();
+        let response = crate::http_proxy::http_client(false).request(request).await.unwrap();
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    #[tokio::test]
+    async fn test_json_api_and_dashboard() {
+        let addr = serve(router().await).await;
+        let client = crate::http_proxy::http_client(false);
+
+        let (_, state) = send(Method::GET, format!("http://{addr}/api/state"), None).await;
+        assert_eq!(state[0]["name"], "default");
+        assert_eq!(state[0]["policy"], "prequal");
+        assert_eq!(state[0]["backends"][1]["probe"]["rif"], 2);
+        assert_eq!(state[0]["backends"][1]["routed_by"]["cold"], 3);
+
+        let request = http::Request::get(format!("http://{addr}/"))
+            .body(Empty::new().map_err(|never| match never {}).boxed())
+            .unwrap();
+        let response = client.request(request).await.unwrap();
+        assert!(response.headers()["content-type"].to_str().unwrap().starts_with("text/html"));
+    }
+
+    #[tokio::test]
+    async fn test_backend_operations() {
+        let router = router().await;
+        let addr = serve(router.clone()).await;
+        let backends = format!("http://{addr}/api/clusters/default/backends");
+        let backend = |address: &str| Some(serde_json::json!({ "address": address }));
+
+        let (status, _) = send(Method::POST, format!("{backends}/drain"), backend("http://[::1]:50053")).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        {
+            let mut lb = router.clusters["default"].lock().await;
+            assert!(lb.clients[1].draining.load(Acquire));
+            // The cold server is draining, so the hot one is picked
+            assert_eq!(lb.get_server().unwrap().client_add, "http://[::1]:50052");
+        }
+
+        let (status, _) = send(Method::DELETE, backends.clone(), backend("http://[::1]:50053")).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, error) = send(Method::DELETE, backends.clone(), backend("http://[::1]:50053")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(error["error"].as_str().unwrap().contains("http://[::1]:50053"));
+        {
+            let lb = router.clusters["default"].lock().await;
+            assert_eq!(lb.clients.len(), 1);
+            assert!(lb.probe_pool.get("http://[::1]:50053").is_none());
+        }
+
+        // Nothing listens there
+        let (status, _) = send(Method::POST, backends, backend("http://127.0.0.1:1")).await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        let (status, _) = send(Method::POST, format!("http://{addr}/api/clusters/missing/backends"), backend("http://127.0.0.1:1")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_settings_and_decisions() {
+        let router = router().await;
+        let addr = serve(router.clone()).await;
+
+        let settings = serde_json::json!({ "q_rif": 0.5, "probe_pool_size": 3, "probe_interval_ms": 250 });
+        let (status, state) = send(Method::PUT, format!("http://{addr}/api/settings"), Some(settings)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!((state[0]["q_rif"].as_f64(), state[0]["probe_pool_size"].as_u64()), (Some(0.5), Some(3)));
+        assert_eq!(router.probe_interval(), Duration::from_millis(250));
+        assert_eq!(router.clusters["default"].lock().await.probe_pool.q_rif, 0.5);
+        // Nothing is applied when one of the settings is invalid
+        let settings = serde_json::json!({ "policy": "random", "q_rif": 2.0 });
+        let (status, _) = send(Method::PUT, format!("http://{addr}/api/settings"), Some(settings)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(router.clusters["default"].lock().await.config.policy, Policy::Prequal);
+        let settings = serde_json::json!({ "cluster": "default", "policy": "round_robin" });
+        let (_, state) = send(Method::PUT, format!("http://{addr}/api/settings"), Some(settings)).await;
+        assert_eq!(state[0]["policy"], "round_robin");
+
+        let (_, decisions) = send(Method::GET, format!("http://{addr}/api/clusters/default/decisions?after=1"), None).await;
+        let seqs = decisions.as_array().unwrap().iter().map(|decision| decision["seq"].as_u64().unwrap()).collect::<Vec<u64>>();
+        assert_eq!(seqs, [2, 3]);
+        assert_eq!(decisions[1]["reason"], "affinity");
+    }
+}
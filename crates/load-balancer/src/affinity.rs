@@ -0,0 +1,232 @@
+use serde::Deserialize;
+use std::str::FromStr;
+
+/// Points placed on the ring for every backend, more points spread the keys more evenly
+const RING_POINTS_PER_BACKEND: u64 = 128;
+/// Maglev lookup table size, has to be a prime well above the number of backends
+const MAGLEV_TABLE_SIZE: usize = 65537;
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AffinityMode {
+    /// Every request goes through Prequal selection
+    #[default]
+    None,
+    /// Consistent hashing on a ring with virtual nodes
+    Ring,
+    /// Maglev lookup table hashing
+    Maglev,
+}
+
+/**
+Where the affinity key of a request is read from.
+Written as `metadata:<header>` or `field:<name>` in the config.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AffinityKey {
+    Metadata(String),
+    Field(String),
+}
+
+impl FromStr for AffinityKey {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.split_once(':') {
+            Some(("metadata", key)) if !key.is_empty() => Ok(AffinityKey::Metadata(key.to_lowercase())),
+            Some(("field", field)) if !field.is_empty() => Ok(AffinityKey::Field(field.to_string())),
+            _ => Err(format!(
+                "`{value}` should look like `metadata:<header>` or `field:<name>`"
+            )),
+        }
+    }
+}
+
+/**
+Messages which expose fields that can be used as an affinity key
+*/
+pub trait AffinityFields {
+    fn affinity_field(&self, field: &str) -> Option<&str>;
+}
+
+/**
+Maps an affinity key to a preferred backend address
+*/
+#[derive(Debug, Clone)]
+pub enum HashRing {
+    Ring(Vec<(u64, usize)>, Vec<String>),
+    Maglev(Vec<usize>, Vec<String>),
+}
+
+impl HashRing {
+    pub fn new(mode: AffinityMode, backends: &[String]) -> Option<Self> {
+        if backends.is_empty() {
+            return None;
+        }
+        // Sorting keeps the result independent of the order backends were added in
+        let mut backends = backends.to_vec();
+        backends.sort();
+        backends.dedup();
+        match mode {
+            AffinityMode::None => None,
+            AffinityMode::Ring => {
+                let mut points = Vec::with_capacity(backends.len() * RING_POINTS_PER_BACKEND as usize);
+                for (idx, backend) in backends.iter().enumerate() {
+                    for point in 0..RING_POINTS_PER_BACKEND {
+                        points.push((hash(format!("{backend}#{point}").as_bytes()), idx));
+                    }
+                }
+                points.sort_unstable();
+                Some(HashRing::Ring(points, backends))
+            }
+            AffinityMode::Maglev => Some(HashRing::Maglev(maglev_table(&backends), backends)),
+        }
+    }
+    /**
+    Returns the backend address the key is pinned to
+    */
+    pub fn pick(&self, key: &[u8]) -> &str {
+        let key = hash(key);
+        match self {
+            HashRing::Ring(points, backends) => {
+                let idx = points.partition_point(|(point, _)| *point < key);
+                // Wrap around to the first point past the end of the ring
+                let (_, backend) = points[idx % points.len()];
+                &backends[backend]
+            }
+            HashRing::Maglev(table, backends) => &backends[table[(key % table.len() as u64) as usize]],
+        }
+    }
+}
+
+/**
+Populates the lookup table by letting every backend take turns claiming its next preferred slot,
+see "Maglev: A Fast and Reliable Software Network Load Balancer" section 3.4
+*/
+fn maglev_table(backends: &[String]) -> Vec<usize> {
+    let size = MAGLEV_TABLE_SIZE as u64;
+    let permutations = backends
+        .iter()
+        .map(|backend| {
+            let offset = hash(format!("{backend}#offset").as_bytes()) % size;
+            let skip = hash(format!("{backend}#skip").as_bytes()) % (size - 1) + 1;
+            (offset, skip)
+        })
+        .collect::<Vec<(u64, u64)>>();
+    let mut next = vec![0u64; backends.len()];
+    let mut table = vec![usize::MAX; MAGLEV_TABLE_SIZE];
+    let mut filled = 0;
+    loop {
+        for (idx, (offset, skip)) in permutations.iter().enumerate() {
+            let mut slot = ((offset + next[idx] * skip) % size) as usize;
+            while table[slot] != usize::MAX {
+                next[idx] += 1;
+                slot = ((offset + next[idx] * skip) % size) as usize;
+            }
+            table[slot] = idx;
+            next[idx] += 1;
+            filled += 1;
+            if filled == MAGLEV_TABLE_SIZE {
+                return table;
+            }
+        }
+    }
+}
+
+/**
+FNV-1a followed by a 64 bit finalizer, stable across processes and Rust versions
+so every load balancer instance maps a key to the same backend
+*/
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn backends(count: usize) -> Vec<String> {
+        (0..count).map(|i| format!("http://[::1]:{}", 50052 + i)).collect()
+    }
+
+    fn distribution(ring: &HashRing, keys: usize) -> HashMap<String, usize> {
+        let mut counts = HashMap::new();
+        for key in 0..keys {
+            *counts.entry(ring.pick(format!("user-{key}").as_bytes()).to_string()).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn test_parse_affinity_key() {
+        assert_eq!(
+            "metadata:X-Session-Id".parse::<AffinityKey>(),
+            Ok(AffinityKey::Metadata("x-session-id".to_string()))
+        );
+        assert_eq!("field:name".parse::<AffinityKey>(), Ok(AffinityKey::Field("name".to_string())));
+        assert!("name".parse::<AffinityKey>().is_err());
+        assert!("metadata:".parse::<AffinityKey>().is_err());
+    }
+
+    #[test]
+    fn test_no_ring_without_backends_or_mode() {
+        assert!(HashRing::new(AffinityMode::Ring, &[]).is_none());
+        assert!(HashRing::new(AffinityMode::None, &backends(3)).is_none());
+    }
+
+    #[test]
+    fn test_same_key_same_backend_regardless_of_order() {
+        for mode in [AffinityMode::Ring, AffinityMode::Maglev] {
+            let mut reversed = backends(5);
+            reversed.reverse();
+            let ring = HashRing::new(mode, &backends(5)).unwrap();
+            let other = HashRing::new(mode, &reversed).unwrap();
+            for key in 0..100 {
+                let key = format!("user-{key}");
+                assert_eq!(ring.pick(key.as_bytes()), other.pick(key.as_bytes()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_keys_spread_over_all_backends() {
+        for mode in [AffinityMode::Ring, AffinityMode::Maglev] {
+            let ring = HashRing::new(mode, &backends(4)).unwrap();
+            let counts = distribution(&ring, 10_000);
+            assert_eq!(counts.len(), 4);
+            // Every backend should get its share within a generous margin
+            assert!(counts.values().all(|count| *count > 1_500 && *count < 3_500), "{mode:?} {counts:?}");
+        }
+    }
+
+    #[test]
+    fn test_removing_a_backend_only_moves_its_keys() {
+        for mode in [AffinityMode::Ring, AffinityMode::Maglev] {
+            let all = backends(5);
+            let removed = all[2].clone();
+            let before = HashRing::new(mode, &all).unwrap();
+            let after = HashRing::new(mode, &all.iter().filter(|b| **b != removed).cloned().collect::<Vec<_>>()).unwrap();
+            let mut moved = 0;
+            for key in 0..10_000 {
+                let key = format!("user-{key}");
+                let old = before.pick(key.as_bytes());
+                let new = after.pick(key.as_bytes());
+                if old != removed && old != new {
+                    moved += 1;
+                }
+            }
+            // Maglev allows a little disruption, the ring none at all
+            assert!(moved < 500, "{mode:?} moved {moved} keys");
+        }
+    }
+}
@@ -0,0 +1,532 @@
+use crate::hello_world::greeter_server::GreeterServer;
+use crate::routing::{Router, RoutingConfig};
+use crate::admin;
+use crate::http_proxy::HttpProxy;
+use crate::{
+    background_process, initialise_load_balancer, probe_cluster, tls, watch_certificates, BootstrapPolicy, Config,
+    FailCancelledStreams, LoadBalancerError, Mode, MyGreeter, Policy,
+};
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpListener;
+use tokio::sync::{oneshot, watch};
+use tokio::task;
+use tokio::time::timeout;
+use tokio_rustls::rustls::server::ServerConfig;
+use tokio_stream::{Stream, StreamExt};
+use tonic::transport::server::{Connected, TcpIncoming};
+use tonic::transport::Server;
+
+/**
+Configures a load balancer from code, settings which are not given keep the defaults of [`Config`]
+*/
+#[derive(Debug, Default)]
+pub struct LoadBalancerBuilder {
+    config: Config,
+    routing: Option<RoutingConfig>,
+}
+
+impl LoadBalancerBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /**
+    Starts from an existing config, like the one the binary reads from the environment
+    */
+    pub fn from_config(config: Config) -> Self {
+        Self { config, routing: None }
+    }
+    /**
+    Backends of the default cluster
+    */
+    pub fn backends<I, S>(mut self, backends: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.config.server_urls = backends.into_iter().map(Into::into).collect::<Vec<String>>().join(",");
+        self
+    }
+    /**
+    Named clusters and the rules routing requests to them, the backends of the default cluster are ignored
+    */
+    pub fn routing(mut self, routing: RoutingConfig) -> Self {
+        self.routing = Some(routing);
+        self
+    }
+    /**
+    Whether gRPC or plain HTTP is balanced
+    */
+    pub fn mode(mut self, mode: Mode) -> Self {
+        self.config.mode = mode;
+        self
+    }
+    /**
+    Path HTTP backends report their load on
+    */
+    pub fn http_probe_path(mut self, path: impl Into<String>) -> Self {
+        self.config.http_probe_path = path.into();
+        self
+    }
+    pub fn policy(mut self, policy: Policy) -> Self {
+        self.config.policy = policy;
+        self
+    }
+    /**
+    How the Prequal policy spreads requests while no available server has a probe
+    */
+    pub fn bootstrap_policy(mut self, bootstrap_policy: BootstrapPolicy) -> Self {
+        self.config.bootstrap_policy = bootstrap_policy;
+        self
+    }
+    /**
+    Probes the backends once in `build`, before the listener is bound
+    */
+    pub fn wait_for_first_probe(mut self, wait_for_first_probe: bool) -> Self {
+        self.config.wait_for_first_probe = wait_for_first_probe;
+        self
+    }
+    pub fn q_rif(mut self, q_rif: f32) -> Self {
+        self.config.q_rif = q_rif;
+        self
+    }
+    pub fn probe_interval(mut self, probe_interval: Duration) -> Self {
+        self.config.probe_interval_ms = probe_interval.as_millis() as u64;
+        self
+    }
+    /**
+    Number of servers probed in every round
+    */
+    pub fn probe_pool_size(mut self, probe_pool_size: usize) -> Self {
+        self.config.probe_pool_size = probe_pool_size;
+        self
+    }
+    /**
+    A probe not answered in time marks the server hot
+    */
+    pub fn probe_timeout(mut self, probe_timeout: Duration) -> Self {
+        self.config.probe_timeout_ms = probe_timeout.as_millis() as u64;
+        self
+    }
+    /**
+    Port 0 picks a free port, see [`LoadBalancerHandle::local_addr`]
+    */
+    pub fn listen_addr(mut self, listen_addr: SocketAddr) -> Self {
+        self.config.listen_addr = listen_addr.to_string();
+        self
+    }
+    /**
+    Serves the dashboard and JSON API on this address, port 0 picks a free port.
+    Only loopback addresses are accepted unless remote access is allowed.
+    */
+    pub fn admin_addr(mut self, admin_addr: SocketAddr) -> Self {
+        self.config.admin_addr = Some(admin_addr.to_string());
+        self
+    }
+    /**
+    Lets the admin API bind an address which is not a loopback one, it has no authentication
+    */
+    pub fn admin_allow_remote(mut self, admin_allow_remote: bool) -> Self {
+        self.config.admin_allow_remote = admin_allow_remote;
+        self
+    }
+    pub fn grace_period(mut self, grace_period: Duration) -> Self {
+        self.config.grace_period_ms = grace_period.as_millis() as u64;
+        self
+    }
+    /**
+    Connects to the backends and binds the listener, requests are served once `serve` is called.
+    Backends which can't be reached are logged and left out.
+    */
+    pub async fn build(self) -> Result<LoadBalancerHandle, LoadBalancerError> {
+        let config = self.config;
+        let routing = match (self.routing, &config.routing_config_path) {
+            (Some(routing), _) => routing,
+            (None, Some(path)) => RoutingConfig::from_file(path)?,
+            (None, None) => RoutingConfig::from_env(&config),
+        };
+        let router = Arc::new(Router::new(&routing, &config)?);
+        for cluster in &routing.clusters {
+            tracing::info!("Initialising the cluster {}", cluster.name);
+            initialise_load_balancer(router.clusters[&cluster.name].clone(), cluster.server_urls.clone()).await;
+        }
+        if config.wait_for_first_probe {
+            for (name, load_balancer) in &router.clusters {
+                probe_cluster(load_balancer, usize::MAX).await;
+                let probes = load_balancer.lock().await.probe_pool.len();
+                tracing::info!("The first probing round of the cluster {} left {} probes", name, probes);
+            }
+        }
+        let listener_tls = config.listener_tls().transpose()?;
+        let (listener, local_addr) = bind(&config.listen_addr).await?;
+        let (admin_listener, admin_addr) = match &config.admin_addr {
+            Some(addr) => {
+                let addr = admin_bind_addr(addr, config.admin_allow_remote)?;
+                let (listener, addr) = bind(&addr).await?;
+                (Some(listener), Some(addr))
+            }
+            None => (None, None),
+        };
+        Ok(LoadBalancerHandle {
+            config,
+            router,
+            local_addr,
+            admin_addr,
+            listener: Arc::new(std::sync::Mutex::new(Some(listener))),
+            admin_listener: Arc::new(std::sync::Mutex::new(admin_listener)),
+            listener_tls,
+            shutdown: Arc::new(watch::channel(false).0),
+        })
+    }
+}
+
+/**
+The admin API can add and remove backends without any authentication,
+so it listens on `[::1]` for a bare port and refuses other than loopback addresses unless allowed.
+*/
+fn admin_bind_addr(addr: &str, allow_remote: bool) -> Result<String, LoadBalancerError> {
+    let addr = match addr.parse::<u16>() {
+        Ok(port) => format!("[::1]:{port}"),
+        Err(_) => addr.to_string(),
+    };
+    let socket_addr: SocketAddr = addr
+        .parse()
+        .map_err(|error| LoadBalancerError::InvalidConfig(format!("admin address {addr}: {error}")))?;
+    if !allow_remote && !socket_addr.ip().is_loopback() {
+        return Err(LoadBalancerError::InvalidConfig(format!(
+            "the admin API has no authentication, set ADMIN_ALLOW_REMOTE=true to serve it on {addr}"
+        )));
+    }
+    Ok(addr)
+}
+
+async fn bind(addr: &str) -> Result<(TcpListener, SocketAddr), LoadBalancerError> {
+    let addr: SocketAddr = addr
+        .parse()
+        .map_err(|error| LoadBalancerError::InvalidConfig(format!("address {addr}: {error}")))?;
+    let listener = TcpListener::bind(addr)
+        .await
+        .map_err(|error| LoadBalancerError::ServerFailed(format!("unable to bind {addr}: {error}")))?;
+    let local_addr = listener
+        .local_addr()
+        .map_err(|error| LoadBalancerError::ServerFailed(error.to_string()))?;
+    Ok((listener, local_addr))
+}
+
+/**
+A built load balancer. Clones share the same instance, so one clone can serve while another shuts it down.
+*/
+#[derive(Debug, Clone)]
+pub struct LoadBalancerHandle {
+    config: Config,
+    router: Arc<Router>,
+    local_addr: SocketAddr,
+    admin_addr: Option<SocketAddr>,
+    /// Taken by `serve`
+    listener: Arc<std::sync::Mutex<Option<TcpListener>>>,
+    admin_listener: Arc<std::sync::Mutex<Option<TcpListener>>>,
+    listener_tls: Option<Arc<ServerConfig>>,
+    shutdown: Arc<watch::Sender<bool>>,
+}
+
+impl LoadBalancerHandle {
+    /**
+    The address the listener is bound to
+    */
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+    /**
+    The address the dashboard is served on, if enabled
+    */
+    pub fn admin_addr(&self) -> Option<SocketAddr> {
+        self.admin_addr
+    }
+    pub fn router(&self) -> &Arc<Router> {
+        &self.router
+    }
+    /**
+    Applies the policy and probing settings of a reloaded config to the running load balancer,
+    see [`Router::reload`]. Everything else only changes on restart.
+    */
+    pub async fn reload(&self, config: &Config) -> Result<(), LoadBalancerError> {
+        let routing = match &config.routing_config_path {
+            Some(path) => RoutingConfig::from_file(path)?,
+            None => RoutingConfig::from_env(config),
+        };
+        self.router.reload(config, &routing).await
+    }
+    /**
+    Stops accepting new connections, `serve` returns once the in-flight requests drained
+    */
+    pub fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+    /**
+    Serves requests, probes the backends and reloads certificates until `shutdown` is called.
+    In-flight requests get the grace period to finish, after that their connections are closed
+    and [`LoadBalancerError::GracePeriodElapsed`] is returned.
+    */
+    pub async fn serve(&self) -> Result<(), LoadBalancerError> {
+        let listener = self
+            .listener
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or_else(|| LoadBalancerError::ServerFailed("the load balancer is already serving".to_string()))?;
+        let (background_tx, background_rx) = oneshot::channel();
+        let background_task = task::spawn(background_process(background_rx, self.router.clone()));
+        let listener_tls = self.listener_tls.clone().map(|server_config| Arc::new(RwLock::new(server_config)));
+        let certificate_task = task::spawn(watch_certificates(self.config.clone(), listener_tls.clone(), self.router.clone()));
+        let admin_task = self.admin_listener.lock().unwrap().take().map(|admin_listener| {
+            tracing::info!("Serving the dashboard on {:?}", self.admin_addr);
+            let mut shutdown = self.shutdown.subscribe();
+            let app = admin::app(self.router.clone());
+            task::spawn(async move {
+                let signal = async move {
+                    let _ = shutdown.wait_for(|stop| *stop).await;
+                };
+                if let Err(error) = axum::serve(admin_listener, app).with_graceful_shutdown(signal).await {
+                    tracing::error!(%error, "The dashboard stopped");
+                }
+            })
+        });
+
+        // Fired as soon as shutdown is called, the server itself keeps draining after that
+        let (cut_off_tx, cut_off) = watch::channel(false);
+        let (draining_tx, draining_rx) = oneshot::channel::<()>();
+        let mut shutdown = self.shutdown.subscribe();
+        let signal = async move {
+            let _ = shutdown.wait_for(|stop| *stop).await;
+            let _ = draining_tx.send(());
+        };
+        let server = match listener_tls {
+            Some(server_config) => {
+                tracing::info!("Terminating TLS on {} in {:?} mode", self.local_addr, self.config.mode);
+                self.spawn_server(tls::incoming(listener, server_config), signal, cut_off)
+            }
+            None => {
+                tracing::info!("Listening on {} in {:?} mode", self.local_addr, self.config.mode);
+                let incoming = TcpIncoming::from_listener(listener, true, None)
+                    .map_err(|error| LoadBalancerError::ServerFailed(error.to_string()))?;
+                self.spawn_server(incoming, signal, cut_off)
+            }
+        };
+        tokio::pin!(server);
+
+        let result = tokio::select! {
+            result = &mut server => {
+                // The server stopped on its own before shutdown was called
+                server_result(result)
+            }
+            Ok(()) = draining_rx => {
+                let grace_period = Duration::from_millis(self.config.grace_period_ms);
+                tracing::info!(
+                    "Stopped accepting new connections, draining in-flight requests for up to {:?}",
+                    grace_period
+                );
+                match timeout(grace_period, &mut server).await {
+                    Ok(result) => {
+                        tracing::info!("All in-flight requests are drained");
+                        server_result(result)
+                    }
+                    Err(_) => {
+                        tracing::warn!("Grace period elapsed with requests still in flight, forcing shutdown");
+                        cut_off_tx.send_replace(true);
+                        server.abort();
+                        Err(LoadBalancerError::GracePeriodElapsed)
+                    }
+                }
+            }
+        };
+
+        // Stop probing and wait for the background task to finish
+        certificate_task.abort();
+        if let Some(admin_task) = admin_task {
+            admin_task.abort();
+        }
+        let _ = background_tx.send(());
+        let _ = background_task.await;
+        result
+    }
+}
+
+impl LoadBalancerHandle {
+    /**
+    Serves the gRPC service or the HTTP proxy, depending on the mode, on the accepted connections
+    */
+    fn spawn_server<S, IO>(
+        &self,
+        incoming: S,
+        signal: impl Future<Output = ()> + Send + 'static,
+        cut_off: watch::Receiver<bool>,
+    ) -> task::JoinHandle<Result<(), LoadBalancerError>>
+    where
+        S: Stream<Item = Result<IO, std::io::Error>> + Send + 'static,
+        IO: Connected + AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let incoming = incoming.map(move |io| io.map(|io| CutOffIo::new(io, cut_off.clone())));
+        match self.config.mode {
+            Mode::Grpc => {
+                let greeter = MyGreeter {
+                    router: self.router.clone(),
+                };
+                let service = Server::builder().add_service(FailCancelledStreams(GreeterServer::new(greeter)));
+                task::spawn(async move {
+                    service
+                        .serve_with_incoming_shutdown(incoming, signal)
+                        .await
+                        .map_err(|error| LoadBalancerError::ServerFailed(error.to_string()))
+                })
+            }
+            Mode::Http => {
+                let proxy = HttpProxy::new(self.router.clone(), self.config.http_backend_http2);
+                task::spawn(proxy.serve_with_incoming_shutdown(incoming, signal))
+            }
+        }
+    }
+}
+
+/**
+Connection of a caller which fails once the grace period elapsed, or `serve` returned.
+The connection tasks of the server then stop with their requests instead of outliving it.
+*/
+struct CutOffIo<IO> {
+    io: IO,
+    /// None once the connection is cut off
+    cut_off: Option<Pin<Box<dyn Future<Output = ()> + Send>>>,
+}
+
+impl<IO> CutOffIo<IO> {
+    fn new(io: IO, mut cut_off: watch::Receiver<bool>) -> Self {
+        let cut_off = async move {
+            let _ = cut_off.wait_for(|cut_off| *cut_off).await;
+        };
+        Self {
+            io,
+            cut_off: Some(Box::pin(cut_off)),
+        }
+    }
+    fn poll_cut_off(&mut self, cx: &mut Context<'_>) -> io::Result<()> {
+        if let Some(cut_off) = &mut self.cut_off {
+            if cut_off.as_mut().poll(cx).is_pending() {
+                return Ok(());
+            }
+            self.cut_off = None;
+        }
+        Err(io::Error::new(io::ErrorKind::ConnectionAborted, "the grace period elapsed"))
+    }
+}
+
+impl<IO: Connected> Connected for CutOffIo<IO> {
+    type ConnectInfo = IO::ConnectInfo;
+
+    fn connect_info(&self) -> Self::ConnectInfo {
+        self.io.connect_info()
+    }
+}
+
+impl<IO: AsyncRead + Unpin> AsyncRead for CutOffIo<IO> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.poll_cut_off(cx)?;
+        Pin::new(&mut this.io).poll_read(cx, buf)
+    }
+}
+
+impl<IO: AsyncWrite + Unpin> AsyncWrite for CutOffIo<IO> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        this.poll_cut_off(cx)?;
+        Pin::new(&mut this.io).poll_write(cx, buf)
+    }
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        this.poll_cut_off(cx)?;
+        Pin::new(&mut this.io).poll_write_vectored(cx, bufs)
+    }
+    fn is_write_vectored(&self) -> bool {
+        self.io.is_write_vectored()
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.poll_cut_off(cx)?;
+        Pin::new(&mut this.io).poll_flush(cx)
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_shutdown(cx)
+    }
+}
+
+fn server_result(result: Result<Result<(), LoadBalancerError>, task::JoinError>) -> Result<(), LoadBalancerError> {
+    result.map_err(|error| LoadBalancerError::ServerFailed(error.to_string()))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn builder() -> LoadBalancerBuilder {
+        LoadBalancerBuilder::new()
+            .listen_addr("127.0.0.1:0".parse().unwrap())
+            .backends(["http://[::1]:50052", "http://[::1]:50053"])
+            .policy(Policy::RoundRobin)
+            .q_rif(0.5)
+            .probe_pool_size(1)
+            .grace_period(Duration::from_secs(1))
+    }
+
+    #[test]
+    fn test_builder_sets_the_config() {
+        let builder = builder();
+        assert_eq!(builder.config.server_urls, "http://[::1]:50052,http://[::1]:50053");
+        assert_eq!(builder.config.policy, Policy::RoundRobin);
+        assert_eq!(builder.config.q_rif, 0.5);
+        assert_eq!(builder.config.probe_pool_size, 1);
+        // Untouched settings keep their defaults
+        assert_eq!(builder.config.probe_interval_ms, 100);
+        assert_eq!(builder.config.inline_report_backoff_ms, 1000);
+    }
+
+    #[tokio::test]
+    async fn test_serve_until_shutdown() {
+        let handle = builder()
+            .backends(Vec::<String>::new())
+            .admin_addr("127.0.0.1:0".parse().unwrap())
+            .build()
+            .await
+            .unwrap();
+        assert_ne!(handle.local_addr().port(), 0);
+        assert_ne!(handle.admin_addr().unwrap().port(), 0);
+        let server = tokio::spawn({
+            let handle = handle.clone();
+            async move { handle.serve().await }
+        });
+        // Something is accepting connections on the bound port
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        tokio::net::TcpStream::connect(handle.local_addr()).await.unwrap();
+        tokio::net::TcpStream::connect(handle.admin_addr().unwrap()).await.unwrap();
+        assert!(matches!(handle.serve().await, Err(LoadBalancerError::ServerFailed(_))));
+
+        handle.shutdown();
+        timeout(Duration::from_secs(5), server).await.unwrap().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_admin_api_only_binds_loopback_addresses_by_default() {
+        assert_eq!(admin_bind_addr("9090", false).unwrap(), "[::1]:9090");
+        assert_eq!(admin_bind_addr("127.0.0.1:9090", false).unwrap(), "127.0.0.1:9090");
+        assert!(matches!(admin_bind_addr("0.0.0.0:9090", false), Err(LoadBalancerError::InvalidConfig(_))));
+        assert_eq!(admin_bind_addr("0.0.0.0:9090", true).unwrap(), "0.0.0.0:9090");
+    }
+}
@@ -0,0 +1,356 @@
+use crate::hello_world::Metric;
+use crate::routing::Router;
+use crate::{select_server, LoadBalancerError};
+use http::header::{CONNECTION, HOST, PROXY_AUTHENTICATE, PROXY_AUTHORIZATION, TE, TRAILER, TRANSFER_ENCODING, UPGRADE};
+use http::{HeaderMap, Request, Response, StatusCode, Uri, Version};
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Empty, Full};
+use hyper::body::{Bytes, Incoming};
+use hyper::service::service_fn;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client as LegacyClient;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use hyper_util::server::graceful::GracefulShutdown;
+use serde::Deserialize;
+use std::convert::Infallible;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_stream::{Stream, StreamExt};
+use tonic::metadata::MetadataMap;
+use tonic::{Code, Status};
+use utils::inflight::InFlightGuard;
+use utils::loadreport::{LATENCY_TRAILER, RIF_TRAILER};
+
+/// Routing rules see proxied HTTP requests as this service, with the request path as the method
+pub const HTTP_SERVICE: &str = "http";
+
+pub type ProxyBody = BoxBody<Bytes, hyper::Error>;
+pub type HttpClient = LegacyClient<HttpConnector, ProxyBody>;
+
+/// Headers which only apply to a single connection and must not be forwarded
+const HOP_BY_HOP_HEADERS: [http::HeaderName; 7] = [
+    CONNECTION,
+    PROXY_AUTHENTICATE,
+    PROXY_AUTHORIZATION,
+    TE,
+    TRAILER,
+    TRANSFER_ENCODING,
+    UPGRADE,
+];
+
+pub fn http_client(http2: bool) -> HttpClient {
+    LegacyClient::builder(TokioExecutor::new()).http2_only(http2).build_http()
+}
+
+/**
+Load reported by an HTTP backend in the body of the probe response.
+Only `rif` and `latency` are required.
+*/
+#[derive(Deserialize, Debug, Default)]
+struct HttpLoadReport {
+    rif: u32,
+    latency: u64,
+    #[serde(default)]
+    server_id: String,
+    #[serde(default)]
+    start_epoch_ms: u64,
+    #[serde(default)]
+    queue_length: u32,
+    #[serde(default)]
+    cpu_utilization: f32,
+    #[serde(default)]
+    max_concurrency: u32,
+    #[serde(default)]
+    draining: bool,
+}
+
+impl From<HttpLoadReport> for Metric {
+    fn from(report: HttpLoadReport) -> Self {
+        Metric {
+            rif: report.rif,
+            latency: report.latency,
+            server_id: report.server_id,
+            start_epoch_ms: report.start_epoch_ms,
+            queue_length: report.queue_length,
+            cpu_utilization: report.cpu_utilization,
+            max_concurrency: report.max_concurrency,
+            draining: report.draining,
+            ..Default::default()
+        }
+    }
+}
+
+/**
+Fetches the load of an HTTP backend from `probe_path`.
+RIF and latency are read from the `prequal-rif` and `prequal-latency` headers when the backend sets them,
+otherwise from a JSON body like `{"rif": 3, "latency": 1200}`.
+*/
+pub async fn probe(client: &HttpClient, server: &str, probe_path: &str) -> Result<Metric, Status> {
+    let uri = format!("{}{}", server.trim_end_matches('/'), probe_path)
+        .parse::<Uri>()
+        .map_err(|error| Status::invalid_argument(format!("{server}{probe_path}: {error}")))?;
+    let request = Request::get(uri)
+        .body(Empty::new().map_err(|never| match never {}).boxed())
+        .map_err(|error| Status::internal(error.to_string()))?;
+    let response = client
+        .request(request)
+        .await
+        .map_err(|error| Status::unavailable(error.to_string()))?;
+    if !response.status().is_success() {
+        return Err(Status::unavailable(format!("The probe returned {}", response.status())));
+    }
+    if let Some(rif) = header_value::<u32>(response.headers(), RIF_TRAILER) {
+        return Ok(Metric {
+            rif,
+            latency: header_value(response.headers(), LATENCY_TRAILER).unwrap_or_default(),
+            ..Default::default()
+        });
+    }
+    let body = response
+        .into_body()
+        .collect()
+        .await
+        .map_err(|error| Status::unavailable(error.to_string()))?
+        .to_bytes();
+    serde_json::from_slice::<HttpLoadReport>(&body)
+        .map(Metric::from)
+        .map_err(|error| Status::internal(format!("Invalid load report: {error}")))
+}
+
+fn header_value<T: std::str::FromStr>(headers: &HeaderMap, name: &str) -> Option<T> {
+    headers.get(name)?.to_str().ok()?.parse().ok()
+}
+
+fn remove_hop_by_hop_headers(headers: &mut HeaderMap) {
+    for header in HOP_BY_HOP_HEADERS {
+        headers.remove(header);
+    }
+    headers.remove("keep-alive");
+}
+
+fn error_response(status: StatusCode, message: String) -> Response<ProxyBody> {
+    let mut response = Response::new(Full::new(Bytes::from(message)).map_err(|never| match never {}).boxed());
+    *response.status_mut() = status;
+    response
+}
+
+/**
+Reverse proxy which sends every HTTP request to the backend picked by the load balancer of its cluster
+*/
+#[derive(Debug, Clone)]
+pub struct HttpProxy {
+    router: Arc<Router>,
+    client: HttpClient,
+}
+
+impl HttpProxy {
+    pub fn new(router: Arc<Router>, http2_backends: bool) -> Self {
+        Self {
+            router,
+            client: http_client(http2_backends),
+        }
+    }
+    /**
+    Forwards the request to the selected backend and returns its response.
+    Backends which attach `prequal-rif` and `prequal-latency` headers to responses refresh their probe with them,
+    the headers are not passed on to the client.
+    The forwarding latency is recorded under `HTTP_SERVICE`, up to the response headers.
+    */
+    pub async fn forward(&self, request: Request<Incoming>) -> Response<ProxyBody> {
+        let received_at = Instant::now();
+        let metadata = MetadataMap::from_headers(request.headers().clone());
+        let path = request.uri().path().to_string();
+        let (load_balancer, server) = match select_server(&self.router, HTTP_SERVICE, &path, &metadata, None).await {
+            Ok(selected) => selected,
+            Err(status) if status.code() == Code::NotFound => {
+                return error_response(StatusCode::NOT_FOUND, status.message().to_string())
+            }
+            Err(status) => return error_response(StatusCode::SERVICE_UNAVAILABLE, status.message().to_string()),
+        };
+        let in_flight = InFlightGuard::new(server.in_flight.clone());
+        let (mut parts, body) = request.into_parts();
+        let path_and_query = parts.uri.path_and_query().map(|path| path.as_str()).unwrap_or("/");
+        parts.uri = match format!("{}{}", server.client_add.trim_end_matches('/'), path_and_query).parse() {
+            Ok(uri) => uri,
+            Err(error) => return error_response(StatusCode::BAD_GATEWAY, format!("Invalid backend address: {error}")),
+        };
+        // The client picks the protocol towards the backend and sets its host
+        parts.version = Version::HTTP_11;
+        parts.headers.remove(HOST);
+        remove_hop_by_hop_headers(&mut parts.headers);
+
+        match self.client.request(Request::from_parts(parts, body.boxed())).await {
+            Ok(response) => {
+                let (mut parts, body) = response.into_parts();
+                {
+                    let mut load_balancer = load_balancer.lock().await;
+                    if parts.headers.contains_key(RIF_TRAILER) {
+                        let report = MetadataMap::from_headers(parts.headers.clone());
+                        load_balancer.record_inline_report(&server.client_add, &report);
+                    }
+                    load_balancer.record_forwarding_latency(HTTP_SERVICE, received_at.elapsed());
+                }
+                parts.headers.remove(RIF_TRAILER);
+                parts.headers.remove(LATENCY_TRAILER);
+                remove_hop_by_hop_headers(&mut parts.headers);
+                // The request stays in flight until the whole body is sent
+                let body = body.map_frame(move |frame| {
+                    let _in_flight = &in_flight;
+                    frame
+                });
+                Response::from_parts(parts, body.boxed())
+            }
+            Err(error) => {
+                tracing::error!(%error, "Unable to forward the request to {}", server.client_add);
+                error_response(StatusCode::BAD_GATEWAY, "Unable to reach the backend".to_string())
+            }
+        }
+    }
+    /**
+    Serves HTTP/1.1 and HTTP/2 on the connections from `incoming` until `signal` resolves,
+    then waits for the open connections to finish their requests
+    */
+    pub async fn serve_with_incoming_shutdown<S, IO>(
+        self,
+        incoming: S,
+        signal: impl Future<Output = ()>,
+    ) -> Result<(), LoadBalancerError>
+    where
+        S: Stream<Item = Result<IO, std::io::Error>>,
+        IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let builder = auto::Builder::new(TokioExecutor::new());
+        let graceful = GracefulShutdown::new();
+        tokio::pin!(incoming);
+        tokio::pin!(signal);
+        loop {
+            let io = tokio::select! {
+                connection = incoming.next() => match connection {
+                    Some(Ok(io)) => io,
+                    Some(Err(error)) => {
+                        tracing::warn!(%error, "Unable to accept the connection");
+                        continue;
+                    }
+                    None => break,
+                },
+                _ = &mut signal => break,
+            };
+            let proxy = self.clone();
+            let service = service_fn(move |request| {
+                let proxy = proxy.clone();
+                async move { Ok::<_, Infallible>(proxy.forward(request).await) }
+            });
+            let connection = graceful.watch(builder.serve_connection(TokioIo::new(io), service).into_owned());
+            tokio::spawn(async move {
+                if let Err(error) = connection.await {
+                    tracing::debug!(%error, "The connection closed with an error");
+                }
+            });
+        }
+        graceful.shutdown().await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LoadBalancerBuilder;
+    use crate::{Mode, Policy};
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+    use tokio_stream::wrappers::TcpListenerStream;
+
+    /// Backend answering with its name and reporting the given load in JSON or headers
+    async fn spawn_backend(name: &'static str, rif: u32, latency: u64, report_in_headers: bool) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = format!("http://{}", listener.local_addr().unwrap());
+        let service = service_fn(move |request: Request<Incoming>| async move {
+            let response = match request.uri().path() {
+                "/prequal/load" if report_in_headers => Response::builder()
+                    .header(RIF_TRAILER, rif)
+                    .header(LATENCY_TRAILER, latency)
+                    .body(Full::new(Bytes::new())),
+                "/prequal/load" => Response::builder().body(Full::new(Bytes::from(format!(
+                    r#"{{"rif": {rif}, "latency": {latency}, "server_id": "{name}"}}"#
+                )))),
+                path if report_in_headers => Response::builder()
+                    .header(RIF_TRAILER, rif)
+                    .header(LATENCY_TRAILER, latency)
+                    .body(Full::new(Bytes::from(format!("{name} {path}")))),
+                path => Response::builder().body(Full::new(Bytes::from(format!("{name} {path}")))),
+            };
+            Ok::<_, Infallible>(response.unwrap())
+        });
+        tokio::spawn(async move {
+            let builder = auto::Builder::new(TokioExecutor::new());
+            let mut incoming = TcpListenerStream::new(listener);
+            while let Some(Ok(io)) = incoming.next().await {
+                tokio::spawn(builder.serve_connection(TokioIo::new(io), service).into_owned());
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_probe_reads_json_and_headers() {
+        let client = http_client(false);
+        let json = spawn_backend("json", 3, 1200, false).await;
+        let metric = probe(&client, &json, "/prequal/load").await.unwrap();
+        assert_eq!((metric.rif, metric.latency, metric.server_id.as_str()), (3, 1200, "json"));
+
+        let headers = spawn_backend("headers", 4, 900, true).await;
+        let metric = probe(&client, &headers, "/prequal/load").await.unwrap();
+        assert_eq!((metric.rif, metric.latency), (4, 900));
+
+        assert!(probe(&client, &json, "/missing").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_requests_are_proxied_to_the_cold_backend() {
+        let hot = spawn_backend("hot", 10, 1, false).await;
+        let cold = spawn_backend("cold", 1, 50, true).await;
+        let load_balancer = LoadBalancerBuilder::new()
+            .mode(Mode::Http)
+            .backends([hot, cold])
+            .policy(Policy::Prequal)
+            .listen_addr("127.0.0.1:0".parse().unwrap())
+            .probe_interval(Duration::from_millis(10))
+            .build()
+            .await
+            .unwrap();
+        let addr = load_balancer.local_addr();
+        let server = tokio::spawn({
+            let load_balancer = load_balancer.clone();
+            async move { load_balancer.serve().await }
+        });
+        let probed = async {
+            while load_balancer.router().clusters.values().next().unwrap().lock().await.probe_pool.len() < 2 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), probed).await.unwrap();
+
+        let client = http_client(false);
+        for version in [Version::HTTP_11, Version::HTTP_2] {
+            let client = if version == Version::HTTP_2 { http_client(true) } else { client.clone() };
+            let request = Request::get(format!("http://{addr}/hello?name=world"))
+                .body(Empty::new().map_err(|never| match never {}).boxed())
+                .unwrap();
+            let response = client.request(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert!(!response.headers().contains_key(RIF_TRAILER));
+            assert!(!response.headers().contains_key(LATENCY_TRAILER));
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            assert_eq!(body, "cold /hello");
+        }
+        let cluster = load_balancer.router().clusters.values().next().unwrap().clone();
+        assert!(cluster.lock().await.metric("").method_latency.contains_key(HTTP_SERVICE));
+
+        load_balancer.shutdown();
+        tokio::time::timeout(Duration::from_secs(5), server).await.unwrap().unwrap().unwrap();
+    }
+}
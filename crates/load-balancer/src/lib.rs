@@ -0,0 +1,1791 @@
+pub mod affinity;
+pub mod admin;
+mod builder;
+pub mod http_proxy;
+pub mod routing;
+mod tls;
+
+pub use builder::{LoadBalancerBuilder, LoadBalancerHandle};
+pub use prequal::Probe;
+
+use crate::hello_world::greeter_client::GreeterClient;
+use crate::affinity::{AffinityFields, AffinityKey, AffinityMode, HashRing};
+use crate::hello_world::{Empty, Metric};
+use crate::http_proxy::HttpClient;
+use crate::routing::Router;
+use prequal::prober::{ProbeRateConfig, ProbeResult, ProbeScheduler, ProbeUsage};
+use prequal::{prober, ProbePool};
+use hello_world::greeter_server::{Greeter, GreeterServer};
+use hello_world::{HelloReply, HelloRequest};
+use rand::seq::SliceRandom;
+use rand::thread_rng;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fmt::Debug;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64};
+use std::sync::atomic::Ordering::{Acquire, Release, SeqCst};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::sync::oneshot;
+use tokio::sync::Mutex;
+use tokio::task::JoinSet;
+use tokio::time::{interval, timeout};
+use tokio_rustls::rustls::server as rustls_server;
+use tokio_stream::Stream;
+use http_body_util::combinators::MapErr;
+use http_body_util::BodyExt;
+use tonic::body::BoxBody;
+use tonic::codec::{Codec, EncodeBuf, Encoder, ProstCodec};
+use tonic::codegen::http::uri::PathAndQuery;
+use tonic::codegen::Service;
+use tonic::server::NamedService;
+use tonic::transport::{Channel, ClientTlsConfig, Endpoint, Error};
+use tonic::metadata::MetadataMap;
+use tonic::{Code, Request, Response, Status, Streaming};
+use utils::clock::epoch_millis;
+use utils::inflight::InFlightGuard;
+use utils::loadreport::{LATENCY_TRAILER, RIF_TRAILER};
+use utils::latencies::RecentLatencies;
+
+/**
+Settings of the load balancer, read from the environment by the binary.
+Every field except the backends has a default.
+*/
+#[derive(Deserialize, Debug, Clone)]
+pub struct Config {
+    /// Address the gRPC listener binds to
+    #[serde(default = "default_listen_addr")]
+    listen_addr: String,
+    /// Serves the dashboard and JSON API when set, a bare port listens on `[::1]`
+    admin_addr: Option<String>,
+    /// The admin API has no authentication, it only binds loopback addresses unless this is set
+    #[serde(default)]
+    admin_allow_remote: bool,
+    /// Backends of the default cluster, ignored when a routing config file is given
+    #[serde(default)]
+    server_urls: String,
+    /// TOML file describing named clusters and the rules routing requests to them
+    routing_config_path: Option<String>,
+    #[serde(default)]
+    mode: Mode,
+    #[serde(default)]
+    policy: Policy,
+    /// Spreads the requests of the Prequal policy while no available server has a probe
+    #[serde(default)]
+    bootstrap_policy: BootstrapPolicy,
+    /// Runs a probing round before the listener is bound, so the first requests are balanced on probes
+    #[serde(default)]
+    wait_for_first_probe: bool,
+    #[serde(default = "default_q_rif")]
+    q_rif: f32,
+    /// Time between two probing rounds
+    #[serde(default = "default_probe_interval_ms")]
+    probe_interval_ms: u64,
+    /// Servers probed in every round
+    #[serde(default = "default_probe_pool_size")]
+    probe_pool_size: usize,
+    /// A probe not answered within this many milliseconds marks the server hot, it stays active
+    #[serde(default = "default_probe_timeout_ms")]
+    probe_timeout_ms: u64,
+    /// Adapts the probe interval so the probes used by requests are this old on average, fixed when unset
+    target_probe_age_ms: Option<u64>,
+    /// Bounds of the adapted probe interval
+    #[serde(default = "default_min_probe_interval_ms")]
+    min_probe_interval_ms: u64,
+    #[serde(default = "default_max_probe_interval_ms")]
+    max_probe_interval_ms: u64,
+    /// Probing speeds up when a probe is used by more requests than this
+    #[serde(default = "default_max_probe_reuse")]
+    max_probe_reuse: u32,
+    /// Share of the interval every delay between rounds is randomized by
+    #[serde(default = "default_probe_jitter")]
+    probe_jitter: f64,
+    /// Probes per forwarded request at most, 0 for no limit
+    #[serde(default)]
+    probe_budget: f64,
+    /// Milliseconds to wait for in-flight requests to finish once a shutdown signal is received
+    #[serde(default = "default_grace_period_ms")]
+    grace_period_ms: u64,
+    /// Certificate and key to terminate TLS on the listener, plain HTTP/2 when unset
+    tls_cert_path: Option<String>,
+    tls_key_path: Option<String>,
+    /// When set, clients must present a certificate signed by this CA
+    tls_client_ca_path: Option<String>,
+    /// CA used to verify `https://` backends
+    backend_tls_ca_path: Option<String>,
+    /// Client certificate and key presented to `https://` backends for mutual TLS
+    backend_tls_cert_path: Option<String>,
+    backend_tls_key_path: Option<String>,
+    /// Overrides the server name checked against the backend certificates
+    backend_tls_domain: Option<String>,
+    /// How often the certificate files are checked for changes, 0 turns reloading off
+    #[serde(default = "default_tls_reload_interval_secs")]
+    tls_reload_interval_secs: u64,
+    /// Pins requests with the same affinity key to one backend, Prequal is used when that backend is hot
+    #[serde(default)]
+    affinity_mode: AffinityMode,
+    /// `metadata:<header>` or `field:<name>`, required when an affinity mode is set
+    affinity_key: Option<String>,
+    /// Servers which reported their load on a response within this many milliseconds are not probed
+    #[serde(default = "default_inline_report_backoff_ms")]
+    inline_report_backoff_ms: u64,
+    /// Path on HTTP backends which reports their load
+    #[serde(default = "default_http_probe_path")]
+    http_probe_path: String,
+    /// Talk HTTP/2 without TLS to HTTP backends instead of HTTP/1.1
+    #[serde(default)]
+    http_backend_http2: bool,
+}
+fn default_listen_addr() -> String {
+    "[::1]:50051".to_string()
+}
+fn default_q_rif() -> f32 {
+    0.7
+}
+fn default_probe_interval_ms() -> u64 {
+    100
+}
+fn default_probe_pool_size() -> usize {
+    2
+}
+fn default_probe_timeout_ms() -> u64 {
+    100
+}
+fn default_min_probe_interval_ms() -> u64 {
+    10
+}
+fn default_max_probe_interval_ms() -> u64 {
+    1000
+}
+fn default_max_probe_reuse() -> u32 {
+    4
+}
+fn default_probe_jitter() -> f64 {
+    0.1
+}
+fn default_http_probe_path() -> String {
+    "/prequal/load".to_string()
+}
+fn default_grace_period_ms() -> u64 {
+    30_000
+}
+fn default_tls_reload_interval_secs() -> u64 {
+    10
+}
+fn default_inline_report_backoff_ms() -> u64 {
+    1000
+}
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen_addr: default_listen_addr(),
+            admin_addr: None,
+            admin_allow_remote: false,
+            server_urls: String::new(),
+            routing_config_path: None,
+            mode: Mode::default(),
+            policy: Policy::default(),
+            bootstrap_policy: BootstrapPolicy::default(),
+            wait_for_first_probe: false,
+            q_rif: default_q_rif(),
+            probe_interval_ms: default_probe_interval_ms(),
+            probe_pool_size: default_probe_pool_size(),
+            probe_timeout_ms: default_probe_timeout_ms(),
+            target_probe_age_ms: None,
+            min_probe_interval_ms: default_min_probe_interval_ms(),
+            max_probe_interval_ms: default_max_probe_interval_ms(),
+            max_probe_reuse: default_max_probe_reuse(),
+            probe_jitter: default_probe_jitter(),
+            probe_budget: 0.0,
+            grace_period_ms: default_grace_period_ms(),
+            tls_cert_path: None,
+            tls_key_path: None,
+            tls_client_ca_path: None,
+            backend_tls_ca_path: None,
+            backend_tls_cert_path: None,
+            backend_tls_key_path: None,
+            backend_tls_domain: None,
+            tls_reload_interval_secs: default_tls_reload_interval_secs(),
+            affinity_mode: AffinityMode::default(),
+            affinity_key: None,
+            inline_report_backoff_ms: default_inline_report_backoff_ms(),
+            http_probe_path: default_http_probe_path(),
+            http_backend_http2: false,
+        }
+    }
+}
+impl Config {
+    pub fn from_env() -> Result<Self, LoadBalancerError> {
+        envy::from_env::<Config>().map_err(|error| LoadBalancerError::InvalidConfig(error.to_string()))
+    }
+    /**
+    Reads the config again on SIGHUP. Values in the `.env` file win over the environment,
+    which still holds the values loaded from the file at startup, so edits to the file take effect.
+    */
+    pub fn reload() -> Result<Self, LoadBalancerError> {
+        let mut vars = env::vars().collect::<HashMap<String, String>>();
+        // Read the file without loading it, loading never overrides variables which are set already
+        if let Ok(file) = dotenvy::dotenv_iter() {
+            for item in file {
+                let (key, value) = item.map_err(|error| LoadBalancerError::InvalidConfig(error.to_string()))?;
+                vars.insert(key, value);
+            }
+        }
+        envy::from_iter::<_, Config>(vars).map_err(|error| LoadBalancerError::InvalidConfig(error.to_string()))
+    }
+    /**
+    How the probe rate adapts, shared by every cluster
+    */
+    fn probe_rate(&self) -> Result<ProbeRateConfig, LoadBalancerError> {
+        if self.target_probe_age_ms == Some(0) {
+            return Err(LoadBalancerError::InvalidConfig("the target probe age must not be zero".to_string()));
+        }
+        if self.min_probe_interval_ms == 0 || self.min_probe_interval_ms > self.max_probe_interval_ms {
+            return Err(LoadBalancerError::InvalidConfig(format!(
+                "the probe interval bounds {}ms to {}ms are not valid",
+                self.min_probe_interval_ms, self.max_probe_interval_ms
+            )));
+        }
+        if !(0.0..1.0).contains(&self.probe_jitter) {
+            return Err(LoadBalancerError::InvalidConfig(format!("probe jitter {} is not within [0, 1)", self.probe_jitter)));
+        }
+        if self.probe_budget.is_nan() || self.probe_budget < 0.0 {
+            return Err(LoadBalancerError::InvalidConfig(format!("probe budget {} is negative", self.probe_budget)));
+        }
+        Ok(ProbeRateConfig {
+            target_age: self.target_probe_age_ms.map(Duration::from_millis),
+            min_interval: Duration::from_millis(self.min_probe_interval_ms),
+            max_interval: Duration::from_millis(self.max_probe_interval_ms),
+            max_reuse: self.max_probe_reuse,
+            jitter: self.probe_jitter,
+            budget: self.probe_budget,
+        })
+    }
+    fn affinity_key(&self) -> Result<Option<AffinityKey>, LoadBalancerError> {
+        match (self.affinity_mode, &self.affinity_key) {
+            (AffinityMode::None, _) => Ok(None),
+            (_, Some(key)) => key.parse().map(Some).map_err(LoadBalancerError::InvalidAffinityKey),
+            (_, None) => Err(LoadBalancerError::InvalidAffinityKey(
+                "AFFINITY_KEY must be set with AFFINITY_MODE".to_string(),
+            )),
+        }
+    }
+    fn listener_tls(&self) -> Option<Result<Arc<rustls_server::ServerConfig>, LoadBalancerError>> {
+        match (&self.tls_cert_path, &self.tls_key_path) {
+            (Some(cert), Some(key)) => Some(tls::server_config(
+                cert,
+                key,
+                self.tls_client_ca_path.as_deref(),
+                self.mode == Mode::Http,
+            )),
+            _ => None,
+        }
+    }
+    /**
+    The HTTP proxy reaches its backends over plain HTTP, backend TLS is only for gRPC mode
+    */
+    fn check_http_backends(&self) -> Result<(), LoadBalancerError> {
+        if self.mode != Mode::Http {
+            return Ok(());
+        }
+        if let Some(url) = self.server_urls.split(',').find(|url| url.trim().starts_with("https://")) {
+            return Err(LoadBalancerError::InvalidConfig(format!("the HTTP proxy can't reach the backend {url} over TLS")));
+        }
+        let backend_tls = [
+            &self.backend_tls_ca_path,
+            &self.backend_tls_cert_path,
+            &self.backend_tls_key_path,
+            &self.backend_tls_domain,
+        ];
+        if backend_tls.iter().any(|setting| setting.is_some()) {
+            return Err(LoadBalancerError::InvalidConfig("backend TLS is only supported in gRPC mode".to_string()));
+        }
+        Ok(())
+    }
+    fn backend_tls(&self) -> Result<ClientTlsConfig, LoadBalancerError> {
+        let identity = match (&self.backend_tls_cert_path, &self.backend_tls_key_path) {
+            (Some(cert), Some(key)) => Some((cert.as_str(), key.as_str())),
+            _ => None,
+        };
+        tls::client_tls_config(
+            self.backend_tls_ca_path.as_deref(),
+            identity,
+            self.backend_tls_domain.as_deref(),
+        )
+    }
+}
+/**
+Settings which can change while the load balancer runs, through SIGHUP or the admin API.
+Settings which are not given are left alone.
+*/
+#[derive(Deserialize, Serialize, Debug, Default, Clone, PartialEq)]
+pub struct TuningUpdate {
+    pub policy: Option<Policy>,
+    pub q_rif: Option<f32>,
+    pub probe_pool_size: Option<usize>,
+    /// Shared by every cluster
+    pub probe_interval_ms: Option<u64>,
+}
+impl TuningUpdate {
+    /**
+    Every tunable setting of the config
+    */
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            policy: Some(config.policy),
+            q_rif: Some(config.q_rif),
+            probe_pool_size: Some(config.probe_pool_size),
+            probe_interval_ms: Some(config.probe_interval_ms),
+        }
+    }
+    pub fn validate(&self) -> Result<(), LoadBalancerError> {
+        if let Some(q_rif) = self.q_rif {
+            if !(q_rif > 0.0 && q_rif <= 1.0) {
+                return Err(LoadBalancerError::InvalidConfig(format!("q_rif {q_rif} is not within (0, 1]")));
+            }
+        }
+        if self.probe_interval_ms == Some(0) {
+            return Err(LoadBalancerError::InvalidConfig("the probe interval must not be zero".to_string()));
+        }
+        Ok(())
+    }
+}
+const GREETER_SERVICE: &str = <GreeterServer<MyGreeter> as tonic::server::NamedService>::NAME;
+pub use prequal::hello_world;
+/**
+Protocol spoken by the listener and the backends
+*/
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// gRPC Greeter service, backends are probed with `GetMetrics`
+    #[default]
+    Grpc,
+    /// Plain HTTP/1.1 and HTTP/2 reverse proxy, backends are probed on `http_probe_path`
+    Http,
+}
+/**
+How a server is picked for a request which is not pinned by affinity
+*/
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Policy {
+    /// Hot-cold lexicographic selection over the probe pool
+    #[default]
+    Prequal,
+    /// Cycles through the active servers, probes are ignored
+    RoundRobin,
+    /// Picks an active server at random, probes are ignored
+    Random,
+}
+/**
+How the Prequal policy picks a server before there is a probe to select from,
+at startup and after every probed server failed
+*/
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BootstrapPolicy {
+    #[default]
+    RoundRobin,
+    Random,
+}
+#[derive(Debug, Default)]
+pub struct MyGreeter {
+    router: Arc<Router>,
+}
+#[derive(Debug, Clone)]
+pub struct Client {
+    pub client_add: String,
+    /// Not connected for HTTP backends, those are reached through the HTTP client of the load balancer
+    pub client: GreeterClient<Channel>,
+    /// The connection behind `client`, for the streaming calls the generated client cannot forward as they are
+    pub channel: Channel,
+    pub is_active: Arc<AtomicBool>,
+    /// Calls and open streams this load balancer currently has on the server
+    pub in_flight: Arc<AtomicU32>,
+    /// Start time reported by the last probe, used to notice restarts
+    pub start_epoch_ms: Arc<AtomicU64>,
+    /// When the server last reported its load on a response trailer
+    pub last_inline_report_ms: Arc<AtomicU64>,
+    /// Set by an operator, a draining server gets no new requests but keeps being probed
+    pub draining: Arc<AtomicBool>,
+}
+impl Client {
+    /**
+    An active server, not probed yet
+    */
+    pub fn new(addr: String, channel: Channel) -> Self {
+        Self {
+            client_add: addr,
+            client: GreeterClient::new(channel.clone()),
+            channel,
+            is_active: Arc::new(AtomicBool::new(true)),
+            in_flight: Arc::new(AtomicU32::new(0)),
+            start_epoch_ms: Arc::new(AtomicU64::new(0)),
+            last_inline_report_ms: Arc::new(AtomicU64::new(0)),
+            draining: Arc::new(AtomicBool::new(false)),
+        }
+    }
+    /**
+    Whether new requests may be sent to the server
+    */
+    pub fn is_available(&self) -> bool {
+        self.is_active.load(SeqCst) && !self.draining.load(SeqCst)
+    }
+}
+#[derive(Debug, Default)]
+pub struct LoadBalancer {
+    pub clients: Vec<Client>,
+    pub probe_pool: ProbePool,
+    pub config: Config,
+    /// Applied to every `https://` backend
+    pub backend_tls: Option<ClientTlsConfig>,
+    /// Where to read the affinity key from, affinity routing is off when unset
+    pub affinity_key: Option<AffinityKey>,
+    /// Rebuilt whenever a backend is added or removed
+    pub hash_ring: Option<HashRing>,
+    /// Position of the round robin policy
+    next_client: usize,
+    /// Probes HTTP backends
+    http_client: Option<HttpClient>,
+    /// The most recent routing decisions, oldest first
+    pub decisions: VecDeque<Decision>,
+    /// Decisions recorded since the start, the sequence number of the next one
+    decision_count: u64,
+    /// Reported to an upstream load balancer probing this one, changes on restart
+    start_epoch_ms: u64,
+    /// Time from receiving a unary call to forwarding the response of the backend of the last calls, in nanoseconds
+    forwarding_latencies: RecentLatencies,
+    /// How the requests used the probes since the last probing round
+    probe_usage: ProbeUsage,
+}
+
+/// Routing decisions kept per cluster
+const DECISION_LOG_SIZE: usize = 1000;
+
+/// A backend which is added has to accept the connection within this time
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/**
+Why a server was picked for a request
+*/
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum SelectionReason {
+    /// Pinned by the affinity key
+    Affinity,
+    /// Cold probe with the lowest latency
+    Cold,
+    /// Every probe was hot, lowest RIF
+    Hot,
+    RoundRobin,
+    Random,
+    /// No available server had a probe yet, picked by the bootstrap policy
+    Bootstrap,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Decision {
+    /// Increases by one with every decision of the cluster, lets a reader continue where it left off
+    pub seq: u64,
+    pub timestamp_ms: u64,
+    pub server: String,
+    pub reason: SelectionReason,
+}
+
+#[derive(Error, Debug)]
+pub enum LoadBalancerError {
+    #[error("The route`{0}` is not found to delete")]
+    RouteNotFoundToDelete(String),
+    #[error("Unable to establish the connectivity `{0}`")]
+    UnableToEstablishConnectivity(String),
+    #[error("Unable to find the server for best probe")]
+    NoProbeFound,
+    #[error("No server is available")]
+    NoServerAvailable,
+    #[error("Invalid TLS configuration `{0}`")]
+    InvalidTlsConfig(String),
+    #[error("Invalid affinity key `{0}`")]
+    InvalidAffinityKey(String),
+    #[error("Invalid routing configuration `{0}`")]
+    InvalidRoutingConfig(String),
+    #[error("The cluster `{0}` is not defined")]
+    UnknownCluster(String),
+    #[error("No route matches `{0}`")]
+    NoRouteFound(String),
+    #[error("Invalid configuration `{0}`")]
+    InvalidConfig(String),
+    #[error("The server failed `{0}`")]
+    ServerFailed(String),
+    #[error("The grace period elapsed with requests still in flight")]
+    GracePeriodElapsed,
+    #[error("The backend `{0}` is not known")]
+    UnknownBackend(String),
+}
+impl AffinityFields for HelloRequest {
+    fn affinity_field(&self, field: &str) -> Option<&str> {
+        match field {
+            "name" => Some(&self.name),
+            _ => None,
+        }
+    }
+}
+
+impl LoadBalancer {
+    pub fn new(config: Config) -> Self {
+        let http_client = (config.mode == Mode::Http).then(|| http_proxy::http_client(config.http_backend_http2));
+        Self {
+            clients: vec![],
+            probe_pool: ProbePool::new(config.q_rif),
+            config,
+            backend_tls: None,
+            affinity_key: None,
+            hash_ring: None,
+            next_client: 0,
+            http_client,
+            decisions: VecDeque::with_capacity(DECISION_LOG_SIZE),
+            decision_count: 0,
+            start_epoch_ms: epoch_millis(),
+            forwarding_latencies: RecentLatencies::default(),
+            probe_usage: ProbeUsage::default(),
+        }
+    }
+    pub fn is_probe_hot(&self, probe: &Probe) -> bool {
+        self.probe_pool.is_hot(probe)
+    }
+    /**
+    Takes a server address starting with http or https and adds in the clients
+    */
+    pub async fn add_client(&mut self, addr: String) -> Result<(), LoadBalancerError> {
+        self.check_new_client(&addr)?;
+        let channel = connect_client(self.config.mode, &addr, self.backend_tls.as_ref()).await?;
+        self.insert_client(addr, channel)
+    }
+    /**
+    Fails when the backend is already one of the clients
+    */
+    pub fn check_new_client(&self, addr: &str) -> Result<(), LoadBalancerError> {
+        if self.clients.iter().any(|client| client.client_add.eq(addr)) {
+            return Err(LoadBalancerError::InvalidConfig(format!("the backend {addr} is already added")));
+        }
+        Ok(())
+    }
+    /**
+    Adds a backend connected with `connect_client`, which lets the connection be made without holding the lock
+    */
+    pub fn insert_client(&mut self, addr: String, channel: Channel) -> Result<(), LoadBalancerError> {
+        self.check_new_client(&addr)?;
+        self.clients.push(Client::new(addr, channel));
+        self.rebuild_hash_ring();
+        Ok(())
+    }
+    /**
+    This function is to remove any un-registered clients from the clients vector
+    */
+    pub async fn remove_client(&mut self, addr: String) -> Result<(), LoadBalancerError> {
+        let mut idx = usize::MAX;
+        for i in 0..self.clients.len() {
+            if self.clients[i].client_add.eq(&addr) {
+                idx = i;
+            }
+        }
+        if idx != usize::MAX {
+            self.clients.remove(idx);
+            self.rebuild_hash_ring();
+        }
+        Ok(())
+    }
+    /**
+    Stops sending new requests to the server, requests in flight finish normally.
+    The server stays in the cluster until it is removed.
+    */
+    pub fn drain_client(&mut self, addr: &str) -> Result<(), LoadBalancerError> {
+        let client = self
+            .clients
+            .iter()
+            .find(|client| client.client_add.eq(addr))
+            .ok_or_else(|| LoadBalancerError::UnknownBackend(addr.to_string()))?;
+        client.draining.store(true, SeqCst);
+        tracing::info!("Draining {}, {} requests in flight", addr, client.in_flight.load(Acquire));
+        Ok(())
+    }
+    /**
+    Applies the cluster settings of a validated update and logs every value which changed.
+    A new q_rif applies to the probes already in the pool.
+    */
+    pub fn apply_tuning(&mut self, cluster: &str, update: &TuningUpdate) {
+        if let Some(policy) = update.policy.filter(|policy| *policy != self.config.policy) {
+            tracing::info!(cluster, old = ?self.config.policy, new = ?policy, "Changed the policy");
+            self.config.policy = policy;
+        }
+        if let Some(q_rif) = update.q_rif.filter(|q_rif| *q_rif != self.config.q_rif) {
+            tracing::info!(cluster, old = self.config.q_rif, new = q_rif, "Changed q_rif");
+            self.config.q_rif = q_rif;
+            self.probe_pool.q_rif = q_rif;
+        }
+        if let Some(size) = update.probe_pool_size.filter(|size| *size != self.config.probe_pool_size) {
+            tracing::info!(cluster, old = self.config.probe_pool_size, new = size, "Changed the probe pool size");
+            self.config.probe_pool_size = size;
+        }
+    }
+    fn rebuild_hash_ring(&mut self) {
+        if self.affinity_key.is_none() {
+            return;
+        }
+        let backends = self.clients.iter().map(|client| client.client_add.clone()).collect::<Vec<String>>();
+        self.hash_ring = HashRing::new(self.config.affinity_mode, &backends);
+    }
+    /**
+    Reads the affinity key of the request from the configured metadata header or message field.
+    Streaming requests have no message up front, only metadata keys apply to them.
+    */
+    pub fn affinity_key_of(&self, metadata: &MetadataMap, message: Option<&dyn AffinityFields>) -> Option<String> {
+        match self.affinity_key.as_ref()? {
+            AffinityKey::Metadata(key) => metadata.get(key)?.to_str().ok().map(str::to_string),
+            AffinityKey::Field(field) => message?.affinity_field(field).map(str::to_string),
+        }
+        .filter(|key| !key.is_empty())
+    }
+    /**
+    Returns the backend the affinity key hashes to, as long as it is active and its latest probe is not hot.
+    A backend without a probe in the pool is assumed to be cold.
+    None means the caller should fall back to the regular Prequal selection.
+    */
+    pub fn get_affinity_server(&mut self, key: &str) -> Option<Client> {
+        let preferred = self.hash_ring.as_ref()?.pick(key.as_bytes());
+        let client = self
+            .clients
+            .iter()
+            .find(|client| client.client_add.eq(preferred) && client.is_available())?;
+        if let Some(probe) = self.probe_pool.get(preferred) {
+            if self.is_probe_hot(probe) || probe.draining {
+                tracing::debug!("The preferred server {} is hot or draining, falling back to prequal", preferred);
+                return None;
+            }
+            let times_used = probe.times_used.fetch_add(1, Acquire) + 1;
+            let age = probe.received_at.map(|received_at| received_at.elapsed()).unwrap_or_default();
+            self.probe_usage.record_selection(age, times_used);
+        }
+        Some(client.clone())
+    }
+    /**
+    Picks the server for a request with the configured policy
+    */
+    pub fn get_server(&mut self) -> Result<&mut Client, LoadBalancerError> {
+        match self.config.policy {
+            Policy::Prequal => self.get_prequal_server(),
+            Policy::RoundRobin => self.get_round_robin_server(),
+            Policy::Random => self.get_random_server(),
+        }
+    }
+    fn get_round_robin_server(&mut self) -> Result<&mut Client, LoadBalancerError> {
+        self.next_client = self.next_client.wrapping_add(1);
+        let active = self.active_clients();
+        let idx = *active
+            .get(self.next_client % active.len().max(1))
+            .ok_or(LoadBalancerError::NoServerAvailable)?;
+        Ok(&mut self.clients[idx])
+    }
+    fn get_random_server(&mut self) -> Result<&mut Client, LoadBalancerError> {
+        let idx = *self
+            .active_clients()
+            .choose(&mut thread_rng())
+            .ok_or(LoadBalancerError::NoServerAvailable)?;
+        Ok(&mut self.clients[idx])
+    }
+    /**
+    The available servers. Servers whose probe reports they are draining are left out, unless every one does.
+    */
+    fn active_clients(&self) -> Vec<usize> {
+        let available = (0..self.clients.len())
+            .filter(|idx| self.clients[*idx].is_available())
+            .collect::<Vec<usize>>();
+        let serving = available
+            .iter()
+            .copied()
+            .filter(|idx| !self.reports_draining(&self.clients[*idx].client_add))
+            .collect::<Vec<usize>>();
+        if serving.is_empty() {
+            available
+        } else {
+            serving
+        }
+    }
+    fn reports_draining(&self, server: &str) -> bool {
+        self.probe_pool.get(server).is_some_and(|probe| probe.draining)
+    }
+    /**
+    This function has to determine the best server to chose from the existing probe pool
+    Only probes of active servers are considered, see `ProbePool::select` for the rule.
+    Until one of them has a probe the bootstrap policy picks among the active servers.
+    */
+    fn get_prequal_server(&mut self) -> Result<&mut Client, LoadBalancerError> {
+        let clients = &self.clients;
+        let best_probe = self.probe_pool.select(|probe| {
+            clients
+                .iter()
+                .any(|client| client.client_add.eq(&probe.server) && client.is_available())
+        });
+        let Some(best_probe) = best_probe else {
+            tracing::debug!("No probe of an available server, bootstrapping with {:?}", self.config.bootstrap_policy);
+            return match self.config.bootstrap_policy {
+                BootstrapPolicy::RoundRobin => self.get_round_robin_server(),
+                BootstrapPolicy::Random => self.get_random_server(),
+            };
+        };
+        let times_used = best_probe.times_used.fetch_add(1, Acquire) + 1;
+        let age = best_probe.received_at.map(|received_at| received_at.elapsed()).unwrap_or_default();
+        let server = best_probe.server.clone();
+        self.probe_usage.record_selection(age, times_used);
+        self.clients
+            .iter_mut()
+            .find(|client| client.client_add.eq(&server))
+            .ok_or(LoadBalancerError::NoProbeFound)
+    }
+    /**
+    This function can be called by a job to frequently update the probe pool.
+    Probes the servers of a round at once and records the results, see `probe_cluster` to probe
+    without holding the balancer meanwhile.
+    */
+    pub async fn probe_servers(&mut self) {
+        let results = self.probe_round(self.config.probe_pool_size).run().await;
+        self.record_probe_results(results);
+    }
+    /**
+    This selects random servers of SIZE provided by `probe_pool_size`, or fewer when the probe budget is short,
+    to probe in the next round.
+    Servers which recently reported their load inline on a response are left out, their probe is fresh already.
+    */
+    fn probe_round(&self, allowed: usize) -> ProbeRound {
+        let now_ms = epoch_millis();
+        let backoff_ms = self.config.inline_report_backoff_ms;
+        let candidates = self
+            .clients
+            .iter()
+            .filter(|client| now_ms.saturating_sub(client.last_inline_report_ms.load(Acquire)) >= backoff_ms)
+            .cloned()
+            .collect::<Vec<Client>>();
+        ProbeRound {
+            targets: prober::choose_targets(&candidates, self.config.probe_pool_size.min(allowed)),
+            http_client: self.http_client.clone(),
+            http_probe_path: self.config.http_probe_path.clone(),
+            timeout: Duration::from_millis(self.config.probe_timeout_ms),
+        }
+    }
+    /**
+    Updates the probe pool with the results of a round.
+    A server which failed its probe is taken out until it answers again, one which did not answer in time stays in as hot.
+    Results of servers removed while they were probed are dropped.
+    */
+    fn record_probe_results(&mut self, results: Vec<(Client, ProbeResult)>) {
+        for (server, result) in results {
+            if !self.clients.iter().any(|client| client.client_add.eq(&server.client_add)) {
+                continue;
+            }
+            match result {
+                ProbeResult::Answered { metric, rtt } => {
+                    server.is_active.store(true, Release);
+                    let previous_start = server.start_epoch_ms.swap(metric.start_epoch_ms, SeqCst);
+                    if previous_start != 0 && previous_start != metric.start_epoch_ms {
+                        tracing::warn!(
+                            "The server {} restarted, started at {} and before at {}",
+                            server.client_add,
+                            metric.start_epoch_ms,
+                            previous_start
+                        );
+                    }
+                    if metric.draining {
+                        tracing::info!("The server {} reports it is draining, no new requests go to it", server.client_add);
+                    }
+                    self.record_probe(&server.client_add, &metric);
+                    self.probe_pool.record_rtt(&server.client_add, Some(rtt));
+                    tracing::debug!("pool after the probe {:?}", self.probe_pool);
+                    tracing::info! {
+                        %metric,
+                        ?rtt,
+                        "Received the metric response"
+                    }
+                }
+                ProbeResult::TimedOut => {
+                    tracing::warn!(
+                        "The server {} did not answer the probe within {}ms, taking it as hot",
+                        server.client_add,
+                        self.config.probe_timeout_ms
+                    );
+                    self.probe_pool.record_timeout(&server.client_add, epoch_millis());
+                }
+                ProbeResult::Failed(status) => {
+                    if status.code() != Code::Unavailable {
+                        tracing::error!("Server is not available for probing {:?}", server);
+                    }
+                    // Deletes the existing probe if any for this server
+                    self.probe_pool.remove(&server.client_add);
+                    server.is_active.store(false, SeqCst);
+                    tracing::info!("The server seems to be not active, Marking is_active false {:?}", server);
+                    tracing::error! {
+                        %status,
+                        "Failed to receive the response for probe "
+                    }
+                }
+            }
+        }
+    }
+    /**
+    Appends to the decision log, dropping the oldest entry once it is full
+    */
+    pub fn record_decision(&mut self, server: &str, reason: SelectionReason) {
+        if self.decisions.len() == DECISION_LOG_SIZE {
+            self.decisions.pop_front();
+        }
+        self.decisions.push_back(Decision {
+            seq: self.decision_count,
+            timestamp_ms: epoch_millis(),
+            server: server.to_string(),
+            reason,
+        });
+        self.decision_count += 1;
+        self.probe_usage.requests += 1;
+    }
+    /**
+    Replaces the probe of the server in the pool with the given metric
+    */
+    pub fn record_probe(&mut self, server: &str, metric: &Metric) {
+        self.probe_pool.record(server, metric);
+    }
+    /**
+    The load of this load balancer, as an upstream load balancer probing it sees it.
+    Its RIF are the calls it is forwarding, the latency is the median time the last calls took through it,
+    and the fleet RIF sums the RIF of the probes in its pool. Backends without a probe in the pool are left out,
+    the pool only holds the freshest probes, so the fleet RIF is a sample of the load of the backends.
+    */
+    pub fn metric(&self, server_id: &str) -> Metric {
+        Metric {
+            rif: self.clients.iter().map(|client| client.in_flight.load(Acquire)).sum(),
+            latency: self.forwarding_latencies.find_median().unwrap_or_default() as u64,
+            server_id: server_id.to_string(),
+            start_epoch_ms: self.start_epoch_ms,
+            timestamp_ms: epoch_millis(),
+            method_latency: self
+                .forwarding_latencies
+                .method_medians()
+                .into_iter()
+                .map(|(method, latency)| (method, latency as u64))
+                .collect(),
+            fleet_rif: self.probe_pool.probes.iter().map(|probe| probe.rif).sum(),
+            ..Default::default()
+        }
+    }
+    /**
+    Records how long a call took through the load balancer, reported by `metric`
+    */
+    pub fn record_forwarding_latency(&mut self, method: &str, latency: Duration) {
+        self.forwarding_latencies.add_latency(method, latency.as_nanos());
+    }
+    /**
+    Refreshes the probe of a server from the load it attached to the trailers of a response.
+    The other signals of its previous probe are kept, only RIF and latency are reported inline.
+    */
+    pub fn record_inline_report(&mut self, server: &str, metadata: &MetadataMap) {
+        let Some(rif) = metadata
+            .get(RIF_TRAILER)
+            .and_then(|value| value.to_str().ok()?.parse::<u32>().ok())
+        else {
+            return;
+        };
+        let Some(client) = self.clients.iter().find(|client| client.client_add.eq(server)) else {
+            return;
+        };
+        client.last_inline_report_ms.store(epoch_millis(), Release);
+        let latency = metadata
+            .get(LATENCY_TRAILER)
+            .and_then(|value| value.to_str().ok()?.parse::<u64>().ok());
+        let start_epoch_ms = client.start_epoch_ms.load(Acquire);
+        self.probe_pool.record_inline(server, rif, latency, start_epoch_ms, epoch_millis());
+    }
+}
+
+/**
+The servers probed in one round and what is needed to reach them, so the balancer need not be held meanwhile
+*/
+struct ProbeRound {
+    targets: Vec<Client>,
+    /// Probes HTTP backends, gRPC backends are probed with `GetMetrics` when unset
+    http_client: Option<HttpClient>,
+    http_probe_path: String,
+    timeout: Duration,
+}
+
+impl ProbeRound {
+    /**
+    Probes every target at once, each probe gets the timeout to be answered
+    */
+    async fn run(self) -> Vec<(Client, ProbeResult)> {
+        let (http_client, http_probe_path) = (self.http_client, self.http_probe_path);
+        prober::probe_all(self.targets, self.timeout, |server| {
+            let (mut client, server) = (server.client.clone(), server.client_add.clone());
+            let (http_client, http_probe_path) = (http_client.clone(), http_probe_path.clone());
+            async move {
+                match &http_client {
+                    Some(http_client) => http_proxy::probe(http_client, &server, &http_probe_path).await,
+                    None => client.get_metrics(Empty {}).await.map(Response::into_inner),
+                }
+            }
+        })
+        .await
+    }
+}
+
+/**
+Runs a probing round of the cluster, probing at most `allowed` servers.
+The balancer is only locked to pick the servers and to record the results, requests are not held up by slow probes.
+*/
+pub(crate) async fn probe_cluster(load_balancer: &Mutex<LoadBalancer>, allowed: usize) {
+    let round = load_balancer.lock().await.probe_round(allowed);
+    let results = round.run().await;
+    load_balancer.lock().await.record_probe_results(results);
+}
+
+type ReplyStream = Pin<Box<dyn Stream<Item = Result<HelloReply, Status>> + Send>>;
+
+impl MyGreeter {
+    async fn select_server(
+        &self,
+        method: &str,
+        metadata: &MetadataMap,
+        message: Option<&(dyn AffinityFields + Sync)>,
+    ) -> Result<(Arc<Mutex<LoadBalancer>>, Client), Status> {
+        select_server(&self.router, GREETER_SERVICE, method, metadata, message).await
+    }
+}
+
+/**
+Routes the request to a cluster and picks the server of that cluster which should serve it.
+The balancer lock is released before returning so the call itself does not block other requests.
+*/
+async fn select_server(
+    router: &Router,
+    service: &str,
+    method: &str,
+    metadata: &MetadataMap,
+    message: Option<&(dyn AffinityFields + Sync)>,
+) -> Result<(Arc<Mutex<LoadBalancer>>, Client), Status> {
+    let (cluster, load_balancer) = match router.route(service, method, metadata) {
+        Ok(route) => route,
+        Err(error) => {
+            tracing::error!(%error, "Unable to route the request {:?}", metadata);
+            return Err(Status::new(Code::NotFound, error.to_string()));
+        }
+    };
+    let mut lb = load_balancer.lock().await;
+    let affinity_server = lb
+        .affinity_key_of(metadata, message.map(|message| message as &dyn AffinityFields))
+        .and_then(|key| lb.get_affinity_server(&key));
+    let server = match affinity_server {
+        Some(server) => Ok((server, SelectionReason::Affinity)),
+        None => lb.get_server().cloned().map(|server| {
+            let reason = match lb.config.policy {
+                Policy::Prequal if lb.probe_pool.get(&server.client_add).is_none() => SelectionReason::Bootstrap,
+                Policy::Prequal if lb.probe_pool.get(&server.client_add).is_some_and(|probe| lb.is_probe_hot(probe)) => {
+                    SelectionReason::Hot
+                }
+                Policy::Prequal => SelectionReason::Cold,
+                Policy::RoundRobin => SelectionReason::RoundRobin,
+                Policy::Random => SelectionReason::Random,
+            };
+            (server, reason)
+        }),
+    };
+    match server {
+        Ok((server, reason)) => {
+            lb.record_decision(&server.client_add, reason);
+            tracing::debug!(
+                "Diverting the call to {} in cluster {} ({:?}), pool {:?}",
+                server.client_add,
+                cluster,
+                reason,
+                lb.probe_pool
+            );
+            Ok((load_balancer.clone(), server))
+        }
+        Err(LoadBalancerError::NoServerAvailable) => {
+            tracing::error!("No server is available for the request {:?}", metadata);
+            Err(Status::new(Code::Unavailable, LoadBalancerError::NoServerAvailable.to_string()))
+        }
+        Err(error) => {
+            tracing::error!(%error, "Internal error while getting the best server for the request {:?}", metadata);
+            Err(Status::new(
+                Code::Internal,
+                "Internal error while getting the best server",
+            ))
+        }
+    }
+}
+
+/**
+Records how long the call took through the load balancer and refreshes the probe of the server
+when it attached its load to the response trailers. That load is taken off the response,
+a caller balancing over load balancers must not mistake it for the load of this one.
+*/
+async fn finish_call(
+    load_balancer: &Mutex<LoadBalancer>,
+    server: &Client,
+    method: &str,
+    received_at: Instant,
+    response: &mut Result<Response<HelloReply>, Status>,
+) {
+    if let Ok(response) = response {
+        let mut load_balancer = load_balancer.lock().await;
+        load_balancer.record_forwarding_latency(method, received_at.elapsed());
+        if response.metadata().contains_key(RIF_TRAILER) {
+            load_balancer.record_inline_report(&server.client_add, response.metadata());
+            response.metadata_mut().remove(RIF_TRAILER);
+            response.metadata_mut().remove(LATENCY_TRAILER);
+        }
+    }
+}
+
+/**
+Passes a stream from the backend through to the caller.
+The guard is dropped with the stream, so the stream is counted in flight until it ends or the caller goes away.
+So is the call of a forwarded request stream, see `ForwardedRequests`.
+*/
+fn forward_stream(
+    response: Response<Streaming<HelloReply>>,
+    guard: InFlightGuard,
+    call: Option<oneshot::Sender<()>>,
+) -> Response<ReplyStream> {
+    let (metadata, inbound, extensions) = response.into_parts();
+    let outbound = ForwardedReplies {
+        inbound,
+        _in_flight: guard,
+        _call: call,
+    };
+    Response::from_parts(metadata, Box::pin(outbound) as ReplyStream, extensions)
+}
+
+struct ForwardedReplies {
+    inbound: Streaming<HelloReply>,
+    _in_flight: InFlightGuard,
+    _call: Option<oneshot::Sender<()>>,
+}
+
+impl Stream for ForwardedReplies {
+    type Item = Result<HelloReply, Status>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.inbound).poll_next(cx)
+    }
+}
+
+/**
+Stream of the caller forwarded to a backend, errors of the caller included, see `ForwardingCodec`.
+It fails once the call is dropped, which the server does when the caller resets the stream or its connection breaks,
+so the stream to the backend is reset even when the messages of the caller merely stop coming.
+*/
+struct ForwardedRequests {
+    inbound: Streaming<HelloRequest>,
+    /// Closed when the call is dropped
+    call: oneshot::Receiver<()>,
+    failed: bool,
+}
+
+impl ForwardedRequests {
+    /**
+    Wraps the stream of the request, the returned sender has to be kept as long as the call
+    */
+    fn new(request: Request<Streaming<HelloRequest>>) -> (Request<Self>, oneshot::Sender<()>) {
+        let (call, dropped) = oneshot::channel();
+        let request = request.map(|inbound| Self {
+            inbound,
+            call: dropped,
+            failed: false,
+        });
+        (request, call)
+    }
+}
+
+impl Stream for ForwardedRequests {
+    type Item = Result<HelloRequest, Status>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.failed {
+            return Poll::Ready(None);
+        }
+        if Pin::new(&mut self.call).poll(cx).is_ready() {
+            self.failed = true;
+            return Poll::Ready(Some(Err(Status::cancelled("The caller went away"))));
+        }
+        let next = Pin::new(&mut self.inbound).poll_next(cx);
+        self.failed = matches!(next, Poll::Ready(Some(Err(_))));
+        next
+    }
+}
+
+/**
+Codec of the streams forwarded to the backends. The generated client only takes messages, so a broken caller stream
+would reach the backend as a complete one. This one takes the stream of the caller as it is:
+an error of the caller fails the encoding, which resets the stream to the backend.
+*/
+#[derive(Default)]
+struct ForwardingCodec(ProstCodec<HelloRequest, HelloReply>);
+
+impl Codec for ForwardingCodec {
+    type Encode = Result<HelloRequest, Status>;
+    type Decode = HelloReply;
+    type Encoder = ForwardingEncoder;
+    type Decoder = <ProstCodec<HelloRequest, HelloReply> as Codec>::Decoder;
+
+    fn encoder(&mut self) -> Self::Encoder {
+        ForwardingEncoder(self.0.encoder())
+    }
+
+    fn decoder(&mut self) -> Self::Decoder {
+        self.0.decoder()
+    }
+}
+
+struct ForwardingEncoder(<ProstCodec<HelloRequest, HelloReply> as Codec>::Encoder);
+
+impl Encoder for ForwardingEncoder {
+    type Item = Result<HelloRequest, Status>;
+    type Error = Status;
+
+    fn encode(&mut self, item: Self::Item, dst: &mut EncodeBuf<'_>) -> Result<(), Self::Error> {
+        self.0.encode(item?, dst)
+    }
+}
+
+/**
+Wraps the Greeter service so that a caller which cancels its stream breaks it. Tonic ends a cancelled request stream
+like a complete one, which would complete the forwarded stream on the backend, see `ForwardingCodec`.
+*/
+#[derive(Clone)]
+pub(crate) struct FailCancelledStreams<S>(pub(crate) S);
+
+type CheckedBody = MapErr<BoxBody, fn(Status) -> Status>;
+
+impl<S: NamedService> NamedService for FailCancelledStreams<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+impl<S> Service<http::Request<BoxBody>> for FailCancelledStreams<S>
+where
+    S: Service<http::Request<CheckedBody>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<BoxBody>) -> Self::Future {
+        fn cancelled_to_aborted(status: Status) -> Status {
+            match status.code() {
+                Code::Cancelled => Status::aborted(format!("the caller cancelled the stream: {}", status.message())),
+                _ => status,
+            }
+        }
+        self.0.call(request.map(|body| body.map_err(cancelled_to_aborted as fn(Status) -> Status)))
+    }
+}
+
+#[tonic::async_trait]
+impl Greeter for MyGreeter {
+    /**
+    It has to find the best server to serve request
+    Update the RIF and Latencies of the requests
+    */
+    async fn say_hello(
+        &self,
+        request: Request<HelloRequest>,
+    ) -> Result<Response<HelloReply>, Status> {
+        let received_at = Instant::now();
+        let (load_balancer, mut server) = self.select_server("SayHello", request.metadata(), Some(request.get_ref())).await?;
+        let in_flight = InFlightGuard::new(server.in_flight.clone());
+        let mut response = server.client.say_hello(request).await;
+        drop(in_flight);
+        finish_call(&load_balancer, &server, "SayHello", received_at, &mut response).await;
+        response
+    }
+    /**
+    Reports the load of the cluster the probe is routed to, see `LoadBalancer::metric`.
+    This lets a load balancer in front balance over several load balancers like over backends.
+    */
+    async fn get_metrics(&self, request: Request<Empty>) -> Result<Response<Metric>, Status> {
+        let (cluster, load_balancer) = match self.router.route(GREETER_SERVICE, "GetMetrics", request.metadata()) {
+            Ok(route) => route,
+            Err(error) => return Err(Status::new(Code::NotFound, error.to_string())),
+        };
+        let metric = load_balancer.lock().await.metric(cluster);
+        Ok(Response::new(metric))
+    }
+
+    type LotsOfRepliesStream = ReplyStream;
+
+    async fn lots_of_replies(
+        &self,
+        request: Request<HelloRequest>,
+    ) -> Result<Response<Self::LotsOfRepliesStream>, Status> {
+        let (_, mut server) = self.select_server("LotsOfReplies", request.metadata(), Some(request.get_ref())).await?;
+        let in_flight = InFlightGuard::new(server.in_flight.clone());
+        let replies = server.client.lots_of_replies(request).await?;
+        Ok(forward_stream(replies, in_flight, None))
+    }
+
+    async fn lots_of_greetings(
+        &self,
+        request: Request<Streaming<HelloRequest>>,
+    ) -> Result<Response<HelloReply>, Status> {
+        let received_at = Instant::now();
+        let (load_balancer, server) = self.select_server("LotsOfGreetings", request.metadata(), None).await?;
+        let in_flight = InFlightGuard::new(server.in_flight.clone());
+        let (request, _call) = ForwardedRequests::new(request);
+        let mut grpc = tonic::client::Grpc::new(server.channel.clone());
+        let path = PathAndQuery::from_static("/helloworld.Greeter/LotsOfGreetings");
+        let mut response = match grpc.ready().await {
+            Ok(()) => grpc.client_streaming(request, path, ForwardingCodec::default()).await,
+            Err(error) => Err(Status::unknown(format!("Service was not ready: {error}"))),
+        };
+        drop(in_flight);
+        finish_call(&load_balancer, &server, "LotsOfGreetings", received_at, &mut response).await;
+        response
+    }
+
+    type BidiHelloStream = ReplyStream;
+
+    async fn bidi_hello(
+        &self,
+        request: Request<Streaming<HelloRequest>>,
+    ) -> Result<Response<Self::BidiHelloStream>, Status> {
+        let (_, server) = self.select_server("BidiHello", request.metadata(), None).await?;
+        let in_flight = InFlightGuard::new(server.in_flight.clone());
+        let mut grpc = tonic::client::Grpc::new(server.channel.clone());
+        grpc.ready().await.map_err(|error| Status::unknown(format!("Service was not ready: {error}")))?;
+        let path = PathAndQuery::from_static("/helloworld.Greeter/BidiHello");
+        let (request, call) = ForwardedRequests::new(request);
+        let replies = grpc.streaming(request, path, ForwardingCodec::default()).await?;
+        Ok(forward_stream(replies, in_flight, Some(call)))
+    }
+}
+
+/**
+This function takes care of initialising the clients defined the config
+TODO: Add more servers
+*/
+async fn initialise_load_balancer(balancer: Arc<Mutex<LoadBalancer>>, server_urls: Vec<String>) {
+    for server in &server_urls {
+        let res = balancer.lock().await.add_client(server.clone()).await;
+        match res {
+            Ok(_) => {
+                tracing::info!("Added the client {:?}", server);
+            }
+            Err(error) => {
+                tracing::info!(
+                    "Something wrong happened while connecting to the server {:?}, Error: {:?}",
+                    &server,
+                    error
+                );
+            }
+        }
+    }
+}
+
+
+/**
+Connects to a backend, using TLS when the address is `https://`
+*/
+async fn connect_backend(
+    addr: &str,
+    backend_tls: Option<&ClientTlsConfig>,
+) -> Result<Channel, Error> {
+    let mut endpoint = Endpoint::from_shared(addr.to_string())?;
+    if addr.starts_with("https://") {
+        endpoint = endpoint.tls_config(backend_tls.cloned().unwrap_or_default())?;
+    }
+    endpoint.connect().await
+}
+
+/**
+Connects to a new backend within `CONNECT_TIMEOUT`, HTTP backends are only checked by probing them
+*/
+pub async fn connect_client(
+    mode: Mode,
+    addr: &str,
+    backend_tls: Option<&ClientTlsConfig>,
+) -> Result<Channel, LoadBalancerError> {
+    if mode == Mode::Http && addr.starts_with("https://") {
+        return Err(LoadBalancerError::InvalidConfig(format!("the HTTP proxy can't reach the backend {addr} over TLS")));
+    }
+    let connection = async {
+        match mode {
+            Mode::Grpc => connect_backend(addr, backend_tls).await,
+            Mode::Http => Ok(Endpoint::from_shared(addr.to_string())?.connect_lazy()),
+        }
+    };
+    match timeout(CONNECT_TIMEOUT, connection).await {
+        Ok(Ok(channel)) => Ok(channel),
+        Ok(Err(error)) => Err(LoadBalancerError::UnableToEstablishConnectivity(error.to_string())),
+        Err(_) => Err(LoadBalancerError::UnableToEstablishConnectivity(format!(
+            "{addr} did not accept the connection within {CONNECT_TIMEOUT:?}"
+        ))),
+    }
+}
+
+/**
+Replaces the backend TLS config of the cluster and reconnects every `https://` backend with it.
+The connections are made concurrently without holding the lock, requests keep using the old channels meanwhile.
+Backends which fail to reconnect are marked inactive and picked up by the background task.
+*/
+async fn reload_backend_tls(load_balancer: &Mutex<LoadBalancer>, backend_tls: ClientTlsConfig) {
+    let addresses = {
+        let mut balancer = load_balancer.lock().await;
+        balancer.backend_tls = Some(backend_tls.clone());
+        balancer
+            .clients
+            .iter()
+            .filter(|client| client.client_add.starts_with("https://"))
+            .map(|client| client.client_add.clone())
+            .collect::<Vec<String>>()
+    };
+    let mut connections = JoinSet::new();
+    for addr in addresses {
+        let backend_tls = backend_tls.clone();
+        connections.spawn(async move {
+            let result = connect_backend(&addr, Some(&backend_tls)).await;
+            (addr, result)
+        });
+    }
+    let mut reconnected = Vec::new();
+    while let Some(connection) = connections.join_next().await {
+        match connection {
+            Ok(connection) => reconnected.push(connection),
+            Err(error) => tracing::error!(%error, "A backend reconnect task failed"),
+        }
+    }
+
+    let mut balancer = load_balancer.lock().await;
+    for (addr, result) in reconnected {
+        // Removed while reconnecting
+        let Some(client) = balancer.clients.iter_mut().find(|client| client.client_add == addr) else {
+            continue;
+        };
+        match result {
+            Ok(channel) => {
+                client.client = GreeterClient::new(channel.clone());
+                client.channel = channel;
+                client.is_active.store(true, Release);
+            }
+            Err(error) => {
+                tracing::error!(%error, "Unable to reconnect {} with the new TLS config", addr);
+                client.is_active.store(false, Release);
+            }
+        }
+    }
+}
+
+/**
+Periodically checks the configured certificate files and reloads them when they change.
+The listener picks the new certificate up for new connections, backends are reconnected.
+A broken certificate is logged and the previous one stays in use.
+An interval of 0 turns reloading off.
+*/
+async fn watch_certificates(
+    config: Config,
+    listener_tls: Option<tls::ReloadableServerConfig>,
+    router: Arc<Router>,
+) {
+    let mut listener_files = tls::FileWatcher::new(
+        [&config.tls_cert_path, &config.tls_key_path, &config.tls_client_ca_path]
+            .into_iter()
+            .flatten(),
+    );
+    let mut backend_files = tls::FileWatcher::new(
+        [&config.backend_tls_ca_path, &config.backend_tls_cert_path, &config.backend_tls_key_path]
+            .into_iter()
+            .flatten(),
+    );
+    if config.tls_reload_interval_secs == 0 {
+        tracing::info!("Certificate reloading is turned off");
+        return;
+    }
+    let mut interval = interval(Duration::from_secs(config.tls_reload_interval_secs));
+    loop {
+        interval.tick().await;
+        if let Some(listener_tls) = &listener_tls {
+            if listener_files.changed() {
+                match config.listener_tls() {
+                    Some(Ok(server_config)) => {
+                        *listener_tls.write().unwrap() = server_config;
+                        tracing::info!("Reloaded the listener certificates");
+                    }
+                    Some(Err(error)) => {
+                        tracing::error!(%error, "Unable to reload the listener certificates, keeping the old ones");
+                    }
+                    None => {}
+                }
+            }
+        }
+        if backend_files.changed() {
+            match config.backend_tls() {
+                Ok(backend_tls) => {
+                    for load_balancer in router.clusters.values() {
+                        reload_backend_tls(load_balancer, backend_tls.clone()).await;
+                    }
+                    tracing::info!("Reloaded the backend certificates");
+                }
+                Err(error) => {
+                    tracing::error!(%error, "Unable to reload the backend certificates, keeping the old ones");
+                }
+            }
+        }
+    }
+}
+
+/**
+Finds the inactive gRPC servers and tries to connect, each attempt gets the probe timeout.
+HTTP backends are marked active again once a probe succeeds.
+*/
+async fn reconnect_inactive(load_balancer: &Mutex<LoadBalancer>) {
+    let (inactive, backend_tls, connect_timeout) = {
+        let balancer = load_balancer.lock().await;
+        if balancer.config.mode != Mode::Grpc {
+            return;
+        }
+        let inactive = balancer
+            .clients
+            .iter()
+            .filter(|server| !server.is_active.load(Acquire))
+            .cloned()
+            .collect::<Vec<Client>>();
+        (inactive, balancer.backend_tls.clone(), Duration::from_millis(balancer.config.probe_timeout_ms))
+    };
+    for server in inactive {
+        tracing::info!("An inactive server is found, Trying to reconnect");
+        match timeout(connect_timeout, connect_backend(&server.client_add, backend_tls.as_ref())).await {
+            Ok(Ok(_)) => server.is_active.store(true, Release),
+            _ => tracing::error!("Unable to contact the server while ticking {:?}", server),
+        }
+    }
+}
+
+/**
+1. Finds the in active servers and tries to connect
+2. Probes the random server to update the metrics
+
+The delay until the next round adapts to how the requests used the probes, see `ProbeScheduler`.
+*/
+async fn background_process(
+    mut shutdown_signal: oneshot::Receiver<()>,
+    router: Arc<Router>,
+) {
+    let mut probe_interval = router.probe_interval();
+    let mut scheduler = ProbeScheduler::new(router.probe_rate(), probe_interval);
+    let mut last_round = Instant::now();
+    // The first round runs right away
+    let mut delay = Duration::ZERO;
+
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {
+                // Changed through the admin API
+                if router.probe_interval() != probe_interval {
+                    probe_interval = router.probe_interval();
+                    scheduler.set_interval(probe_interval);
+                }
+                let mut usage = ProbeUsage::default();
+                let mut wanted = Vec::with_capacity(router.clusters.len());
+                for load_balancer in router.clusters.values() {
+                    let mut balancer = load_balancer.lock().await;
+                    usage.merge(&std::mem::take(&mut balancer.probe_usage));
+                    wanted.push(balancer.config.probe_pool_size);
+                }
+                scheduler.adapt(&usage, last_round.elapsed());
+                last_round = Instant::now();
+                router.set_adapted_probe_interval(scheduler.interval());
+                tracing::debug!("Probing the servers, {:?} until the next round", scheduler.interval());
+                for (load_balancer, wanted) in router.clusters.values().zip(wanted) {
+                    reconnect_inactive(load_balancer).await;
+                    probe_cluster(load_balancer, scheduler.allow(wanted)).await;
+                }
+                delay = scheduler.next_delay(&mut thread_rng());
+            }
+            _ = &mut shutdown_signal => {
+                // Clean up before exiting
+                tracing::info!("Background task received shutdown signal.");
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routing::RoutingConfig;
+
+    /// Client which only connects on its first call, shared with the tests of the other modules
+    pub(crate) fn lazy_client(addr: &str) -> Client {
+        Client::new(addr.to_string(), Endpoint::from_shared(addr.to_string()).unwrap().connect_lazy())
+    }
+
+    fn probe(server: &str, normalized_rif: f32) -> Probe {
+        Probe {
+            server: server.to_string(),
+            normalized_rif,
+            ..Default::default()
+        }
+    }
+
+    fn affinity_balancer() -> LoadBalancer {
+        let mut lb = LoadBalancer::new(Config {
+            q_rif: 0.7,
+            affinity_mode: AffinityMode::Ring,
+            ..Default::default()
+        });
+        lb.affinity_key = Some(AffinityKey::Metadata("x-session-id".to_string()));
+        lb.clients = vec![lazy_client("http://[::1]:50052"), lazy_client("http://[::1]:50053")];
+        lb.rebuild_hash_ring();
+        lb
+    }
+
+    #[tokio::test]
+    async fn test_affinity_key_from_metadata_and_field() {
+        let mut lb = affinity_balancer();
+        let mut request = Request::new(HelloRequest { name: "world".to_string() });
+        assert_eq!(lb.affinity_key_of(request.metadata(), Some(request.get_ref())), None);
+        request.metadata_mut().insert("x-session-id", "session-1".parse().unwrap());
+        assert_eq!(lb.affinity_key_of(request.metadata(), Some(request.get_ref())), Some("session-1".to_string()));
+
+        lb.affinity_key = Some(AffinityKey::Field("name".to_string()));
+        assert_eq!(lb.affinity_key_of(request.metadata(), Some(request.get_ref())), Some("world".to_string()));
+        assert_eq!(lb.affinity_key_of(request.metadata(), None), None);
+    }
+
+    #[tokio::test]
+    async fn test_affinity_falls_back_when_preferred_server_is_hot() {
+        let mut lb = affinity_balancer();
+        let preferred = lb.hash_ring.as_ref().unwrap().pick(b"session-1").to_string();
+        assert!(lb.get_affinity_server("session-1").is_some());
+
+        lb.probe_pool.probes = vec![probe(&preferred, 0.9)];
+        assert!(lb.get_affinity_server("session-1").is_none());
+
+        lb.probe_pool.probes = vec![probe(&preferred, 0.2)];
+        assert!(lb.get_affinity_server("session-1").is_some());
+        assert_eq!(lb.probe_pool.probes[0].times_used.load(SeqCst), 1);
+        assert_eq!(lb.probe_usage.selections, 1);
+    }
+
+    #[tokio::test]
+    async fn test_inline_report_refreshes_probe() {
+        let mut lb = affinity_balancer();
+        let server = "http://[::1]:50052";
+        lb.record_probe(
+            server,
+            &Metric {
+                rif: 10,
+                latency: 500,
+                server_id: "server 1".to_string(),
+                ..Default::default()
+            },
+        );
+        lb.probe_pool.probes[0].times_used.store(3, SeqCst);
+
+        let mut metadata = MetadataMap::new();
+        metadata.insert(RIF_TRAILER, "4".parse().unwrap());
+        lb.record_inline_report(server, &metadata);
+        assert_eq!(lb.probe_pool.len(), 1);
+        assert_eq!(lb.probe_pool.probes[0].rif, 4);
+        assert_eq!(lb.probe_pool.probes[0].normalized_rif, 0.4);
+        // Signals which were not reported inline are kept
+        assert_eq!(lb.probe_pool.probes[0].latency, 500);
+        assert_eq!(lb.probe_pool.probes[0].server_id, "server 1");
+        assert_eq!(lb.probe_pool.probes[0].times_used.load(SeqCst), 0);
+
+        metadata.insert(LATENCY_TRAILER, "700".parse().unwrap());
+        lb.record_inline_report(server, &metadata);
+        assert_eq!(lb.probe_pool.probes[0].latency, 700);
+    }
+
+    #[tokio::test]
+    async fn test_inline_reporting_servers_are_not_probed() {
+        let mut lb = affinity_balancer();
+        let mut metadata = MetadataMap::new();
+        metadata.insert(RIF_TRAILER, "1".parse().unwrap());
+        lb.config.inline_report_backoff_ms = 60_000;
+        lb.record_inline_report("http://[::1]:50052", &metadata);
+        lb.record_inline_report("http://[::1]:50053", &metadata);
+
+        // Both servers reported inline, probing would fail against the lazy clients otherwise
+        lb.probe_servers().await;
+        assert_eq!(lb.probe_pool.len(), 2);
+        assert!(lb.clients.iter().all(|client| client.is_active.load(SeqCst)));
+    }
+
+    #[tokio::test]
+    async fn test_round_robin_skips_inactive_servers() {
+        let mut lb = affinity_balancer();
+        lb.config.policy = Policy::RoundRobin;
+        lb.clients.push(lazy_client("http://[::1]:50054"));
+        lb.clients[1].is_active.store(false, SeqCst);
+        let picked = (0..4)
+            .map(|_| lb.get_server().unwrap().client_add.clone())
+            .collect::<Vec<String>>();
+        assert_eq!(picked[0], picked[2]);
+        assert_eq!(picked[1], picked[3]);
+        assert_ne!(picked[0], picked[1]);
+        assert!(!picked.contains(&"http://[::1]:50053".to_string()));
+
+        lb.clients.iter().for_each(|client| client.is_active.store(false, SeqCst));
+        assert!(lb.get_server().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_draining_server_is_not_picked_while_another_is_available() {
+        let mut lb = affinity_balancer();
+        lb.probe_pool.record("http://[::1]:50052", &Metric { rif: 0, latency: 1, draining: true, ..Default::default() });
+        lb.probe_pool.record("http://[::1]:50053", &Metric { rif: 9, latency: 50, ..Default::default() });
+        assert!((0..10).all(|_| lb.get_server().unwrap().client_add == "http://[::1]:50053"));
+        assert!(lb.get_affinity_server("session-1").is_none_or(|client| client.client_add == "http://[::1]:50053"));
+
+        // Without a probe of the other server the bootstrap leaves the draining one out as well
+        lb.probe_pool.remove("http://[::1]:50053");
+        for policy in [Policy::Prequal, Policy::RoundRobin, Policy::Random] {
+            lb.config.policy = policy;
+            assert!((0..10).all(|_| lb.get_server().unwrap().client_add == "http://[::1]:50053"));
+        }
+
+        // Still served when every server is draining
+        lb.probe_pool.record("http://[::1]:50053", &Metric { draining: true, ..Default::default() });
+        assert!(lb.get_server().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_prequal_bootstraps_until_probes_arrive() {
+        let mut lb = affinity_balancer();
+        let picked = (0..4)
+            .map(|_| lb.get_server().unwrap().client_add.clone())
+            .collect::<Vec<String>>();
+        assert_eq!(picked[0], picked[2]);
+        assert_ne!(picked[0], picked[1]);
+
+        // A probe of an inactive server does not end the bootstrap
+        lb.probe_pool.probes = vec![probe("http://[::1]:50053", 0.0)];
+        lb.clients[1].is_active.store(false, SeqCst);
+        lb.config.bootstrap_policy = BootstrapPolicy::Random;
+        assert!((0..10).all(|_| lb.get_server().unwrap().client_add == "http://[::1]:50052"));
+
+        lb.clients[1].is_active.store(true, SeqCst);
+        assert!((0..10).all(|_| lb.get_server().unwrap().client_add == "http://[::1]:50053"));
+
+        lb.clients.iter().for_each(|client| client.is_active.store(false, SeqCst));
+        assert!(matches!(lb.get_server(), Err(LoadBalancerError::NoServerAvailable)));
+    }
+
+    #[tokio::test]
+    async fn test_bootstrap_decisions_and_unavailable_status() {
+        let config = Config::default();
+        let router = Router::new(&RoutingConfig::from_env(&config), &config).unwrap();
+        let load_balancer = router.clusters[routing::DEFAULT_CLUSTER].clone();
+        load_balancer.lock().await.clients = vec![lazy_client("http://[::1]:50052")];
+
+        let (_, server) = select_server(&router, GREETER_SERVICE, "SayHello", &MetadataMap::new(), None).await.unwrap();
+        assert_eq!(server.client_add, "http://[::1]:50052");
+        assert_eq!(load_balancer.lock().await.decisions[0].reason, SelectionReason::Bootstrap);
+
+        load_balancer.lock().await.clients[0].is_active.store(false, SeqCst);
+        let status = select_server(&router, GREETER_SERVICE, "SayHello", &MetadataMap::new(), None).await.unwrap_err();
+        assert_eq!(status.code(), Code::Unavailable);
+    }
+
+    #[tokio::test]
+    async fn test_requests_record_how_they_use_the_probes() {
+        let config = Config::default();
+        let router = Router::new(&RoutingConfig::from_env(&config), &config).unwrap();
+        let load_balancer = router.clusters[routing::DEFAULT_CLUSTER].clone();
+        {
+            let mut lb = load_balancer.lock().await;
+            lb.clients = vec![lazy_client("http://[::1]:50052"), lazy_client("http://[::1]:50053")];
+            lb.probe_pool.probes = vec![Probe {
+                received_at: Some(Instant::now() - Duration::from_millis(40)),
+                ..probe("http://[::1]:50052", 0.0)
+            }];
+        }
+        for _ in 0..3 {
+            select_server(&router, GREETER_SERVICE, "SayHello", &MetadataMap::new(), None).await.unwrap();
+        }
+        let usage = std::mem::take(&mut load_balancer.lock().await.probe_usage);
+        assert_eq!((usage.requests, usage.selections, usage.max_reuse), (3, 3, 3));
+        assert!(usage.total_age >= Duration::from_millis(120));
+
+        // Round robin does not use the probes
+        load_balancer.lock().await.config.policy = Policy::RoundRobin;
+        select_server(&router, GREETER_SERVICE, "SayHello", &MetadataMap::new(), None).await.unwrap();
+        let usage = load_balancer.lock().await.probe_usage;
+        assert_eq!((usage.requests, usage.selections), (1, 0));
+    }
+
+    #[test]
+    fn test_probe_rate_config_is_validated() {
+        let rate = Config { target_probe_age_ms: Some(50), probe_budget: 0.2, ..Default::default() }.probe_rate().unwrap();
+        assert_eq!(rate.target_age, Some(Duration::from_millis(50)));
+        assert_eq!((rate.min_interval, rate.max_interval), (Duration::from_millis(10), Duration::from_secs(1)));
+        for config in [
+            Config { target_probe_age_ms: Some(0), ..Default::default() },
+            Config { min_probe_interval_ms: 2000, ..Default::default() },
+            Config { probe_jitter: 1.0, ..Default::default() },
+            Config { probe_budget: -1.0, ..Default::default() },
+        ] {
+            assert!(matches!(config.probe_rate(), Err(LoadBalancerError::InvalidConfig(_))));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_zero_tls_reload_interval_turns_reloading_off() {
+        let config = Config { tls_reload_interval_secs: 0, ..Default::default() };
+        let router = Arc::new(Router::new(&RoutingConfig::from_env(&config), &config).unwrap());
+        timeout(Duration::from_secs(1), watch_certificates(config, None, router)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_metrics_report_the_load_of_the_load_balancer() {
+        let config = Config::default();
+        let router = Arc::new(Router::new(&RoutingConfig::from_env(&config), &config).unwrap());
+        let greeter = MyGreeter { router: router.clone() };
+        let metric = greeter.get_metrics(Request::new(Empty {})).await.unwrap().into_inner();
+        assert_eq!((metric.rif, metric.latency, metric.fleet_rif), (0, 0, 0));
+        assert_eq!(metric.server_id, routing::DEFAULT_CLUSTER);
+        assert_ne!(metric.start_epoch_ms, 0);
+
+        {
+            let mut lb = router.clusters[routing::DEFAULT_CLUSTER].lock().await;
+            lb.clients = vec![lazy_client("http://[::1]:50052"), lazy_client("http://[::1]:50053")];
+            lb.clients[0].in_flight.store(2, SeqCst);
+            lb.clients[1].in_flight.store(1, SeqCst);
+            lb.probe_pool.probes = [("http://[::1]:50052", 5), ("http://[::1]:50053", 7)]
+                .map(|(server, rif)| Probe { rif, ..probe(server, 0.0) })
+                .to_vec();
+            lb.forwarding_latencies.add_latency("SayHello", 100);
+            lb.forwarding_latencies.add_latency("SayHello", 300);
+        }
+        let metric = greeter.get_metrics(Request::new(Empty {})).await.unwrap().into_inner();
+        assert_eq!((metric.rif, metric.latency, metric.fleet_rif), (3, 200, 12));
+        assert_eq!(metric.method_latency["SayHello"], 200);
+    }
+
+    #[tokio::test]
+    async fn test_affinity_falls_back_when_preferred_server_is_inactive() {
+        let mut lb = affinity_balancer();
+        let preferred = lb.hash_ring.as_ref().unwrap().pick(b"session-1").to_string();
+        let client = lb.clients.iter().find(|client| client.client_add.eq(&preferred)).unwrap();
+        client.is_active.store(false, SeqCst);
+        assert!(lb.get_affinity_server("session-1").is_none());
+    }
+}
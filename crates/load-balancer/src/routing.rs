@@ -0,0 +1,472 @@
+use crate::affinity::AffinityMode;
+use crate::{Config, LoadBalancer, LoadBalancerError, Policy, TuningUpdate};
+use prequal::prober::ProbeRateConfig;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tonic::metadata::MetadataMap;
+
+/// Cluster used when no routing config file is given
+pub const DEFAULT_CLUSTER: &str = "default";
+
+/**
+Contents of the file pointed to by ROUTING_CONFIG_PATH
+*/
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct RoutingConfig {
+    pub clusters: Vec<ClusterConfig>,
+    #[serde(default)]
+    pub routes: Vec<RouteRule>,
+    /// Receives the requests no rule matches, defaults to the only cluster if there is just one
+    pub default_cluster: Option<String>,
+}
+
+/**
+A named set of backends with its own probe pool and policy settings.
+Settings which are not given are taken from the environment config.
+*/
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct ClusterConfig {
+    pub name: String,
+    pub server_urls: Vec<String>,
+    pub policy: Option<Policy>,
+    pub q_rif: Option<f32>,
+    pub affinity_mode: Option<AffinityMode>,
+    pub affinity_key: Option<String>,
+}
+
+/**
+Sends requests to `cluster` when every condition given matches.
+`service` is the fully qualified gRPC service name like `helloworld.Greeter` and `method` the bare method name.
+*/
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct RouteRule {
+    pub cluster: String,
+    pub service: Option<String>,
+    pub method: Option<String>,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+impl RouteRule {
+    pub fn matches(&self, service: &str, method: &str, metadata: &MetadataMap) -> bool {
+        self.service.as_deref().is_none_or(|expected| expected == service)
+            && self.method.as_deref().is_none_or(|expected| expected == method)
+            && self.headers.iter().all(|(key, expected)| {
+                metadata
+                    .get(key.as_str())
+                    .and_then(|value| value.to_str().ok())
+                    .is_some_and(|value| value == expected)
+            })
+    }
+}
+
+impl RoutingConfig {
+    pub fn from_file(path: &str) -> Result<Self, LoadBalancerError> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|error| LoadBalancerError::InvalidRoutingConfig(format!("{path}: {error}")))?;
+        toml::from_str(&contents).map_err(|error| LoadBalancerError::InvalidRoutingConfig(format!("{path}: {error}")))
+    }
+    /**
+    A single cluster built from SERVER_URLS which receives every request
+    */
+    pub fn from_env(config: &Config) -> Self {
+        Self {
+            clusters: vec![ClusterConfig {
+                name: DEFAULT_CLUSTER.to_string(),
+                server_urls: config
+                    .server_urls
+                    .split(",")
+                    .filter(|item| !item.is_empty())
+                    .map(|item| item.to_string())
+                    .collect(),
+                ..Default::default()
+            }],
+            routes: vec![],
+            default_cluster: Some(DEFAULT_CLUSTER.to_string()),
+        }
+    }
+}
+
+/**
+Holds a load balancer per cluster and picks one for every request based on the routing rules.
+Rules are evaluated in order and the first match wins.
+*/
+#[derive(Debug, Default)]
+pub struct Router {
+    pub clusters: HashMap<String, Arc<Mutex<LoadBalancer>>>,
+    pub rules: Vec<RouteRule>,
+    pub default_cluster: Option<String>,
+    /// Shared by every cluster, the background task probes all of them at once
+    probe_interval_ms: AtomicU64,
+    /// How the background task adapts the probe interval
+    probe_rate: ProbeRateConfig,
+    /// The interval as adapted by the background task
+    adapted_probe_interval_ms: AtomicU64,
+}
+
+impl Router {
+    /**
+    Creates an empty load balancer for every cluster, backends are added afterwards
+    */
+    pub fn new(routing: &RoutingConfig, config: &Config) -> Result<Self, LoadBalancerError> {
+        let probe_rate = config.probe_rate()?;
+        let mut clusters = HashMap::new();
+        for cluster in &routing.clusters {
+            let mut cluster_config = config.clone();
+            cluster_config.server_urls = cluster.server_urls.join(",");
+            if let Some(policy) = cluster.policy {
+                cluster_config.policy = policy;
+            }
+            if let Some(q_rif) = cluster.q_rif {
+                cluster_config.q_rif = q_rif;
+            }
+            if let Some(affinity_mode) = cluster.affinity_mode {
+                cluster_config.affinity_mode = affinity_mode;
+            }
+            if cluster.affinity_key.is_some() {
+                cluster_config.affinity_key = cluster.affinity_key.clone();
+            }
+            // Startup takes the same checks as the settings changed at runtime
+            TuningUpdate::from_config(&cluster_config).validate()?;
+            cluster_config.check_http_backends()?;
+            let mut balancer = LoadBalancer::new(cluster_config.clone());
+            balancer.backend_tls = Some(cluster_config.backend_tls()?);
+            balancer.affinity_key = cluster_config.affinity_key()?;
+            if clusters.insert(cluster.name.clone(), Arc::new(Mutex::new(balancer))).is_some() {
+                return Err(LoadBalancerError::InvalidRoutingConfig(format!(
+                    "cluster `{}` is defined twice",
+                    cluster.name
+                )));
+            }
+        }
+        let default_cluster = match &routing.default_cluster {
+            Some(name) => Some(name.clone()),
+            None if routing.clusters.len() == 1 => Some(routing.clusters[0].name.clone()),
+            None => None,
+        };
+        let referenced = routing.routes.iter().map(|rule| &rule.cluster).chain(default_cluster.as_ref());
+        for name in referenced {
+            if !clusters.contains_key(name) {
+                return Err(LoadBalancerError::UnknownCluster(name.clone()));
+            }
+        }
+        Ok(Self {
+            clusters,
+            rules: routing.routes.clone(),
+            default_cluster,
+            probe_interval_ms: AtomicU64::new(config.probe_interval_ms),
+            probe_rate,
+            adapted_probe_interval_ms: AtomicU64::new(config.probe_interval_ms),
+        })
+    }
+    pub fn probe_interval(&self) -> Duration {
+        Duration::from_millis(self.probe_interval_ms.load(Relaxed))
+    }
+    pub fn probe_rate(&self) -> ProbeRateConfig {
+        self.probe_rate
+    }
+    /**
+    The probe interval the background task currently runs with, the configured one unless it adapts
+    */
+    pub fn adapted_probe_interval(&self) -> Duration {
+        Duration::from_millis(self.adapted_probe_interval_ms.load(Relaxed))
+    }
+    pub(crate) fn set_adapted_probe_interval(&self, interval: Duration) {
+        self.adapted_probe_interval_ms.store(interval.as_millis() as u64, Relaxed);
+    }
+    /**
+    Applies the update to the given cluster, or to every cluster when none is given
+    */
+    pub async fn update_tuning(&self, cluster: Option<&str>, update: &TuningUpdate) -> Result<(), LoadBalancerError> {
+        let names = match cluster {
+            Some(name) if !self.clusters.contains_key(name) => {
+                return Err(LoadBalancerError::UnknownCluster(name.to_string()));
+            }
+            Some(name) => vec![name],
+            None => self.clusters.keys().map(String::as_str).collect(),
+        };
+        let updates = names.into_iter().map(|name| (name, update.clone())).collect();
+        self.apply_tuning(updates, update.probe_interval_ms).await
+    }
+    /**
+    Applies the tunable settings of a reloaded config. Every cluster gets the settings of the environment,
+    overridden by its entry in the routing config. Clusters and backends themselves only change on restart.
+    */
+    pub async fn reload(&self, config: &Config, routing: &RoutingConfig) -> Result<(), LoadBalancerError> {
+        for cluster in &routing.clusters {
+            if !self.clusters.contains_key(&cluster.name) {
+                tracing::warn!("Ignoring the new cluster {}, adding clusters requires a restart", cluster.name);
+            }
+        }
+        let updates = self
+            .clusters
+            .keys()
+            .map(|name| {
+                let mut update = TuningUpdate::from_config(config);
+                update.probe_interval_ms = None;
+                if let Some(cluster) = routing.clusters.iter().find(|cluster| cluster.name.eq(name)) {
+                    update.policy = cluster.policy.or(update.policy);
+                    update.q_rif = cluster.q_rif.or(update.q_rif);
+                }
+                (name.as_str(), update)
+            })
+            .collect();
+        self.apply_tuning(updates, Some(config.probe_interval_ms)).await
+    }
+    /**
+    Validates every update before applying any of them, then applies them while holding the lock of every
+    affected cluster, so no request sees a mix of old and new settings. Locks are taken in name order.
+    */
+    async fn apply_tuning(
+        &self,
+        mut updates: Vec<(&str, TuningUpdate)>,
+        probe_interval_ms: Option<u64>,
+    ) -> Result<(), LoadBalancerError> {
+        for (_, update) in &updates {
+            update.validate()?;
+        }
+        TuningUpdate {
+            probe_interval_ms,
+            ..Default::default()
+        }
+        .validate()?;
+        updates.sort_by_key(|(name, _)| *name);
+        let mut locked = Vec::with_capacity(updates.len());
+        for (name, update) in &updates {
+            locked.push((*name, update, self.clusters[*name].lock().await));
+        }
+        for (name, update, load_balancer) in &mut locked {
+            load_balancer.apply_tuning(name, update);
+        }
+        if let Some(probe_interval_ms) = probe_interval_ms {
+            let old = self.probe_interval_ms.swap(probe_interval_ms, Relaxed);
+            if old != probe_interval_ms {
+                tracing::info!(old, new = probe_interval_ms, "Changed the probe interval in milliseconds");
+            }
+        }
+        Ok(())
+    }
+    /**
+    Returns the name and load balancer of the cluster which should serve the request
+    */
+    pub fn route(
+        &self,
+        service: &str,
+        method: &str,
+        metadata: &MetadataMap,
+    ) -> Result<(&str, &Arc<Mutex<LoadBalancer>>), LoadBalancerError> {
+        let name = self
+            .rules
+            .iter()
+            .find(|rule| rule.matches(service, method, metadata))
+            .map(|rule| &rule.cluster)
+            .or(self.default_cluster.as_ref())
+            .ok_or_else(|| LoadBalancerError::NoRouteFound(format!("{service}/{method}")))?;
+        let balancer = self
+            .clusters
+            .get(name)
+            .ok_or_else(|| LoadBalancerError::UnknownCluster(name.clone()))?;
+        Ok((name, balancer))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Mode;
+
+    const ROUTING: &str = r#"
+        default_cluster = "greeter"
+
+        [[clusters]]
+        name = "greeter"
+        server_urls = ["http://[::1]:50052", "http://[::1]:50053"]
+
+        [[clusters]]
+        name = "metrics"
+        server_urls = ["http://[::1]:50054"]
+        policy = "round_robin"
+        q_rif = 0.5
+
+        [[clusters]]
+        name = "canary"
+        server_urls = ["http://[::1]:50055"]
+        affinity_mode = "maglev"
+        affinity_key = "metadata:x-session-id"
+
+        [[routes]]
+        cluster = "canary"
+        service = "helloworld.Greeter"
+        [routes.headers]
+        x-canary = "true"
+
+        [[routes]]
+        cluster = "metrics"
+        method = "GetMetrics"
+    "#;
+
+    fn config() -> Config {
+        Config {
+            q_rif: 0.7,
+            ..Default::default()
+        }
+    }
+
+    fn router() -> Router {
+        Router::new(&toml::from_str(ROUTING).unwrap(), &config()).unwrap()
+    }
+
+    fn metadata(headers: &[(&'static str, &'static str)]) -> MetadataMap {
+        let mut metadata = MetadataMap::new();
+        for (key, value) in headers {
+            metadata.insert(*key, value.parse().unwrap());
+        }
+        metadata
+    }
+
+    #[test]
+    fn test_first_matching_rule_wins() {
+        let router = router();
+        let canary = metadata(&[("x-canary", "true")]);
+        assert_eq!(router.route("helloworld.Greeter", "GetMetrics", &canary).unwrap().0, "canary");
+        assert_eq!(router.route("helloworld.Greeter", "GetMetrics", &metadata(&[])).unwrap().0, "metrics");
+        assert_eq!(router.route("helloworld.Greeter", "SayHello", &metadata(&[])).unwrap().0, "greeter");
+        let not_canary = metadata(&[("x-canary", "false")]);
+        assert_eq!(router.route("helloworld.Greeter", "SayHello", &not_canary).unwrap().0, "greeter");
+    }
+
+    #[tokio::test]
+    async fn test_clusters_keep_their_own_settings() {
+        let router = router();
+        let metrics = router.clusters["metrics"].lock().await;
+        assert_eq!(metrics.config.q_rif, 0.5);
+        assert_eq!(metrics.config.policy, Policy::RoundRobin);
+        assert_eq!(metrics.config.server_urls, "http://[::1]:50054");
+        assert!(metrics.affinity_key.is_none());
+        let canary = router.clusters["canary"].lock().await;
+        assert_eq!(canary.config.q_rif, 0.7);
+        assert_eq!(canary.config.policy, Policy::Prequal);
+        assert_eq!(canary.config.affinity_mode, AffinityMode::Maglev);
+        assert!(canary.affinity_key.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_tuning_is_validated_before_it_is_applied() {
+        let router = router();
+        let update = TuningUpdate {
+            q_rif: Some(0.4),
+            probe_interval_ms: Some(0),
+            ..Default::default()
+        };
+        assert!(router.update_tuning(None, &update).await.is_err());
+        assert_eq!(router.clusters["greeter"].lock().await.config.q_rif, 0.7);
+        assert_eq!(router.probe_interval(), Duration::from_millis(100));
+        assert!(matches!(
+            router.update_tuning(Some("missing"), &TuningUpdate::default()).await,
+            Err(LoadBalancerError::UnknownCluster(_))
+        ));
+
+        let update = TuningUpdate {
+            q_rif: Some(0.4),
+            probe_interval_ms: Some(50),
+            ..Default::default()
+        };
+        router.update_tuning(None, &update).await.unwrap();
+        for load_balancer in router.clusters.values() {
+            let load_balancer = load_balancer.lock().await;
+            assert_eq!((load_balancer.config.q_rif, load_balancer.probe_pool.q_rif), (0.4, 0.4));
+        }
+        assert_eq!(router.probe_interval(), Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn test_reload_keeps_the_cluster_overrides() {
+        let router = router();
+        let mut routing: RoutingConfig = toml::from_str(ROUTING).unwrap();
+        routing.clusters[1].q_rif = Some(0.6);
+        let reloaded = Config {
+            q_rif: 0.9,
+            policy: Policy::Random,
+            probe_interval_ms: 20,
+            ..config()
+        };
+        router.reload(&reloaded, &routing).await.unwrap();
+        let metrics = router.clusters["metrics"].lock().await;
+        assert_eq!((metrics.config.q_rif, metrics.config.policy), (0.6, Policy::RoundRobin));
+        let greeter = router.clusters["greeter"].lock().await;
+        assert_eq!((greeter.config.q_rif, greeter.config.policy), (0.9, Policy::Random));
+        assert_eq!(router.probe_interval(), Duration::from_millis(20));
+    }
+
+    #[test]
+    fn test_no_route_without_default_cluster() {
+        let mut routing: RoutingConfig = toml::from_str(ROUTING).unwrap();
+        routing.default_cluster = None;
+        let router = Router::new(&routing, &config()).unwrap();
+        assert!(matches!(
+            router.route("helloworld.Greeter", "SayHello", &metadata(&[])),
+            Err(LoadBalancerError::NoRouteFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_rules_must_reference_known_clusters() {
+        let mut routing: RoutingConfig = toml::from_str(ROUTING).unwrap();
+        routing.routes[0].cluster = "missing".to_string();
+        assert!(matches!(
+            Router::new(&routing, &config()),
+            Err(LoadBalancerError::UnknownCluster(name)) if name == "missing"
+        ));
+    }
+
+    #[test]
+    fn test_invalid_tuning_is_refused_at_startup() {
+        let zero_interval = Config {
+            probe_interval_ms: 0,
+            ..config()
+        };
+        assert!(matches!(
+            Router::new(&toml::from_str(ROUTING).unwrap(), &zero_interval),
+            Err(LoadBalancerError::InvalidConfig(_))
+        ));
+        let mut routing: RoutingConfig = toml::from_str(ROUTING).unwrap();
+        routing.clusters[0].q_rif = Some(1.5);
+        assert!(matches!(Router::new(&routing, &config()), Err(LoadBalancerError::InvalidConfig(_))));
+    }
+
+    #[test]
+    fn test_http_mode_refuses_backend_tls() {
+        let http = Config {
+            mode: Mode::Http,
+            ..config()
+        };
+        let mut routing: RoutingConfig = toml::from_str(ROUTING).unwrap();
+        Router::new(&routing, &http).unwrap();
+        routing.clusters[1].server_urls.push("https://[::1]:50056".to_string());
+        assert!(matches!(Router::new(&routing, &http), Err(LoadBalancerError::InvalidConfig(_))));
+        let backend_tls = Config {
+            backend_tls_domain: Some("backend.test".to_string()),
+            ..http
+        };
+        assert!(matches!(
+            Router::new(&toml::from_str(ROUTING).unwrap(), &backend_tls),
+            Err(LoadBalancerError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn test_env_config_is_a_single_default_cluster() {
+        let env_config = Config {
+            server_urls: "http://[::1]:50052,http://[::1]:50053".to_string(),
+            ..config()
+        };
+        let router = Router::new(&RoutingConfig::from_env(&env_config), &env_config).unwrap();
+        assert_eq!(router.clusters.len(), 1);
+        assert_eq!(router.route("any.Service", "Any", &metadata(&[])).unwrap().0, DEFAULT_CLUSTER);
+    }
+}
@@ -0,0 +1,347 @@
+use crate::LoadBalancerError;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::transport::{Certificate, ClientTlsConfig, Identity};
+
+/// Server side TLS config which can be swapped while the listener is running
+pub type ReloadableServerConfig = Arc<RwLock<Arc<ServerConfig>>>;
+
+/// A client which does not finish the TLS handshake in time is disconnected
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, LoadBalancerError> {
+    let file = File::open(path)
+        .map_err(|error| LoadBalancerError::InvalidTlsConfig(format!("{path}: {error}")))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|error| LoadBalancerError::InvalidTlsConfig(format!("{path}: {error}")))?;
+    if certs.is_empty() {
+        return Err(LoadBalancerError::InvalidTlsConfig(format!(
+            "{path}: no certificate found"
+        )));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &str) -> Result<PrivateKeyDer<'static>, LoadBalancerError> {
+    let file = File::open(path)
+        .map_err(|error| LoadBalancerError::InvalidTlsConfig(format!("{path}: {error}")))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .map_err(|error| LoadBalancerError::InvalidTlsConfig(format!("{path}: {error}")))?
+        .ok_or_else(|| LoadBalancerError::InvalidTlsConfig(format!("{path}: no private key found")))
+}
+
+fn read_pem(path: &str) -> Result<Vec<u8>, LoadBalancerError> {
+    std::fs::read(path).map_err(|error| LoadBalancerError::InvalidTlsConfig(format!("{path}: {error}")))
+}
+
+/**
+Builds the TLS config used to terminate client connections on the load balancer listener.
+When a client CA is given, clients have to present a certificate signed by it (mutual TLS).
+HTTP/1.1 is only offered with `http1`, for the HTTP proxy, gRPC needs HTTP/2.
+*/
+pub fn server_config(
+    cert_path: &str,
+    key_path: &str,
+    client_ca_path: Option<&str>,
+    http1: bool,
+) -> Result<Arc<ServerConfig>, LoadBalancerError> {
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|error| LoadBalancerError::InvalidTlsConfig(error.to_string()))?;
+    let builder = match client_ca_path {
+        Some(client_ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(client_ca_path)? {
+                roots
+                    .add(cert)
+                    .map_err(|error| LoadBalancerError::InvalidTlsConfig(format!("{client_ca_path}: {error}")))?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .build()
+                .map_err(|error| LoadBalancerError::InvalidTlsConfig(error.to_string()))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut config = builder
+        .with_single_cert(load_certs(cert_path)?, load_key(key_path)?)
+        .map_err(|error| LoadBalancerError::InvalidTlsConfig(error.to_string()))?;
+    config.alpn_protocols = vec![b"h2".to_vec()];
+    if http1 {
+        config.alpn_protocols.push(b"http/1.1".to_vec());
+    }
+    Ok(Arc::new(config))
+}
+
+/**
+Builds the TLS config used when connecting to `https://` backends.
+The CA verifies the backend certificates, the client certificate and key enable mutual TLS.
+*/
+pub fn client_tls_config(
+    ca_path: Option<&str>,
+    identity: Option<(&str, &str)>,
+    domain: Option<&str>,
+) -> Result<ClientTlsConfig, LoadBalancerError> {
+    let mut config = ClientTlsConfig::new();
+    if let Some(ca_path) = ca_path {
+        config = config.ca_certificate(Certificate::from_pem(read_pem(ca_path)?));
+    }
+    if let Some((cert_path, key_path)) = identity {
+        config = config.identity(Identity::from_pem(read_pem(cert_path)?, read_pem(key_path)?));
+    }
+    if let Some(domain) = domain {
+        config = config.domain_name(domain);
+    }
+    Ok(config)
+}
+
+/**
+Accepts TCP connections and completes the TLS handshake with whatever server config is current at
+that moment, so reloaded certificates are picked up by new connections without a restart.
+Failed handshakes, and ones not done within `HANDSHAKE_TIMEOUT`, are logged and dropped without affecting the listener.
+*/
+pub fn incoming(
+    listener: TcpListener,
+    config: ReloadableServerConfig,
+) -> ReceiverStream<Result<TlsStream<TcpStream>, std::io::Error>> {
+    let (tx, rx) = mpsc::channel(128);
+    tokio::spawn(async move {
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(error) => {
+                    if tx.send(Err(error)).await.is_err() {
+                        break;
+                    }
+                    continue;
+                }
+            };
+            let acceptor = TlsAcceptor::from(config.read().unwrap().clone());
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => {
+                        let _ = tx.send(Ok(stream)).await;
+                    }
+                    Ok(Err(error)) => {
+                        tracing::warn!(%error, "TLS handshake failed with {:?}", peer);
+                    }
+                    Err(_) => {
+                        tracing::warn!("TLS handshake with {:?} timed out after {:?}", peer, HANDSHAKE_TIMEOUT);
+                    }
+                }
+            });
+        }
+    });
+    ReceiverStream::new(rx)
+}
+
+/**
+Tracks the modification times of a set of files to find out when certificates are rotated
+*/
+#[derive(Debug)]
+pub struct FileWatcher {
+    files: Vec<(PathBuf, Option<SystemTime>)>,
+}
+
+impl FileWatcher {
+    pub fn new<I, P>(paths: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<PathBuf>,
+    {
+        let files = paths
+            .into_iter()
+            .map(|path| {
+                let path = path.into();
+                let modified = modified_at(&path);
+                (path, modified)
+            })
+            .collect();
+        Self { files }
+    }
+    /**
+    Returns true if any of the files were modified since the last call
+    */
+    pub fn changed(&mut self) -> bool {
+        let mut changed = false;
+        for (path, last_modified) in &mut self.files {
+            let modified = modified_at(path);
+            if modified != *last_modified {
+                *last_modified = modified;
+                changed = true;
+            }
+        }
+        changed
+    }
+}
+
+fn modified_at(path: &PathBuf) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ReplyStream;
+    use crate::hello_world::greeter_client::GreeterClient;
+    use crate::hello_world::greeter_server::{Greeter, GreeterServer};
+    use crate::hello_world::{Empty, HelloReply, HelloRequest, Metric};
+    use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair};
+    use std::path::Path;
+    use tempfile::TempDir;
+    use tonic::transport::{Channel, Server};
+    use tonic::{Request, Response, Status, Streaming};
+
+    struct StubGreeter;
+
+    #[tonic::async_trait]
+    impl Greeter for StubGreeter {
+        async fn say_hello(&self, request: Request<HelloRequest>) -> Result<Response<HelloReply>, Status> {
+            Ok(Response::new(HelloReply {
+                message: format!("Hello {}! over tls", request.into_inner().name),
+            }))
+        }
+        async fn get_metrics(&self, _request: Request<Empty>) -> Result<Response<Metric>, Status> {
+            Ok(Response::new(Metric::default()))
+        }
+
+        type LotsOfRepliesStream = ReplyStream;
+
+        async fn lots_of_replies(&self, _request: Request<HelloRequest>) -> Result<Response<ReplyStream>, Status> {
+            Err(Status::unimplemented("not needed for the tls tests"))
+        }
+
+        async fn lots_of_greetings(
+            &self,
+            _request: Request<Streaming<HelloRequest>>,
+        ) -> Result<Response<HelloReply>, Status> {
+            Err(Status::unimplemented("not needed for the tls tests"))
+        }
+
+        type BidiHelloStream = ReplyStream;
+
+        async fn bidi_hello(
+            &self,
+            _request: Request<Streaming<HelloRequest>>,
+        ) -> Result<Response<ReplyStream>, Status> {
+            Err(Status::unimplemented("not needed for the tls tests"))
+        }
+    }
+
+    // Writes a CA, a server certificate for localhost and a client certificate signed by the CA
+    fn generate_certs(dir: &Path) {
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        ca_params.distinguished_name.push(DnType::CommonName, "prequal test ca");
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        std::fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+
+        for (name, subject) in [("server", "localhost"), ("client", "prequal-client")] {
+            let key = KeyPair::generate().unwrap();
+            let cert = CertificateParams::new(vec![subject.to_string()])
+                .unwrap()
+                .signed_by(&key, &ca, &ca_key)
+                .unwrap();
+            std::fs::write(dir.join(format!("{name}.pem")), cert.pem()).unwrap();
+            std::fs::write(dir.join(format!("{name}.key")), key.serialize_pem()).unwrap();
+        }
+    }
+
+    fn path(dir: &Path, file: &str) -> String {
+        dir.join(file).to_str().unwrap().to_string()
+    }
+
+    async fn serve_tls(dir: &Path) -> String {
+        let config = server_config(
+            &path(dir, "server.pem"),
+            &path(dir, "server.key"),
+            Some(&path(dir, "ca.pem")),
+            false,
+        )
+        .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let incoming = incoming(listener, Arc::new(RwLock::new(config)));
+        tokio::spawn(
+            Server::builder()
+                .add_service(GreeterServer::new(StubGreeter))
+                .serve_with_incoming(incoming),
+        );
+        format!("https://localhost:{port}")
+    }
+
+    #[tokio::test]
+    async fn test_mutual_tls_round_trip() {
+        let dir = TempDir::new().unwrap();
+        generate_certs(dir.path());
+        let addr = serve_tls(dir.path()).await;
+
+        let tls = client_tls_config(
+            Some(&path(dir.path(), "ca.pem")),
+            Some((&path(dir.path(), "client.pem"), &path(dir.path(), "client.key"))),
+            Some("localhost"),
+        )
+        .unwrap();
+        let channel = Channel::from_shared(addr).unwrap().tls_config(tls).unwrap().connect().await.unwrap();
+        let response = GreeterClient::new(channel)
+            .say_hello(HelloRequest { name: "world".to_string() })
+            .await
+            .unwrap();
+        assert_eq!(response.into_inner().message, "Hello world! over tls");
+    }
+
+    #[tokio::test]
+    async fn test_client_without_certificate_is_rejected() {
+        let dir = TempDir::new().unwrap();
+        generate_certs(dir.path());
+        let addr = serve_tls(dir.path()).await;
+
+        let tls = client_tls_config(Some(&path(dir.path(), "ca.pem")), None, Some("localhost")).unwrap();
+        let result = match Channel::from_shared(addr).unwrap().tls_config(tls).unwrap().connect().await {
+            Ok(channel) => GreeterClient::new(channel)
+                .say_hello(HelloRequest { name: "world".to_string() })
+                .await
+                .map(|_| ()),
+            Err(error) => Err(Status::unavailable(error.to_string())),
+        };
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_server_config_rejects_missing_files() {
+        let dir = TempDir::new().unwrap();
+        let result = server_config(&path(dir.path(), "missing.pem"), &path(dir.path(), "missing.key"), None, false);
+        assert!(matches!(result, Err(LoadBalancerError::InvalidTlsConfig(_))));
+    }
+
+    #[test]
+    fn test_file_watcher_detects_rotation() {
+        let dir = TempDir::new().unwrap();
+        generate_certs(dir.path());
+        let mut watcher = FileWatcher::new([dir.path().join("server.pem"), dir.path().join("server.key")]);
+        assert!(!watcher.changed());
+
+        std::fs::remove_file(dir.path().join("server.pem")).unwrap();
+        assert!(watcher.changed());
+        generate_certs(dir.path());
+        assert!(watcher.changed());
+        assert!(!watcher.changed());
+    }
+}
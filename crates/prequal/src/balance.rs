@@ -0,0 +1,388 @@
+use crate::probe::ProbePool;
+use crate::prober;
+use crate::prober::ProbeResult;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tonic::body::BoxBody;
+use tonic::transport::Channel;
+use tower::discover::{Change, Discover};
+use tower::{BoxError, Service};
+use utils::clock::epoch_millis;
+
+pub type ResponseFuture = Pin<Box<dyn Future<Output = Result<http::Response<BoxBody>, BoxError>> + Send>>;
+
+#[derive(Debug, Clone)]
+pub struct PrequalConfig {
+    /// Probes with a normalized RIF at or above this are hot
+    pub q_rif: f32,
+    /// Time between two probing rounds
+    pub probe_interval: Duration,
+    /// Servers probed in every round
+    pub probes_per_round: usize,
+    /// A probe not answered within this time marks the server hot
+    pub probe_timeout: Duration,
+}
+
+impl Default for PrequalConfig {
+    fn default() -> Self {
+        Self {
+            q_rif: 0.7,
+            probe_interval: Duration::from_millis(100),
+            probes_per_round: 2,
+            probe_timeout: Duration::from_millis(100),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    endpoints: HashMap<String, Channel>,
+    pool: ProbePool,
+}
+
+/**
+Client side Prequal balancer over the channels yielded by `D`, keyed by their `to_string()`.
+Every request goes to the server picked from the probe pool, requests are spread round robin
+until the first probes arrive. The servers are probed on a background task which ends with the balancer,
+so it has to be created inside a tokio runtime.
+
+It is a tonic transport on its own, wrap it in `tower::buffer::Buffer` to share it between clients.
+*/
+pub struct Balance<D> {
+    discover: D,
+    discover_done: bool,
+    shared: Arc<Mutex<Shared>>,
+    /// Server picked and made ready by `poll_ready`, taken by `call`
+    ready: Option<(String, Channel)>,
+    next: usize,
+}
+
+impl<D> Balance<D>
+where
+    D: Discover<Service = Channel> + Unpin,
+    D::Key: ToString,
+    D::Error: Into<BoxError>,
+{
+    pub fn new(discover: D, config: PrequalConfig) -> Self {
+        let shared = Arc::new(Mutex::new(Shared {
+            endpoints: HashMap::new(),
+            pool: ProbePool::new(config.q_rif),
+        }));
+        tokio::spawn(probe_endpoints(Arc::downgrade(&shared), config));
+        Self {
+            discover,
+            discover_done: false,
+            shared,
+            ready: None,
+            next: 0,
+        }
+    }
+    /**
+    A snapshot of the current probe pool
+    */
+    pub fn pool(&self) -> ProbePool {
+        self.shared.lock().unwrap().pool.clone()
+    }
+    fn update_from_discover(&mut self, cx: &mut Context<'_>) -> Result<(), BoxError> {
+        while !self.discover_done {
+            let change = match Pin::new(&mut self.discover).poll_discover(cx) {
+                Poll::Pending => break,
+                Poll::Ready(None) => {
+                    self.discover_done = true;
+                    break;
+                }
+                Poll::Ready(Some(change)) => change.map_err(Into::into)?,
+            };
+            let mut shared = self.shared.lock().unwrap();
+            match change {
+                Change::Insert(key, channel) => {
+                    let server = key.to_string();
+                    tracing::debug!("Adding the server {} to the balancer", server);
+                    shared.endpoints.insert(server, channel);
+                }
+                Change::Remove(key) => {
+                    let server = key.to_string();
+                    tracing::debug!("Removing the server {} from the balancer", server);
+                    shared.endpoints.remove(&server);
+                    shared.pool.remove(&server);
+                    if self.ready.as_ref().is_some_and(|(ready, _)| ready.eq(&server)) {
+                        self.ready = None;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+    fn pick(&mut self) -> Option<(String, Channel)> {
+        self.next = self.next.wrapping_add(1);
+        let shared = self.shared.lock().unwrap();
+        if let Some(probe) = shared.pool.select(|probe| shared.endpoints.contains_key(&probe.server)) {
+            probe.times_used.fetch_add(1, Relaxed);
+            return Some((probe.server.clone(), shared.endpoints[&probe.server].clone()));
+        }
+        // No probes yet, spread the requests until the first ones arrive
+        if shared.endpoints.is_empty() {
+            return None;
+        }
+        shared
+            .endpoints
+            .iter()
+            .nth(self.next % shared.endpoints.len())
+            .map(|(server, channel)| (server.clone(), channel.clone()))
+    }
+}
+
+impl<D> Service<http::Request<BoxBody>> for Balance<D>
+where
+    D: Discover<Service = Channel> + Unpin,
+    D::Key: ToString,
+    D::Error: Into<BoxError>,
+{
+    type Response = http::Response<BoxBody>;
+    type Error = BoxError;
+    type Future = ResponseFuture;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.update_from_discover(cx)?;
+        if self.ready.is_none() {
+            self.ready = self.pick();
+        }
+        match &mut self.ready {
+            Some((_, channel)) => channel.poll_ready(cx).map_err(Into::into),
+            None if self.discover_done => Poll::Ready(Err("No servers left to balance over".into())),
+            // Woken up by discover once a server is added
+            None => Poll::Pending,
+        }
+    }
+
+    fn call(&mut self, request: http::Request<BoxBody>) -> Self::Future {
+        let (server, mut channel) = self.ready.take().expect("Balance::call must be preceded by poll_ready");
+        tracing::trace!("Sending the request to {}", server);
+        let response = channel.call(request);
+        Box::pin(async move { response.await.map_err(Into::into) })
+    }
+}
+
+/**
+Probes random servers every interval until the balancer is dropped.
+The servers of a round are probed at once, the lock is never held while waiting for the probes.
+*/
+async fn probe_endpoints(shared: Weak<Mutex<Shared>>, config: PrequalConfig) {
+    let mut interval = tokio::time::interval(config.probe_interval);
+    loop {
+        interval.tick().await;
+        let Some(state) = shared.upgrade() else {
+            break;
+        };
+        let candidates = state
+            .lock()
+            .unwrap()
+            .endpoints
+            .iter()
+            .map(|(server, channel)| (server.clone(), channel.clone()))
+            .collect::<Vec<(String, Channel)>>();
+        drop(state);
+        let targets = prober::choose_targets(&candidates, config.probes_per_round);
+        let results = prober::probe_all(targets, config.probe_timeout, |(_, channel)| prober::probe(channel.clone())).await;
+        let Some(state) = shared.upgrade() else {
+            break;
+        };
+        let mut state = state.lock().unwrap();
+        for ((server, _), result) in results {
+            if !state.endpoints.contains_key(&server) {
+                // Removed while it was being probed
+                continue;
+            }
+            match result {
+                ProbeResult::Answered { metric, rtt } => {
+                    tracing::debug!(%metric, "Received the probe of {}", server);
+                    state.pool.record(&server, &metric);
+                    state.pool.record_rtt(&server, Some(rtt));
+                }
+                ProbeResult::TimedOut => {
+                    tracing::warn!("{} did not answer the probe within {:?}, taking it as hot", server, config.probe_timeout);
+                    state.pool.record_timeout(&server, epoch_millis());
+                }
+                ProbeResult::Failed(status) => {
+                    tracing::warn!(%status, "Unable to probe {}", server);
+                    state.pool.remove(&server);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hello_world::greeter_client::GreeterClient;
+    use crate::hello_world::greeter_server::{Greeter, GreeterServer};
+    use crate::hello_world::{Empty, HelloReply, HelloRequest, Metric};
+    use std::convert::Infallible;
+    use tokio::net::TcpListener;
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tonic::transport::{Endpoint, Server};
+    use tonic::{Request, Response, Status, Streaming};
+
+    type ReplyStream = Pin<Box<dyn tokio_stream::Stream<Item = Result<HelloReply, Status>> + Send>>;
+
+    /// Always reports the same load, or never answers the probes when stalled
+    struct StubGreeter {
+        name: &'static str,
+        rif: u32,
+        latency: u64,
+        stall: bool,
+    }
+
+    #[tonic::async_trait]
+    impl Greeter for StubGreeter {
+        async fn say_hello(&self, _: Request<HelloRequest>) -> Result<Response<HelloReply>, Status> {
+            Ok(Response::new(HelloReply {
+                message: self.name.to_string(),
+            }))
+        }
+        async fn get_metrics(&self, _: Request<Empty>) -> Result<Response<Metric>, Status> {
+            if self.stall {
+                std::future::pending::<()>().await;
+            }
+            Ok(Response::new(Metric {
+                rif: self.rif,
+                latency: self.latency,
+                ..Default::default()
+            }))
+        }
+        type LotsOfRepliesStream = ReplyStream;
+        async fn lots_of_replies(&self, _: Request<HelloRequest>) -> Result<Response<ReplyStream>, Status> {
+            Err(Status::unimplemented("not used"))
+        }
+        async fn lots_of_greetings(&self, _: Request<Streaming<HelloRequest>>) -> Result<Response<HelloReply>, Status> {
+            Err(Status::unimplemented("not used"))
+        }
+        type BidiHelloStream = ReplyStream;
+        async fn bidi_hello(&self, _: Request<Streaming<HelloRequest>>) -> Result<Response<ReplyStream>, Status> {
+            Err(Status::unimplemented("not used"))
+        }
+    }
+
+    async fn spawn_server(greeter: StubGreeter) -> (String, Channel) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(
+            Server::builder()
+                .add_service(GreeterServer::new(greeter))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+        let channel = Endpoint::from_shared(addr.clone()).unwrap().connect_lazy();
+        (addr, channel)
+    }
+
+    async fn balancer(config: PrequalConfig) -> Balance<impl Discover<Key = String, Service = Channel, Error = Infallible> + Unpin> {
+        balancer_over(
+            vec![
+                StubGreeter { name: "hot", rif: 10, latency: 1, stall: false },
+                StubGreeter { name: "cold", rif: 1, latency: 50, stall: false },
+            ],
+            config,
+        )
+        .await
+    }
+
+    async fn balancer_over(
+        greeters: Vec<StubGreeter>,
+        config: PrequalConfig,
+    ) -> Balance<impl Discover<Key = String, Service = Channel, Error = Infallible> + Unpin> {
+        let mut servers = vec![];
+        for greeter in greeters {
+            servers.push(spawn_server(greeter).await);
+        }
+        let changes = servers
+            .into_iter()
+            .map(|(addr, channel)| Ok::<_, Infallible>(Change::Insert(addr, channel)));
+        Balance::new(tokio_stream::iter(changes), config)
+    }
+
+    async fn say_hello<D>(client: &mut GreeterClient<Balance<D>>) -> String
+    where
+        D: Discover<Service = Channel> + Unpin,
+        D::Key: ToString,
+        D::Error: Into<BoxError>,
+    {
+        let request = HelloRequest { name: "world".to_string() };
+        client.say_hello(request).await.unwrap().into_inner().message
+    }
+
+    #[tokio::test]
+    async fn test_requests_go_to_the_cold_server() {
+        let balance = balancer(PrequalConfig {
+            probe_interval: Duration::from_millis(10),
+            ..Default::default()
+        })
+        .await;
+        let shared = balance.shared.clone();
+        let mut client = GreeterClient::new(balance);
+        // The balancer learns about the servers on the first request
+        say_hello(&mut client).await;
+        for _ in 0..100 {
+            if shared.lock().unwrap().pool.len() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        for _ in 0..10 {
+            assert_eq!(say_hello(&mut client).await, "cold");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_round_robin_without_probes() {
+        let balance = balancer(PrequalConfig {
+            probes_per_round: 0,
+            ..Default::default()
+        })
+        .await;
+        let mut client = GreeterClient::new(balance);
+        let mut replies = vec![];
+        for _ in 0..4 {
+            replies.push(say_hello(&mut client).await);
+        }
+        replies.sort();
+        assert_eq!(replies, ["cold", "cold", "hot", "hot"]);
+    }
+
+    #[tokio::test]
+    async fn test_unanswered_probes_time_out_as_hot() {
+        let balance = balancer_over(
+            vec![
+                StubGreeter { name: "stalled", rif: 1, latency: 1, stall: true },
+                StubGreeter { name: "cold", rif: 1, latency: 50, stall: false },
+            ],
+            PrequalConfig {
+                probe_interval: Duration::from_millis(10),
+                probe_timeout: Duration::from_millis(20),
+                ..Default::default()
+            },
+        )
+        .await;
+        let shared = balance.shared.clone();
+        let mut client = GreeterClient::new(balance);
+        say_hello(&mut client).await;
+        // The stalled server does not hold up the probe of the other one
+        for _ in 0..100 {
+            if shared.lock().unwrap().pool.len() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let pool = shared.lock().unwrap().pool.clone();
+        assert_eq!(pool.len(), 2);
+        assert!(pool.probes.iter().any(|probe| probe.timed_out && pool.is_hot(probe)));
+        for _ in 0..10 {
+            assert_eq!(say_hello(&mut client).await, "cold");
+        }
+    }
+}
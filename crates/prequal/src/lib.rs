@@ -0,0 +1,28 @@
+/*!
+Prequal load balancing, see "Load is not what you should balance: Introducing Prequal".
+
+Servers are probed for their requests in flight (RIF) and latency, the latest probes are kept in a pool
+and every request goes to the cold server with the lowest latency, or the least loaded one when all are hot.
+The pool and selection are used by the load balancer binary, [`Balance`] does the same in-process
+on top of a set of tonic channels.
+*/
+pub mod balance;
+pub mod probe;
+pub mod prober;
+
+pub use balance::{Balance, PrequalConfig};
+pub use probe::{Probe, ProbePool};
+
+pub mod hello_world {
+    tonic::include_proto!("helloworld");
+}
+
+impl std::fmt::Display for hello_world::Metric {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "(server_id: {} rif: {} latency: {} queue_length: {} cpu: {:.2} draining: {})",
+            self.server_id, self.rif, self.latency, self.queue_length, self.cpu_utilization, self.draining
+        )
+    }
+}
@@ -0,0 +1,283 @@
+use crate::hello_world::Metric;
+use std::collections::HashMap;
+use std::sync::atomic::AtomicU32;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Default, Clone)]
+pub struct Probe {
+    pub server: String,
+    pub rif: u32,
+    pub latency: u64,
+    pub times_used: Arc<AtomicU32>,
+    pub normalized_rif: f32, // Used for finding the worst probe based qRIF
+    pub server_id: String,
+    pub start_epoch_ms: u64,
+    /// Server clock when the probe was answered
+    pub timestamp_ms: u64,
+    pub queue_length: u32,
+    pub cpu_utilization: f32,
+    pub max_concurrency: u32,
+    pub draining: bool,
+    /// Median latency per method
+    pub method_latency: HashMap<String, u64>,
+    /// RIF summed over the backends when the server is a load balancer
+    pub fleet_rif: u32,
+    /// When the probe or inline report was received
+    pub received_at: Option<Instant>,
+    /// Time the last answered probe took, None until one was answered
+    pub rtt: Option<Duration>,
+    /// The last probe was not answered in time, the server is taken as hot
+    pub timed_out: bool,
+}
+
+/**
+The latest probe of every sampled server.
+RIF is normalized against the highest RIF seen so far, probes at or above `q_rif` are hot.
+*/
+#[derive(Debug, Default, Clone)]
+pub struct ProbePool {
+    pub probes: Vec<Probe>,
+    pub max_rif: u32,
+    pub q_rif: f32,
+}
+
+impl ProbePool {
+    pub fn new(q_rif: f32) -> Self {
+        Self {
+            probes: vec![],
+            max_rif: 0,
+            q_rif,
+        }
+    }
+    pub fn is_hot(&self, probe: &Probe) -> bool {
+        probe.normalized_rif >= self.q_rif
+    }
+    pub fn get(&self, server: &str) -> Option<&Probe> {
+        self.probes.iter().find(|probe| probe.server.eq(server))
+    }
+    pub fn len(&self) -> usize {
+        self.probes.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.probes.is_empty()
+    }
+    /**
+    Deletes the probe of the server if there is one
+    */
+    pub fn remove(&mut self, server: &str) {
+        self.probes.retain(|probe| !probe.server.eq(server));
+    }
+    /**
+    Replaces the probe of the server with the given metric
+    */
+    pub fn record(&mut self, server: &str, metric: &Metric) {
+        if metric.rif > self.max_rif {
+            tracing::info!("A new max rif is found {}", metric.rif);
+            self.max_rif = metric.rif;
+
+            // When max_rif changes all the probes normalization should change
+            for probe in &mut self.probes {
+                probe.normalized_rif = probe.rif as f32 / self.max_rif as f32;
+            }
+        }
+        self.remove(server);
+        self.probes.push(Probe {
+            server: server.to_string(),
+            rif: metric.rif,
+            latency: metric.latency,
+            times_used: Arc::new(AtomicU32::new(0)),
+            normalized_rif: metric.rif as f32 / self.max_rif as f32,
+            server_id: metric.server_id.clone(),
+            start_epoch_ms: metric.start_epoch_ms,
+            timestamp_ms: metric.timestamp_ms,
+            queue_length: metric.queue_length,
+            cpu_utilization: metric.cpu_utilization,
+            max_concurrency: metric.max_concurrency,
+            draining: metric.draining,
+            method_latency: metric.method_latency.clone(),
+            fleet_rif: metric.fleet_rif,
+            received_at: Some(Instant::now()),
+            rtt: None,
+            timed_out: false,
+        });
+    }
+    /**
+    Refreshes the probe of the server from a load report attached to a response.
+    Only RIF and optionally latency are reported inline, the other signals of the previous probe are kept.
+    */
+    pub fn record_inline(&mut self, server: &str, rif: u32, latency: Option<u64>, start_epoch_ms: u64, timestamp_ms: u64) {
+        let previous = self.get(server);
+        let rtt = previous.and_then(|probe| probe.rtt);
+        let metric = carry_over(previous, rif, latency, start_epoch_ms, timestamp_ms);
+        tracing::debug!("Inline load report from {} {}", server, metric);
+        self.record(server, &metric);
+        self.record_rtt(server, rtt);
+    }
+    /**
+    A probe which was not answered in time is a strong sign the server is overloaded.
+    It is recorded with the highest RIF seen, which makes it hot, the other signals of the previous probe are kept.
+    */
+    pub fn record_timeout(&mut self, server: &str, timestamp_ms: u64) {
+        let previous = self.get(server);
+        let rif = previous.map_or(0, |probe| probe.rif).max(self.max_rif).max(1);
+        let start_epoch_ms = previous.map(|probe| probe.start_epoch_ms).unwrap_or_default();
+        let rtt = previous.and_then(|probe| probe.rtt);
+        let metric = carry_over(previous, rif, None, start_epoch_ms, timestamp_ms);
+        self.record(server, &metric);
+        self.record_rtt(server, rtt);
+        if let Some(probe) = self.probes.iter_mut().find(|probe| probe.server.eq(server)) {
+            probe.timed_out = true;
+        }
+    }
+    /**
+    Sets the round trip time of the latest probe of the server
+    */
+    pub fn record_rtt(&mut self, server: &str, rtt: Option<Duration>) {
+        if let Some(probe) = self.probes.iter_mut().find(|probe| probe.server.eq(server)) {
+            probe.rtt = rtt;
+        }
+    }
+    /**
+    Hot-cold lexicographic selection among the probes accepted by `eligible`:
+    the cold probe with the lowest latency, or the one with the lowest RIF when every probe is hot.
+    Servers which report they are draining are left out.
+    None when no probe is eligible.
+    */
+    pub fn select(&self, eligible: impl Fn(&Probe) -> bool) -> Option<&Probe> {
+        let (cold, hot): (Vec<&Probe>, Vec<&Probe>) = self
+            .probes
+            .iter()
+            .filter(|probe| !probe.draining && eligible(probe))
+            .partition(|probe| !self.is_hot(probe));
+        if cold.is_empty() {
+            hot.into_iter().min_by_key(|probe| probe.rif)
+        } else {
+            cold.into_iter().min_by_key(|probe| probe.latency)
+        }
+    }
+}
+
+/**
+A metric with the given RIF and the other signals of the previous probe of the server
+*/
+fn carry_over(previous: Option<&Probe>, rif: u32, latency: Option<u64>, start_epoch_ms: u64, timestamp_ms: u64) -> Metric {
+    Metric {
+        rif,
+        latency: latency.or(previous.map(|probe| probe.latency)).unwrap_or_default(),
+        server_id: previous.map(|probe| probe.server_id.clone()).unwrap_or_default(),
+        start_epoch_ms,
+        timestamp_ms,
+        queue_length: previous.map(|probe| probe.queue_length).unwrap_or_default(),
+        cpu_utilization: previous.map(|probe| probe.cpu_utilization).unwrap_or_default(),
+        max_concurrency: previous.map(|probe| probe.max_concurrency).unwrap_or_default(),
+        draining: previous.is_some_and(|probe| probe.draining),
+        method_latency: previous.map(|probe| probe.method_latency.clone()).unwrap_or_default(),
+        fleet_rif: previous.map(|probe| probe.fleet_rif).unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metric(rif: u32, latency: u64) -> Metric {
+        Metric {
+            rif,
+            latency,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_new_max_rif_renormalizes_the_pool() {
+        let mut pool = ProbePool::new(0.7);
+        pool.record("a", &metric(5, 10));
+        assert_eq!(pool.get("a").unwrap().normalized_rif, 1.0);
+        pool.record("b", &metric(10, 10));
+        assert_eq!(pool.max_rif, 10);
+        assert_eq!(pool.get("a").unwrap().normalized_rif, 0.5);
+        assert!(!pool.is_hot(pool.get("a").unwrap()));
+        assert!(pool.is_hot(pool.get("b").unwrap()));
+
+        pool.record("a", &metric(2, 10));
+        assert_eq!(pool.len(), 2);
+        assert_eq!(pool.get("a").unwrap().normalized_rif, 0.2);
+    }
+
+    #[test]
+    fn test_cold_probe_with_lowest_latency_wins() {
+        let mut pool = ProbePool::new(0.7);
+        pool.record("hot", &metric(10, 1));
+        pool.record("slow", &metric(1, 50));
+        pool.record("fast", &metric(3, 20));
+        assert_eq!(pool.select(|_| true).unwrap().server, "fast");
+        assert_eq!(pool.select(|probe| probe.server != "fast").unwrap().server, "slow");
+    }
+
+    #[test]
+    fn test_lowest_rif_wins_when_all_are_hot() {
+        let mut pool = ProbePool::new(0.7);
+        pool.record("a", &metric(10, 1));
+        pool.record("b", &metric(8, 50));
+        pool.record("c", &metric(9, 20));
+        assert_eq!(pool.select(|_| true).unwrap().server, "b");
+    }
+
+    #[test]
+    fn test_draining_servers_are_not_selected() {
+        let mut pool = ProbePool::new(0.7);
+        pool.record("fast", &Metric { draining: true, ..metric(1, 1) });
+        pool.record("slow", &metric(9, 50));
+        assert_eq!(pool.select(|_| true).unwrap().server, "slow");
+        pool.record("slow", &Metric { draining: true, ..metric(9, 50) });
+        assert!(pool.select(|_| true).is_none());
+    }
+
+    #[test]
+    fn test_nothing_selected_from_an_empty_pool() {
+        let mut pool = ProbePool::new(0.7);
+        assert!(pool.select(|_| true).is_none());
+        pool.record("a", &metric(1, 1));
+        assert!(pool.select(|_| false).is_none());
+    }
+
+    #[test]
+    fn test_inline_report_keeps_other_signals() {
+        let mut pool = ProbePool::new(0.7);
+        pool.record(
+            "a",
+            &Metric {
+                rif: 10,
+                latency: 500,
+                server_id: "server 1".to_string(),
+                ..Default::default()
+            },
+        );
+        pool.record_inline("a", 4, None, 1, 2);
+        let probe = pool.get("a").unwrap();
+        assert_eq!((probe.rif, probe.latency, probe.server_id.as_str()), (4, 500, "server 1"));
+        pool.record_inline("a", 4, Some(700), 1, 3);
+        assert_eq!(pool.get("a").unwrap().latency, 700);
+    }
+
+    #[test]
+    fn test_timed_out_probe_is_hot() {
+        let mut pool = ProbePool::new(0.7);
+        pool.record("a", &metric(10, 1));
+        pool.record("b", &metric(2, 50));
+        pool.record_rtt("b", Some(Duration::from_millis(3)));
+        pool.record_timeout("b", 1);
+        let probe = pool.get("b").unwrap();
+        assert!(probe.timed_out && pool.is_hot(probe));
+        assert_eq!((probe.rif, probe.latency, probe.rtt), (10, 50, Some(Duration::from_millis(3))));
+        assert_eq!(pool.max_rif, 10);
+
+        // Even on its own, before any RIF was seen
+        let mut pool = ProbePool::new(1.0);
+        pool.record_timeout("a", 1);
+        assert!(pool.is_hot(pool.get("a").unwrap()));
+        pool.record("a", &metric(0, 10));
+        assert!(!pool.get("a").unwrap().timed_out);
+    }
+}
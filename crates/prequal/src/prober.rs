@@ -0,0 +1,313 @@
+use crate::hello_world::greeter_client::GreeterClient;
+use crate::hello_world::{Empty, Metric};
+use rand::seq::SliceRandom;
+use rand::Rng;
+use std::future::Future;
+use std::time::{Duration, Instant};
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+use tonic::transport::Channel;
+use tonic::Status;
+
+/**
+Picks up to `count` distinct servers at random to probe in this round
+*/
+pub fn choose_targets<T: Clone>(candidates: &[T], count: usize) -> Vec<T> {
+    choose_targets_with(&mut rand::thread_rng(), candidates, count)
+}
+
+/**
+Same as [`choose_targets`] drawing from the given generator, a seeded one picks the same targets every time
+*/
+pub fn choose_targets_with<T: Clone>(rng: &mut impl Rng, candidates: &[T], count: usize) -> Vec<T> {
+    candidates.choose_multiple(rng, count).cloned().collect()
+}
+
+/**
+Asks the server behind the channel for its current load
+*/
+pub async fn probe(channel: Channel) -> Result<Metric, Status> {
+    GreeterClient::new(channel)
+        .get_metrics(Empty {})
+        .await
+        .map(|response| response.into_inner())
+}
+
+/**
+Outcome of probing one server
+*/
+#[derive(Debug)]
+pub enum ProbeResult {
+    Answered { metric: Metric, rtt: Duration },
+    Failed(Status),
+    TimedOut,
+}
+
+/**
+Probes every target at once with the future `probe` returns for it, each probe gets `probe_timeout` to be answered.
+Results come back in the order the probes finished.
+*/
+pub async fn probe_all<T, P, F>(targets: Vec<T>, probe_timeout: Duration, probe: P) -> Vec<(T, ProbeResult)>
+where
+    T: Send + 'static,
+    P: Fn(&T) -> F,
+    F: Future<Output = Result<Metric, Status>> + Send + 'static,
+{
+    let mut probes = JoinSet::new();
+    for target in targets {
+        let probe = probe(&target);
+        probes.spawn(async move {
+            let sent_at = Instant::now();
+            let result = match timeout(probe_timeout, probe).await {
+                Ok(Ok(metric)) => ProbeResult::Answered {
+                    metric,
+                    rtt: sent_at.elapsed(),
+                },
+                Ok(Err(status)) => ProbeResult::Failed(status),
+                Err(_) => ProbeResult::TimedOut,
+            };
+            (target, result)
+        });
+    }
+    let mut results = vec![];
+    while let Some(result) = probes.join_next().await {
+        match result {
+            Ok(result) => results.push(result),
+            Err(error) => tracing::error!(%error, "A probe task failed"),
+        }
+    }
+    results
+}
+
+/**
+How fast probing adapts to the traffic, see [`ProbeScheduler`]
+*/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProbeRateConfig {
+    /// Age the probes used by requests should have on average, the interval stays fixed when unset
+    pub target_age: Option<Duration>,
+    pub min_interval: Duration,
+    /// Also the interval probing slows down to when no request uses the probes
+    pub max_interval: Duration,
+    /// A probe used more often than this since it was received is stale, probing speeds up
+    pub max_reuse: u32,
+    /// Every delay is drawn within this share of the interval around it, so load balancers started together drift apart
+    pub jitter: f64,
+    /// Probes per forwarded request at most, on top of one probe per `max_interval`. 0 leaves probing unbounded
+    pub budget: f64,
+}
+
+impl Default for ProbeRateConfig {
+    fn default() -> Self {
+        Self {
+            target_age: None,
+            min_interval: Duration::from_millis(10),
+            max_interval: Duration::from_secs(1),
+            max_reuse: 4,
+            jitter: 0.1,
+            budget: 0.0,
+        }
+    }
+}
+
+/**
+How the requests used the probe pool since the last probing round
+*/
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ProbeUsage {
+    /// Requests routed, whatever the policy
+    pub requests: u64,
+    /// Requests which were sent where a probe said
+    pub selections: u64,
+    /// Ages of the selected probes added up
+    pub total_age: Duration,
+    /// Most uses of a single probe
+    pub max_reuse: u32,
+}
+
+impl ProbeUsage {
+    pub fn record_selection(&mut self, age: Duration, times_used: u32) {
+        self.selections += 1;
+        self.total_age += age;
+        self.max_reuse = self.max_reuse.max(times_used);
+    }
+    pub fn merge(&mut self, other: &ProbeUsage) {
+        self.requests += other.requests;
+        self.selections += other.selections;
+        self.total_age += other.total_age;
+        self.max_reuse = self.max_reuse.max(other.max_reuse);
+    }
+}
+
+/**
+Decides when the next probing round runs and how many probes it may send.
+The interval shrinks when the selected probes are older than the target or reused too often,
+and grows up to `max_interval` when they are fresh or nobody uses them.
+It changes by at most a factor of 2 per round so a single busy or quiet round does not swing it.
+A token bucket filled by the forwarded requests keeps the probes within the budget.
+*/
+#[derive(Debug)]
+pub struct ProbeScheduler {
+    config: ProbeRateConfig,
+    interval: Duration,
+    /// Probes which may be sent, only counted with a budget
+    tokens: f64,
+    /// Probes asked for in the current round, the most which carry over into the next
+    wanted: usize,
+}
+
+impl ProbeScheduler {
+    pub fn new(config: ProbeRateConfig, interval: Duration) -> Self {
+        Self {
+            config,
+            interval,
+            tokens: 0.0,
+            wanted: 0,
+        }
+    }
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+    /**
+    Starts over from an interval set by an operator
+    */
+    pub fn set_interval(&mut self, interval: Duration) {
+        self.interval = interval;
+    }
+    /**
+    The interval with jitter, drawn anew for every round
+    */
+    pub fn next_delay(&self, rng: &mut impl Rng) -> Duration {
+        if self.config.jitter <= 0.0 {
+            return self.interval;
+        }
+        self.interval.mul_f64(1.0 + rng.gen_range(-self.config.jitter..=self.config.jitter))
+    }
+    /**
+    Adapts the interval to the usage since the last round, which lasted `elapsed`,
+    and adds the probes earned meanwhile to the budget
+    */
+    pub fn adapt(&mut self, usage: &ProbeUsage, elapsed: Duration) {
+        self.tokens = self.tokens.min(self.wanted as f64);
+        self.wanted = 0;
+        self.tokens += usage.requests as f64 * self.config.budget
+            + elapsed.as_secs_f64() / self.config.max_interval.as_secs_f64().max(f64::EPSILON);
+        let Some(target_age) = self.config.target_age else {
+            return;
+        };
+        let factor = if usage.selections == 0 {
+            2.0
+        } else {
+            let mean_age = usage.total_age.as_secs_f64() / usage.selections as f64;
+            let mut factor = target_age.as_secs_f64() / mean_age.max(f64::EPSILON);
+            if usage.max_reuse > self.config.max_reuse {
+                factor = factor.min(self.config.max_reuse as f64 / usage.max_reuse as f64);
+            }
+            factor
+        };
+        self.interval = self
+            .interval
+            .mul_f64(factor.clamp(0.5, 2.0))
+            .clamp(self.config.min_interval, self.config.max_interval);
+    }
+    /**
+    How many of the `wanted` probes the budget allows, those are taken from it.
+    Called for every cluster of a round, unused probes carry over up to one round worth.
+    */
+    pub fn allow(&mut self, wanted: usize) -> usize {
+        if self.config.budget <= 0.0 {
+            return wanted;
+        }
+        self.wanted += wanted;
+        let allowed = (self.tokens.floor() as usize).min(wanted);
+        self.tokens -= allowed as f64;
+        allowed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn adaptive() -> ProbeRateConfig {
+        ProbeRateConfig {
+            target_age: Some(Duration::from_millis(50)),
+            ..Default::default()
+        }
+    }
+
+    fn usage(selections: u64, mean_age_ms: u64, max_reuse: u32) -> ProbeUsage {
+        ProbeUsage {
+            requests: selections,
+            selections,
+            total_age: Duration::from_millis(mean_age_ms) * selections as u32,
+            max_reuse,
+        }
+    }
+
+    #[test]
+    fn test_interval_stays_fixed_without_target_age() {
+        let mut scheduler = ProbeScheduler::new(ProbeRateConfig::default(), Duration::from_millis(100));
+        scheduler.adapt(&usage(100, 500, 20), Duration::from_millis(100));
+        scheduler.adapt(&ProbeUsage::default(), Duration::from_millis(100));
+        assert_eq!(scheduler.interval(), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_interval_follows_the_age_and_reuse_of_the_probes() {
+        let mut scheduler = ProbeScheduler::new(adaptive(), Duration::from_millis(100));
+        // Probes twice as old as the target
+        scheduler.adapt(&usage(10, 100, 1), Duration::from_millis(100));
+        assert_eq!(scheduler.interval(), Duration::from_millis(50));
+        // Fresh enough but each used 8 times instead of at most 4
+        scheduler.adapt(&usage(10, 25, 8), Duration::from_millis(50));
+        assert_eq!(scheduler.interval(), Duration::from_millis(25));
+        // At most halved in a round and never below the minimum
+        for _ in 0..10 {
+            scheduler.adapt(&usage(10, 5000, 1), Duration::from_millis(25));
+        }
+        assert_eq!(scheduler.interval(), Duration::from_millis(10));
+        // Probes nobody uses, up to the maximum
+        for _ in 0..10 {
+            scheduler.adapt(&ProbeUsage::default(), Duration::from_millis(10));
+        }
+        assert_eq!(scheduler.interval(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_jitter_stays_around_the_interval() {
+        let scheduler = ProbeScheduler::new(ProbeRateConfig::default(), Duration::from_millis(100));
+        let mut rng = StdRng::seed_from_u64(1);
+        let delays = (0..100).map(|_| scheduler.next_delay(&mut rng)).collect::<Vec<Duration>>();
+        assert!(delays
+            .iter()
+            .all(|delay| (Duration::from_millis(90)..=Duration::from_millis(110)).contains(delay)));
+        assert!(delays.iter().any(|delay| *delay != delays[0]));
+    }
+
+    #[test]
+    fn test_budget_bounds_the_probes() {
+        let config = ProbeRateConfig {
+            budget: 0.5,
+            ..Default::default()
+        };
+        let mut scheduler = ProbeScheduler::new(config, Duration::from_millis(100));
+        // 6 requests pay for 3 probes, shared by the clusters of the round
+        scheduler.adapt(&usage(6, 0, 0), Duration::ZERO);
+        assert_eq!((scheduler.allow(2), scheduler.allow(2)), (2, 1));
+        // Idle, one probe per second
+        scheduler.adapt(&ProbeUsage::default(), Duration::from_millis(500));
+        assert_eq!(scheduler.allow(2), 0);
+        scheduler.adapt(&ProbeUsage::default(), Duration::from_millis(500));
+        assert_eq!(scheduler.allow(2), 1);
+        // A busy round does not pay for more than the next round
+        scheduler.adapt(&usage(100, 0, 0), Duration::ZERO);
+        assert_eq!(scheduler.allow(2), 2);
+        scheduler.adapt(&ProbeUsage::default(), Duration::ZERO);
+        assert_eq!(scheduler.allow(2), 2);
+        scheduler.adapt(&ProbeUsage::default(), Duration::ZERO);
+        assert_eq!(scheduler.allow(2), 0);
+    }
+}
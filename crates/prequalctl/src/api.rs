@@ -0,0 +1,113 @@
+use http::{Method, StatusCode};
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use serde_json::{json, Value};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum CtlError {
+    #[error("Unable to reach the admin API `{0}`")]
+    Unreachable(String),
+    #[error("The load balancer answered {0}: {1}")]
+    Rejected(StatusCode, String),
+    #[error("Invalid response from the admin API `{0}`")]
+    InvalidResponse(String),
+    #[error("Unable to probe the backend `{0}`")]
+    ProbeFailed(String),
+    #[error("Unable to control the simulated backend `{0}`")]
+    ControlFailed(String),
+}
+
+/**
+Client of the admin API the load balancer serves on ADMIN_ADDR
+*/
+pub struct AdminClient {
+    base_url: String,
+    client: Client<HttpConnector, Full<Bytes>>,
+}
+
+impl AdminClient {
+    pub fn new(base_url: &str) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            client: Client::builder(TokioExecutor::new()).build_http(),
+        }
+    }
+    /**
+    Every cluster with its backends and probes, as shown on the dashboard
+    */
+    pub async fn state(&self) -> Result<Value, CtlError> {
+        self.send(Method::GET, "/api/state", None).await
+    }
+    /**
+    The decisions of the cluster with a sequence number above `after`, oldest first
+    */
+    pub async fn decisions(&self, cluster: &str, after: Option<u64>) -> Result<Vec<Value>, CtlError> {
+        let path = match after {
+            Some(after) => format!("/api/clusters/{cluster}/decisions?after={after}"),
+            None => format!("/api/clusters/{cluster}/decisions"),
+        };
+        match self.send(Method::GET, &path, None).await? {
+            Value::Array(decisions) => Ok(decisions),
+            other => Err(CtlError::InvalidResponse(other.to_string())),
+        }
+    }
+    pub async fn add_backend(&self, cluster: &str, address: &str) -> Result<(), CtlError> {
+        let path = format!("/api/clusters/{cluster}/backends");
+        self.send(Method::POST, &path, Some(json!({ "address": address }))).await.map(drop)
+    }
+    pub async fn remove_backend(&self, cluster: &str, address: &str) -> Result<(), CtlError> {
+        let path = format!("/api/clusters/{cluster}/backends");
+        self.send(Method::DELETE, &path, Some(json!({ "address": address }))).await.map(drop)
+    }
+    pub async fn drain_backend(&self, cluster: &str, address: &str) -> Result<(), CtlError> {
+        let path = format!("/api/clusters/{cluster}/backends/drain");
+        self.send(Method::POST, &path, Some(json!({ "address": address }))).await.map(drop)
+    }
+    /**
+    Applies the settings and returns the new state
+    */
+    pub async fn update_settings(&self, settings: Value) -> Result<Value, CtlError> {
+        self.send(Method::PUT, "/api/settings", Some(settings)).await
+    }
+    /**
+    Sends the request and returns the JSON response, null when the response has no body.
+    Error responses are turned into [`CtlError::Rejected`] with the message of the load balancer.
+    */
+    async fn send(&self, method: Method, path: &str, body: Option<Value>) -> Result<Value, CtlError> {
+        let url = format!("{}{}", self.base_url, path);
+        let request = http::Request::builder()
+            .method(method)
+            .uri(&url)
+            .header("content-type", "application/json")
+            .body(Full::new(Bytes::from(body.map(|body| body.to_string()).unwrap_or_default())))
+            .map_err(|error| CtlError::Unreachable(format!("{url}: {error}")))?;
+        let response = self
+            .client
+            .request(request)
+            .await
+            .map_err(|error| CtlError::Unreachable(format!("{url}: {error}")))?;
+        let status = response.status();
+        let body = response
+            .into_body()
+            .collect()
+            .await
+            .map_err(|error| CtlError::Unreachable(format!("{url}: {error}")))?
+            .to_bytes();
+        if !status.is_success() {
+            // Requests the API can't parse are rejected with a plain text body
+            let message = serde_json::from_slice::<Value>(&body)
+                .ok()
+                .and_then(|value| value["error"].as_str().map(str::to_string))
+                .unwrap_or_else(|| String::from_utf8_lossy(&body).into_owned());
+            return Err(CtlError::Rejected(status, message));
+        }
+        if body.is_empty() {
+            return Ok(Value::Null);
+        }
+        serde_json::from_slice(&body).map_err(|error| CtlError::InvalidResponse(format!("{url}: {error}")))
+    }
+}
@@ -0,0 +1,408 @@
+mod api;
+
+use api::{AdminClient, CtlError};
+use backend_sim::sim_control::sim_control_client::SimControlClient;
+use backend_sim::sim_control::{CrashRequest, Faults};
+use clap::{Parser, Subcommand};
+use prequal::prober;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+use tonic::transport::Endpoint;
+
+/// Decisions printed when tailing starts, like `tail`
+const TAIL_BACKLOG: usize = 10;
+
+/**
+Operates a running load balancer through its admin API
+*/
+#[derive(Parser, Debug)]
+#[command(name = "prequalctl", version)]
+struct Cli {
+    /// Base URL of the admin API, see ADMIN_ADDR of the load balancer
+    #[arg(long, env = "PREQUAL_ADMIN_URL", default_value = "http://[::1]:9090")]
+    admin_url: String,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Lists the backends of every cluster
+    Backends,
+    /// Shows the probe pool of every cluster
+    Pool,
+    /// Adds a backend to a cluster
+    Add {
+        address: String,
+        #[arg(long, default_value = "default")]
+        cluster: String,
+    },
+    /// Removes a backend from a cluster
+    Remove {
+        address: String,
+        #[arg(long, default_value = "default")]
+        cluster: String,
+    },
+    /// Stops sending new requests to a backend, requests in flight finish
+    Drain {
+        address: String,
+        #[arg(long, default_value = "default")]
+        cluster: String,
+    },
+    /// Changes the policy and probing settings at runtime, all at once or none when one is invalid
+    Set {
+        /// Only this cluster, every cluster when not given. The probe interval is shared by all of them
+        #[arg(long)]
+        cluster: Option<String>,
+        #[arg(long, value_parser = ["prequal", "round_robin", "random"])]
+        policy: Option<String>,
+        #[arg(long)]
+        q_rif: Option<f32>,
+        #[arg(long)]
+        probe_pool_size: Option<usize>,
+        #[arg(long)]
+        probe_interval_ms: Option<u64>,
+    },
+    /// Follows the routing decisions
+    Tail {
+        /// Only this cluster, every cluster when not given
+        #[arg(long)]
+        cluster: Option<String>,
+        #[arg(long, default_value_t = 500)]
+        poll_interval_ms: u64,
+    },
+    /// Calls the metrics RPC of a backend directly, bypassing the load balancer
+    Probe { address: String },
+    /// Replaces the faults injected by a backend-sim, no flags clears them
+    Faults {
+        address: String,
+        /// Fails this share of the requests with a gRPC code, e.g. 14=0.1, repeatable
+        #[arg(long = "error-rate", value_parser = parse_error_rate)]
+        error_rates: Vec<(i32, f64)>,
+        #[arg(long, default_value_t = 0.0)]
+        spike_rate: f64,
+        #[arg(long, default_value_t = 0)]
+        spike_ms: u64,
+        /// Holds every request until the faults are replaced
+        #[arg(long)]
+        stall: bool,
+        #[arg(long, default_value_t = 0)]
+        metrics_delay_ms: u64,
+        #[arg(long)]
+        report_zero_rif: bool,
+    },
+    /// Crashes a backend-sim, it refuses connections and fails requests until it restarts
+    Crash {
+        address: String,
+        #[arg(long, default_value_t = 5000)]
+        down_ms: u64,
+    },
+}
+
+fn parse_error_rate(value: &str) -> Result<(i32, f64), String> {
+    let (code, rate) = value
+        .split_once('=')
+        .ok_or_else(|| format!("`{value}` should look like 14=0.1"))?;
+    let code = code.parse::<i32>().map_err(|_| format!("`{code}` is not a gRPC code"))?;
+    let rate = rate.parse::<f64>().map_err(|_| format!("`{rate}` is not a rate"))?;
+    Ok((code, rate))
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    match run(cli).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("{error}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(cli: Cli) -> Result<(), CtlError> {
+    let admin = AdminClient::new(&cli.admin_url);
+    match cli.command {
+        Command::Backends => print!("{}", render_backends(&admin.state().await?)),
+        Command::Pool => print!("{}", render_pool(&admin.state().await?)),
+        Command::Add { address, cluster } => {
+            admin.add_backend(&cluster, &address).await?;
+            println!("Added {address} to {cluster}");
+        }
+        Command::Remove { address, cluster } => {
+            admin.remove_backend(&cluster, &address).await?;
+            println!("Removed {address} from {cluster}");
+        }
+        Command::Drain { address, cluster } => {
+            admin.drain_backend(&cluster, &address).await?;
+            println!("Draining {address} in {cluster}");
+        }
+        Command::Set {
+            cluster,
+            policy,
+            q_rif,
+            probe_pool_size,
+            probe_interval_ms,
+        } => {
+            let settings = json!({
+                "cluster": cluster,
+                "policy": policy,
+                "q_rif": q_rif,
+                "probe_pool_size": probe_pool_size,
+                "probe_interval_ms": probe_interval_ms,
+            });
+            print!("{}", render_pool(&admin.update_settings(settings).await?));
+        }
+        Command::Tail {
+            cluster,
+            poll_interval_ms,
+        } => tail(&admin, cluster, Duration::from_millis(poll_interval_ms)).await?,
+        Command::Probe { address } => {
+            let channel = Endpoint::from_shared(address.clone())
+                .map_err(|error| CtlError::ProbeFailed(format!("{address}: {error}")))?
+                .connect()
+                .await
+                .map_err(|error| CtlError::ProbeFailed(format!("{address}: {error}")))?;
+            let start = Instant::now();
+            let metric = prober::probe(channel)
+                .await
+                .map_err(|status| CtlError::ProbeFailed(format!("{address}: {status}")))?;
+            println!("{metric}");
+            println!("start_epoch_ms: {} timestamp_ms: {}", metric.start_epoch_ms, metric.timestamp_ms);
+            println!("max_concurrency: {} fleet_rif: {}", metric.max_concurrency, metric.fleet_rif);
+            let mut methods = metric.method_latency.iter().collect::<Vec<_>>();
+            methods.sort();
+            for (method, latency) in methods {
+                println!("latency of {method}: {latency}");
+            }
+            println!("round trip: {:?}", start.elapsed());
+        }
+        Command::Faults {
+            address,
+            error_rates,
+            spike_rate,
+            spike_ms,
+            stall,
+            metrics_delay_ms,
+            report_zero_rif,
+        } => {
+            let faults = Faults {
+                error_rates: error_rates.into_iter().collect(),
+                spike_rate,
+                spike_ms,
+                stall,
+                metrics_delay_ms,
+                report_zero_rif,
+            };
+            let faults = control(&address)
+                .await?
+                .set_faults(faults)
+                .await
+                .map_err(|status| CtlError::ControlFailed(format!("{address}: {}", status.message())))?
+                .into_inner();
+            println!("{faults:?}");
+        }
+        Command::Crash { address, down_ms } => {
+            control(&address)
+                .await?
+                .crash(CrashRequest { down_ms })
+                .await
+                .map_err(|status| CtlError::ControlFailed(format!("{address}: {}", status.message())))?;
+            println!("Crashed {address} for {down_ms} ms");
+        }
+    }
+    Ok(())
+}
+
+async fn control(address: &str) -> Result<SimControlClient<tonic::transport::Channel>, CtlError> {
+    SimControlClient::connect(address.to_string())
+        .await
+        .map_err(|error| CtlError::ControlFailed(format!("{address}: {error}")))
+}
+
+/**
+Prints the last decisions, then polls for new ones until interrupted
+*/
+async fn tail(admin: &AdminClient, cluster: Option<String>, poll_interval: Duration) -> Result<(), CtlError> {
+    let clusters = match cluster {
+        Some(cluster) => vec![cluster],
+        None => cluster_names(&admin.state().await?),
+    };
+    let mut last_seq: HashMap<String, u64> = HashMap::new();
+    for cluster in &clusters {
+        let decisions = admin.decisions(cluster, None).await?;
+        for decision in decisions.iter().skip(decisions.len().saturating_sub(TAIL_BACKLOG)) {
+            println!("{}", render_decision(cluster, decision));
+        }
+        if let Some(seq) = decisions.last().and_then(|decision| decision["seq"].as_u64()) {
+            last_seq.insert(cluster.clone(), seq);
+        }
+    }
+    loop {
+        tokio::time::sleep(poll_interval).await;
+        for cluster in &clusters {
+            for decision in admin.decisions(cluster, last_seq.get(cluster).copied()).await? {
+                println!("{}", render_decision(cluster, &decision));
+                if let Some(seq) = decision["seq"].as_u64() {
+                    last_seq.insert(cluster.clone(), seq);
+                }
+            }
+        }
+    }
+}
+
+fn cluster_names(state: &Value) -> Vec<String> {
+    clusters(state)
+        .iter()
+        .filter_map(|cluster| cluster["name"].as_str().map(str::to_string))
+        .collect()
+}
+
+fn clusters(state: &Value) -> &[Value] {
+    state.as_array().map(Vec::as_slice).unwrap_or_default()
+}
+
+fn backends(cluster: &Value) -> &[Value] {
+    cluster["backends"].as_array().map(Vec::as_slice).unwrap_or_default()
+}
+
+/**
+Shows a missing value as `-` and strings without their quotes
+*/
+fn field(value: &Value) -> String {
+    match value {
+        Value::Null => "-".to_string(),
+        Value::String(value) => value.clone(),
+        Value::Number(number) if number.is_f64() => format!("{:.2}", number.as_f64().unwrap_or_default()),
+        other => other.to_string(),
+    }
+}
+
+fn render_backends(state: &Value) -> String {
+    let mut out = format!(
+        "{:<12} {:<32} {:<7} {:<9} {:>9} {:>8}\n",
+        "CLUSTER", "BACKEND", "ACTIVE", "DRAINING", "IN_FLIGHT", "ROUTED"
+    );
+    for cluster in clusters(state) {
+        for backend in backends(cluster) {
+            out += &format!(
+                "{:<12} {:<32} {:<7} {:<9} {:>9} {:>8}\n",
+                field(&cluster["name"]),
+                field(&backend["address"]),
+                field(&backend["active"]),
+                field(&backend["draining"]),
+                field(&backend["in_flight"]),
+                field(&backend["routed"]),
+            );
+        }
+    }
+    out
+}
+
+fn render_pool(state: &Value) -> String {
+    let mut out = String::new();
+    for cluster in clusters(state) {
+        out += &format!(
+            "{} policy={} q_rif={} max_rif={} probe_interval_ms={} probe_pool_size={}\n",
+            field(&cluster["name"]),
+            field(&cluster["policy"]),
+            field(&cluster["q_rif"]),
+            field(&cluster["max_rif"]),
+            field(&cluster["probe_interval_ms"]),
+            field(&cluster["probe_pool_size"]),
+        );
+        out += &format!(
+            "  {:<32} {:>6} {:>12} {:>10} {:<5} {:>8} {:>6}\n",
+            "BACKEND", "RIF", "LATENCY", "NORMALIZED", "STATE", "AGE_MS", "USED"
+        );
+        for backend in backends(cluster) {
+            let probe = &backend["probe"];
+            let state = match probe["hot"].as_bool() {
+                Some(true) => "hot",
+                Some(false) => "cold",
+                None => "-",
+            };
+            out += &format!(
+                "  {:<32} {:>6} {:>12} {:>10} {:<5} {:>8} {:>6}\n",
+                field(&backend["address"]),
+                field(&probe["rif"]),
+                field(&probe["latency"]),
+                field(&probe["normalized_rif"]),
+                state,
+                field(&probe["age_ms"]),
+                field(&probe["times_used"]),
+            );
+        }
+    }
+    out
+}
+
+fn render_decision(cluster: &str, decision: &Value) -> String {
+    format!(
+        "{} {} {} {}",
+        field(&decision["timestamp_ms"]),
+        cluster,
+        field(&decision["server"]),
+        field(&decision["reason"])
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state() -> Value {
+        json!([{
+            "name": "default",
+            "policy": "prequal",
+            "q_rif": 0.7,
+            "probe_interval_ms": 100,
+            "probe_pool_size": 2,
+            "max_rif": 10,
+            "backends": [
+                {
+                    "address": "http://[::1]:50052",
+                    "active": true,
+                    "draining": false,
+                    "in_flight": 3,
+                    "routed": 12,
+                    "probe": {"rif": 10, "latency": 500, "normalized_rif": 1.0, "hot": true, "age_ms": 40, "times_used": 1}
+                },
+                {"address": "http://[::1]:50053", "active": false, "draining": true, "in_flight": 0, "routed": 0, "probe": null}
+            ]
+        }])
+    }
+
+    #[test]
+    fn test_render_backends() {
+        let lines = render_backends(&state()).lines().map(str::to_string).collect::<Vec<String>>();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[1].starts_with("default      http://[::1]:50052"));
+        assert_eq!(lines[2].split_whitespace().collect::<Vec<&str>>(), ["default", "http://[::1]:50053", "false", "true", "0", "0"]);
+    }
+
+    #[test]
+    fn test_render_pool() {
+        let pool = render_pool(&state());
+        let lines = pool.lines().collect::<Vec<&str>>();
+        assert_eq!(lines[0], "default policy=prequal q_rif=0.70 max_rif=10 probe_interval_ms=100 probe_pool_size=2");
+        assert_eq!(lines[2].split_whitespace().collect::<Vec<&str>>(), ["http://[::1]:50052", "10", "500", "1.00", "hot", "40", "1"]);
+        // No probe yet
+        assert_eq!(lines[3].split_whitespace().collect::<Vec<&str>>(), ["http://[::1]:50053", "-", "-", "-", "-", "-", "-"]);
+    }
+
+    #[test]
+    fn test_cli_parses_subcommands() {
+        let cli = Cli::parse_from(["prequalctl", "--admin-url", "http://lb:9090", "set", "--q-rif", "0.5"]);
+        assert_eq!(cli.admin_url, "http://lb:9090");
+        assert!(matches!(cli.command, Command::Set { q_rif: Some(q_rif), cluster: None, .. } if q_rif == 0.5));
+        assert!(Cli::try_parse_from(["prequalctl", "set", "--policy", "fastest"]).is_err());
+        let cli = Cli::parse_from(["prequalctl", "drain", "http://[::1]:50052", "--cluster", "canary"]);
+        assert!(matches!(cli.command, Command::Drain { cluster, .. } if cluster == "canary"));
+        let cli = Cli::parse_from(["prequalctl", "faults", "http://[::1]:50052", "--error-rate", "14=0.1", "--stall"]);
+        assert!(matches!(cli.command, Command::Faults { error_rates, stall: true, .. } if error_rates == [(14, 0.1)]));
+        assert!(Cli::try_parse_from(["prequalctl", "faults", "http://[::1]:50052", "--error-rate", "14"]).is_err());
+    }
+}
@@ -1,4 +1,5 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     tonic_build::compile_protos("../../../proto/helloworld.proto")?;
+    tonic_build::compile_protos("../../../proto/simcontrol.proto")?;
     Ok(())
-}
\ No newline at end of file
+}
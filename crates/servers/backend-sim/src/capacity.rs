@@ -0,0 +1,234 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering::SeqCst;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use tokio::time::sleep;
+use utils::inflight::InFlightGuard;
+
+/// Processor sharing re-evaluates the share of every request this often
+const SHARING_TICK: Duration = Duration::from_millis(1);
+
+/**
+How requests in flight compete for the backend, on the command line:
+
+- `unlimited` every request is served right away, latency does not depend on load
+- `workers:4` 4 requests are served at once, the others wait in a FIFO queue
+- `ps:2` processor sharing over 2 cores, with n requests in service each one progresses at `min(1, 2 / n)`
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CapacityModel {
+    #[default]
+    Unlimited,
+    Workers(u32),
+    ProcessorSharing(u32),
+}
+
+impl FromStr for CapacityModel {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let count = |params: &str| {
+            params
+                .parse::<u32>()
+                .ok()
+                .filter(|count| *count > 0)
+                .ok_or_else(|| format!("`{params}` should be a positive number"))
+        };
+        match value.split_once(':') {
+            None if value == "unlimited" => Ok(CapacityModel::Unlimited),
+            Some(("workers", params)) => Ok(CapacityModel::Workers(count(params)?)),
+            Some(("ps", params)) => Ok(CapacityModel::ProcessorSharing(count(params)?)),
+            _ => Err(format!("`{value}` should look like unlimited, workers:4 or ps:2")),
+        }
+    }
+}
+
+impl fmt::Display for CapacityModel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CapacityModel::Unlimited => write!(f, "unlimited"),
+            CapacityModel::Workers(workers) => write!(f, "workers:{workers}"),
+            CapacityModel::ProcessorSharing(cores) => write!(f, "ps:{cores}"),
+        }
+    }
+}
+
+/**
+Serves the work of every request according to the capacity model, at the speed of this replica.
+Work is the time a request takes on an idle replica with speed 1, a replica with speed 0.5 takes twice as long.
+*/
+#[derive(Debug)]
+pub struct Capacity {
+    model: CapacityModel,
+    speed: f64,
+    workers: Semaphore,
+    /// Requests waiting for a worker
+    queued: Arc<AtomicU32>,
+    /// Requests sharing the cores
+    sharing: Arc<AtomicU32>,
+}
+
+impl Default for Capacity {
+    fn default() -> Self {
+        Self::new(CapacityModel::default(), 1.0)
+    }
+}
+
+impl Capacity {
+    pub fn new(model: CapacityModel, speed: f64) -> Self {
+        let workers = match model {
+            CapacityModel::Workers(workers) => workers as usize,
+            _ => 0,
+        };
+        Self {
+            model,
+            speed,
+            workers: Semaphore::new(workers),
+            queued: Arc::new(AtomicU32::new(0)),
+            sharing: Arc::new(AtomicU32::new(0)),
+        }
+    }
+    /**
+    Requests which can be served at once, 0 when unlimited
+    */
+    pub fn concurrency(&self) -> u32 {
+        match self.model {
+            CapacityModel::Unlimited => 0,
+            CapacityModel::Workers(workers) => workers,
+            CapacityModel::ProcessorSharing(cores) => cores,
+        }
+    }
+    /**
+    Requests accepted but not being served yet
+    */
+    pub fn queue_length(&self) -> u32 {
+        match self.model {
+            CapacityModel::Workers(_) => self.queued.load(SeqCst),
+            // Every request is served, only slower
+            CapacityModel::Unlimited | CapacityModel::ProcessorSharing(_) => 0,
+        }
+    }
+    /**
+    Resolves once the work is done, including the time spent waiting for a worker.
+    A request which is cancelled midway leaves the queue and the shared cores when the future is dropped.
+    */
+    pub async fn serve(&self, work: Duration) {
+        let service_time = work.div_f64(self.speed);
+        match self.model {
+            CapacityModel::Unlimited => sleep(service_time).await,
+            CapacityModel::Workers(_) => {
+                let queued = InFlightGuard::new(self.queued.clone());
+                let permit = self.workers.acquire().await;
+                drop(queued);
+                sleep(service_time).await;
+                drop(permit);
+            }
+            CapacityModel::ProcessorSharing(cores) => {
+                let _sharing = InFlightGuard::new(self.sharing.clone());
+                let mut remaining = service_time;
+                while !remaining.is_zero() {
+                    let sharing = self.sharing.load(SeqCst).max(1);
+                    let share = (cores as f64 / sharing as f64).min(1.0);
+                    let start = Instant::now();
+                    sleep(remaining.div_f64(share).min(SHARING_TICK)).await;
+                    remaining = remaining.saturating_sub(start.elapsed().mul_f64(share));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    /// Serves `count` requests of `work` at once and returns how long the last one took
+    async fn serve_concurrently(capacity: Capacity, count: usize, work: Duration) -> Duration {
+        let capacity = Arc::new(capacity);
+        let start = Instant::now();
+        let requests = (0..count).map(|_| {
+            let capacity = capacity.clone();
+            tokio::spawn(async move { capacity.serve(work).await })
+        });
+        for request in requests.collect::<Vec<_>>() {
+            request.await.unwrap();
+        }
+        start.elapsed()
+    }
+
+    #[test]
+    fn test_parse_capacity_models() {
+        assert_eq!("unlimited".parse(), Ok(CapacityModel::Unlimited));
+        assert_eq!("workers:4".parse(), Ok(CapacityModel::Workers(4)));
+        assert_eq!("ps:2".parse(), Ok(CapacityModel::ProcessorSharing(2)));
+        assert!("workers:0".parse::<CapacityModel>().is_err());
+        assert!("ps".parse::<CapacityModel>().is_err());
+        assert_eq!(CapacityModel::Workers(3).to_string(), "workers:3");
+    }
+
+    #[tokio::test]
+    async fn test_unlimited_latency_does_not_depend_on_load() {
+        let elapsed = serve_concurrently(Capacity::default(), 4, Duration::from_millis(20)).await;
+        assert!(elapsed < Duration::from_millis(40), "{elapsed:?}");
+    }
+
+    #[tokio::test]
+    async fn test_workers_queue_the_requests() {
+        let capacity = Arc::new(Capacity::new(CapacityModel::Workers(1), 1.0));
+        let first = tokio::spawn({
+            let capacity = capacity.clone();
+            async move { capacity.serve(Duration::from_millis(30)).await }
+        });
+        let second = tokio::spawn({
+            let capacity = capacity.clone();
+            async move { capacity.serve(Duration::from_millis(30)).await }
+        });
+        sleep(Duration::from_millis(10)).await;
+        assert_eq!(capacity.queue_length(), 1);
+        first.await.unwrap();
+        second.await.unwrap();
+        assert_eq!(capacity.queue_length(), 0);
+
+        let elapsed = serve_concurrently(Capacity::new(CapacityModel::Workers(2), 1.0), 4, Duration::from_millis(20)).await;
+        assert!(elapsed >= Duration::from_millis(40), "{elapsed:?}");
+    }
+
+    #[tokio::test]
+    async fn test_processor_sharing_slows_every_request_down() {
+        let elapsed = serve_concurrently(Capacity::new(CapacityModel::ProcessorSharing(1), 1.0), 3, Duration::from_millis(20)).await;
+        assert!(elapsed >= Duration::from_millis(55), "{elapsed:?}");
+        assert!(elapsed < Duration::from_millis(150), "{elapsed:?}");
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_requests_release_the_counters() {
+        let capacity = Capacity::new(CapacityModel::Workers(1), 1.0);
+        let _permit = capacity.workers.acquire().await.unwrap();
+        let waiting = tokio::time::timeout(Duration::from_millis(10), capacity.serve(Duration::from_millis(10)));
+        let (_, queue_length) = tokio::join!(waiting, async {
+            sleep(Duration::from_millis(5)).await;
+            capacity.queue_length()
+        });
+        assert_eq!(queue_length, 1);
+        assert_eq!(capacity.queued.load(SeqCst), 0);
+
+        let capacity = Capacity::new(CapacityModel::ProcessorSharing(1), 1.0);
+        let serving = tokio::time::timeout(Duration::from_millis(10), capacity.serve(Duration::from_secs(1)));
+        let (_, sharing) = tokio::join!(serving, async {
+            sleep(Duration::from_millis(5)).await;
+            capacity.sharing.load(SeqCst)
+        });
+        assert_eq!(sharing, 1);
+        assert_eq!(capacity.sharing.load(SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_slow_replicas_take_longer() {
+        let elapsed = serve_concurrently(Capacity::new(CapacityModel::Unlimited, 0.5), 1, Duration::from_millis(20)).await;
+        assert!(elapsed >= Duration::from_millis(40), "{elapsed:?}");
+    }
+}
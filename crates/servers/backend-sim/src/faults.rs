@@ -0,0 +1,269 @@
+use crate::sim_control::sim_control_server::SimControl;
+use crate::sim_control::{CrashRequest, Empty, Faults};
+use rand::Rng;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::SeqCst;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::sleep;
+use tonic::{Code, Request, Response, Status};
+use utils::clock::epoch_millis;
+use utils::latencies::LatencyTracker;
+
+#[derive(Debug, Default, Clone)]
+struct FaultState {
+    faults: Faults,
+    /// Down after a crash until the restart
+    crashed: bool,
+    /// Counts the crashes, only the restart of the last one brings the backend back up
+    crashes: u64,
+}
+
+/**
+Faults of a backend, shared by the Greeter service which suffers them and the control service which sets them
+*/
+#[derive(Debug)]
+pub struct FaultInjector {
+    /// Stalled requests wait for changes on it
+    state: watch::Sender<FaultState>,
+}
+
+impl Default for FaultInjector {
+    fn default() -> Self {
+        Self::new(Faults::default())
+    }
+}
+
+/**
+Rates must be probabilities and error codes actual errors
+*/
+pub fn validate(faults: &Faults) -> Result<(), String> {
+    for (code, rate) in &faults.error_rates {
+        if !(1..=16).contains(code) {
+            return Err(format!("{code} is not a gRPC error code"));
+        }
+        if !(0.0..=1.0).contains(rate) {
+            return Err(format!("the error rate {rate} of code {code} is not within [0, 1]"));
+        }
+    }
+    if !(0.0..=1.0).contains(&faults.spike_rate) {
+        return Err(format!("the spike rate {} is not within [0, 1]", faults.spike_rate));
+    }
+    Ok(())
+}
+
+impl FaultInjector {
+    pub fn new(faults: Faults) -> Self {
+        Self {
+            state: watch::channel(FaultState {
+                faults,
+                ..Default::default()
+            })
+            .0,
+        }
+    }
+    pub fn faults(&self) -> Faults {
+        self.state.borrow().faults.clone()
+    }
+    /**
+    Replaces every fault, lifting a stall releases the requests it held
+    */
+    pub fn set_faults(&self, faults: Faults) -> Result<(), String> {
+        validate(&faults)?;
+        tracing::info!(?faults, "Injecting faults");
+        self.state.send_modify(|state| state.faults = faults);
+        Ok(())
+    }
+    pub fn is_crashed(&self) -> bool {
+        self.state.borrow().crashed
+    }
+    /**
+    Takes the backend down until `restart` is called with the returned crash
+    */
+    fn crash(&self) -> u64 {
+        let mut crash = 0;
+        self.state.send_modify(|state| {
+            state.crashes += 1;
+            state.crashed = true;
+            crash = state.crashes;
+        });
+        crash
+    }
+    /**
+    Brings the backend back up, unless it crashed again since `crash`.
+    Returns whether it restarted.
+    */
+    fn restart(&self, crash: u64) -> bool {
+        self.state.send_if_modified(|state| {
+            let restarted = state.crashed && state.crashes == crash;
+            if restarted {
+                state.crashed = false;
+            }
+            restarted
+        })
+    }
+    pub fn reports_zero_rif(&self) -> bool {
+        self.state.borrow().faults.report_zero_rif
+    }
+    /**
+    Applies the faults to a request before it is served: waits out a stall,
+    then fails it or delays it by a spike at the configured rates
+    */
+    pub async fn before_request(&self) -> Result<(), Status> {
+        let mut state = self.state.subscribe();
+        let faults = match state.wait_for(|state| !state.faults.stall || state.crashed).await {
+            Ok(state) if state.crashed => return Err(Status::unavailable("the backend crashed")),
+            Ok(state) => state.faults.clone(),
+            // The sender lives as long as the injector
+            Err(_) => return Ok(()),
+        };
+        let spike = {
+            let mut rng = rand::thread_rng();
+            let mut codes = faults.error_rates.iter().collect::<Vec<_>>();
+            codes.sort_by_key(|(code, _)| **code);
+            for (code, rate) in codes {
+                if rng.gen_bool(*rate) {
+                    return Err(Status::new(Code::from_i32(*code), "injected fault"));
+                }
+            }
+            faults.spike_rate > 0.0 && rng.gen_bool(faults.spike_rate)
+        };
+        if spike {
+            sleep(Duration::from_millis(faults.spike_ms)).await;
+        }
+        Ok(())
+    }
+    /**
+    Applies the faults to a GetMetrics call
+    */
+    pub async fn before_metrics(&self) -> Result<(), Status> {
+        let (crashed, delay) = {
+            let state = self.state.borrow();
+            (state.crashed, state.faults.metrics_delay_ms)
+        };
+        if crashed {
+            return Err(Status::unavailable("the backend crashed"));
+        }
+        sleep(Duration::from_millis(delay)).await;
+        Ok(())
+    }
+}
+
+/**
+Control RPC of the simulated backend
+*/
+#[derive(Debug)]
+pub struct SimControlService {
+    pub faults: Arc<FaultInjector>,
+    /// Cleared on restart
+    pub latencies: Arc<Mutex<LatencyTracker>>,
+    /// Set on restart
+    pub start_epoch_ms: Arc<AtomicU64>,
+}
+
+#[tonic::async_trait]
+impl SimControl for SimControlService {
+    async fn set_faults(&self, request: Request<Faults>) -> Result<Response<Faults>, Status> {
+        self.faults.set_faults(request.into_inner()).map_err(Status::invalid_argument)?;
+        Ok(Response::new(self.faults.faults()))
+    }
+    async fn get_faults(&self, _request: Request<Empty>) -> Result<Response<Faults>, Status> {
+        Ok(Response::new(self.faults.faults()))
+    }
+    async fn crash(&self, request: Request<CrashRequest>) -> Result<Response<Empty>, Status> {
+        let down = Duration::from_millis(request.into_inner().down_ms);
+        tracing::warn!("Crashing, back in {:?}", down);
+        let crash = self.faults.crash();
+        let (faults, latencies, start_epoch_ms) = (self.faults.clone(), self.latencies.clone(), self.start_epoch_ms.clone());
+        tokio::spawn(async move {
+            sleep(down).await;
+            // A later crash keeps the backend down until its own restart
+            if !faults.restart(crash) {
+                return;
+            }
+            *latencies.lock().unwrap() = LatencyTracker::default();
+            start_epoch_ms.store(epoch_millis(), SeqCst);
+            tracing::info!("Restarted after the crash");
+        });
+        Ok(Response::new(Empty {}))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_faults_are_validated() {
+        let mut faults = Faults {
+            error_rates: HashMap::from([(14, 0.5)]),
+            ..Default::default()
+        };
+        assert!(validate(&faults).is_ok());
+        faults.error_rates = HashMap::from([(0, 0.5)]);
+        assert!(validate(&faults).is_err());
+        faults.error_rates = HashMap::from([(14, 1.5)]);
+        assert!(validate(&faults).is_err());
+        faults.error_rates.clear();
+        faults.spike_rate = -0.1;
+        assert!(validate(&faults).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_error_rates_and_spikes() {
+        let injector = FaultInjector::new(Faults {
+            error_rates: HashMap::from([(8, 1.0)]),
+            ..Default::default()
+        });
+        assert_eq!(injector.before_request().await.unwrap_err().code(), Code::ResourceExhausted);
+        injector
+            .set_faults(Faults {
+                spike_rate: 1.0,
+                spike_ms: 30,
+                ..Default::default()
+            })
+            .unwrap();
+        let start = std::time::Instant::now();
+        injector.before_request().await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(30));
+    }
+
+    #[tokio::test]
+    async fn test_stall_holds_requests_until_lifted() {
+        let injector = Arc::new(FaultInjector::new(Faults {
+            stall: true,
+            ..Default::default()
+        }));
+        let stalled = tokio::spawn({
+            let injector = injector.clone();
+            async move { injector.before_request().await }
+        });
+        sleep(Duration::from_millis(20)).await;
+        assert!(!stalled.is_finished());
+        injector.set_faults(Faults::default()).unwrap();
+        assert!(stalled.await.unwrap().is_ok());
+
+        // A crash fails the stalled requests
+        injector.set_faults(Faults { stall: true, ..Default::default() }).unwrap();
+        let stalled = tokio::spawn({
+            let injector = injector.clone();
+            async move { injector.before_request().await }
+        });
+        sleep(Duration::from_millis(20)).await;
+        injector.crash();
+        assert_eq!(stalled.await.unwrap().unwrap_err().code(), Code::Unavailable);
+    }
+
+    #[test]
+    fn test_overlapping_crashes_restart_with_the_last_one() {
+        let injector = FaultInjector::default();
+        let first = injector.crash();
+        let second = injector.crash();
+        assert!(!injector.restart(first));
+        assert!(injector.is_crashed());
+        assert!(injector.restart(second));
+        assert!(!injector.is_crashed());
+    }
+}
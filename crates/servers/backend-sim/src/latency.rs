@@ -0,0 +1,123 @@
+use rand::Rng;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/**
+How long the simulated backend works on a request, in milliseconds on the command line:
+
+- `fixed:5` always 5 ms
+- `uniform:0-10` uniformly between 0 and 10 ms
+- `exp:5` exponentially distributed with a mean of 5 ms
+*/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LatencyModel {
+    Fixed(Duration),
+    Uniform { min: Duration, max: Duration },
+    Exponential { mean: Duration },
+}
+
+impl Default for LatencyModel {
+    fn default() -> Self {
+        Self::Uniform {
+            min: Duration::ZERO,
+            max: Duration::from_millis(10),
+        }
+    }
+}
+
+impl LatencyModel {
+    pub fn sample(&self, rng: &mut impl Rng) -> Duration {
+        match *self {
+            LatencyModel::Fixed(latency) => latency,
+            LatencyModel::Uniform { min, max } => rng.gen_range(min..=max),
+            LatencyModel::Exponential { mean } => {
+                // Inverse transform, 1 - u is never 0 so the logarithm stays finite
+                let u: f64 = rng.gen();
+                mean.mul_f64(-(1.0 - u).ln())
+            }
+        }
+    }
+}
+
+fn parse_millis(value: &str) -> Result<Duration, String> {
+    value
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|millis| millis.is_finite() && *millis >= 0.0)
+        .map(|millis| Duration::from_secs_f64(millis / 1000.0))
+        .ok_or_else(|| format!("`{value}` is not a number of milliseconds"))
+}
+
+impl FromStr for LatencyModel {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (kind, params) = value
+            .split_once(':')
+            .ok_or_else(|| format!("`{value}` should look like fixed:5, uniform:0-10 or exp:5"))?;
+        match kind {
+            "fixed" => Ok(LatencyModel::Fixed(parse_millis(params)?)),
+            "uniform" => {
+                let (min, max) = params
+                    .split_once('-')
+                    .ok_or_else(|| format!("`{params}` should be a range like 0-10"))?;
+                let (min, max) = (parse_millis(min)?, parse_millis(max)?);
+                if min > max {
+                    return Err(format!("the range `{params}` is empty"));
+                }
+                Ok(LatencyModel::Uniform { min, max })
+            }
+            "exp" => Ok(LatencyModel::Exponential {
+                mean: parse_millis(params)?,
+            }),
+            _ => Err(format!("unknown latency model `{kind}`, expected fixed, uniform or exp")),
+        }
+    }
+}
+
+impl fmt::Display for LatencyModel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let millis = |duration: Duration| duration.as_secs_f64() * 1000.0;
+        match *self {
+            LatencyModel::Fixed(latency) => write!(f, "fixed:{}", millis(latency)),
+            LatencyModel::Uniform { min, max } => write!(f, "uniform:{}-{}", millis(min), millis(max)),
+            LatencyModel::Exponential { mean } => write!(f, "exp:{}", millis(mean)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_latency_models() {
+        assert_eq!("fixed:5".parse(), Ok(LatencyModel::Fixed(Duration::from_millis(5))));
+        assert_eq!("uniform:0-10".parse(), Ok(LatencyModel::default()));
+        assert_eq!(
+            "exp:0.5".parse(),
+            Ok(LatencyModel::Exponential {
+                mean: Duration::from_micros(500)
+            })
+        );
+        assert!("uniform:10-0".parse::<LatencyModel>().is_err());
+        assert!("gamma:3".parse::<LatencyModel>().is_err());
+        assert!("fixed".parse::<LatencyModel>().is_err());
+        assert_eq!(LatencyModel::default().to_string().parse(), Ok(LatencyModel::default()));
+    }
+
+    #[test]
+    fn test_samples_follow_the_model() {
+        let mut rng = rand::thread_rng();
+        let uniform = LatencyModel::default();
+        assert!((0..100).all(|_| uniform.sample(&mut rng) <= Duration::from_millis(10)));
+        let exp = LatencyModel::Exponential {
+            mean: Duration::from_millis(4),
+        };
+        let total = (0..10_000).map(|_| exp.sample(&mut rng)).sum::<Duration>();
+        let mean = total / 10_000;
+        assert!(mean > Duration::from_millis(3) && mean < Duration::from_millis(5), "{mean:?}");
+    }
+}
@@ -0,0 +1,760 @@
+pub mod capacity;
+pub mod faults;
+pub mod latency;
+
+use capacity::{Capacity, CapacityModel};
+use clap::Parser;
+use faults::{FaultInjector, SimControlService};
+use hello_world::greeter_server::{Greeter, GreeterServer};
+use hello_world::{Empty, HelloReply, HelloRequest, Metric};
+use latency::LatencyModel;
+use sim_control::sim_control_server::SimControlServer;
+use sim_control::Faults;
+use std::collections::HashMap;
+use std::fs;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tokio_stream::StreamExt;
+use tonic::transport::{Certificate, Identity, Server, ServerTlsConfig};
+use tonic::{Request, Response, Status, Streaming};
+use tower::util::MapResponseLayer;
+use utils::inflight::InFlightGuard;
+use utils::clock::epoch_millis;
+use utils::cpu::CpuSampler;
+use utils::latencies::LatencyTracker;
+use utils::loadreport::LoadReporter;
+use utils::measure_time;
+
+/// Number of replies sent for a single LotsOfReplies request
+const STREAM_REPLIES: usize = 5;
+
+pub mod hello_world {
+    tonic::include_proto!("helloworld");
+}
+
+pub mod sim_control {
+    tonic::include_proto!("simcontrol");
+}
+
+/**
+A simulated backend, run one process per backend with its own id and address
+*/
+#[derive(Parser, Debug, Clone)]
+#[command(name = "backend-sim", version)]
+pub struct Args {
+    /// Reported in probes and in every reply
+    #[arg(long, env = "SERVER_ID", default_value = "server 1")]
+    pub id: String,
+    #[arg(long, default_value = "[::1]:50052")]
+    pub listen_addr: SocketAddr,
+    /// Work of a request on an idle replica: fixed:<ms>, uniform:<min>-<max> or exp:<mean>
+    #[arg(long, default_value_t = LatencyModel::default())]
+    pub latency: LatencyModel,
+    /// How requests in flight compete: unlimited, workers:<count> or ps:<cores>
+    #[arg(long, default_value_t = CapacityModel::default())]
+    pub capacity: CapacityModel,
+    /// Relative speed of this replica, 0.5 takes twice as long for the same work
+    #[arg(long, default_value_t = 1.0, value_parser = parse_speed)]
+    pub speed: f64,
+    /// Reported to the load balancer, defaults to the workers or cores of the capacity model
+    #[arg(long, env = "MAX_CONCURRENCY", default_value_t = 0)]
+    pub max_concurrency: u32,
+    /// Attach the current load to the trailers of every response
+    #[arg(long, env = "LOAD_REPORT_TRAILERS")]
+    pub load_report_trailers: bool,
+    /// Fails this share of the requests with a gRPC code, e.g. 14=0.1 for UNAVAILABLE, repeatable
+    #[arg(long = "error-rate", value_parser = parse_error_rate)]
+    pub error_rates: Vec<(i32, f64)>,
+    /// Delays this share of the requests by --spike-ms
+    #[arg(long, default_value_t = 0.0)]
+    pub spike_rate: f64,
+    #[arg(long, default_value_t = 0)]
+    pub spike_ms: u64,
+    /// Delays every GetMetrics call, the probes of the load balancer go stale
+    #[arg(long, default_value_t = 0)]
+    pub metrics_delay_ms: u64,
+    /// Always reports 0 requests in flight, a misbehaving replica attracting all the traffic
+    #[arg(long)]
+    pub report_zero_rif: bool,
+    /// Certificate and key to serve over TLS
+    #[arg(long, env = "TLS_CERT_PATH", requires = "tls_key_path")]
+    pub tls_cert_path: Option<String>,
+    #[arg(long, env = "TLS_KEY_PATH", requires = "tls_cert_path")]
+    pub tls_key_path: Option<String>,
+    /// Additionally requires clients to present a certificate signed by this CA
+    #[arg(long, env = "TLS_CLIENT_CA_PATH")]
+    pub tls_client_ca_path: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct MyGreeter {
+    pub rif: Arc<AtomicU32>,
+    pub latencies: Arc<Mutex<LatencyTracker>>,
+    pub server_id: String,
+    pub latency_model: LatencyModel,
+    pub capacity: Arc<Capacity>,
+    /// Moves forward when the backend restarts after a simulated crash
+    pub start_epoch_ms: Arc<AtomicU64>,
+    /// Reported to the load balancer, 0 when unlimited
+    pub max_concurrency: u32,
+    pub draining: Arc<AtomicBool>,
+    pub cpu: Arc<Mutex<CpuSampler>>,
+    pub faults: Arc<FaultInjector>,
+}
+
+fn parse_speed(value: &str) -> Result<f64, String> {
+    value
+        .parse::<f64>()
+        .ok()
+        .filter(|speed| speed.is_finite() && *speed > 0.0)
+        .ok_or_else(|| format!("`{value}` should be a positive number"))
+}
+
+fn parse_error_rate(value: &str) -> Result<(i32, f64), String> {
+    let (code, rate) = value
+        .split_once('=')
+        .ok_or_else(|| format!("`{value}` should look like 14=0.1"))?;
+    let code = code.parse::<i32>().map_err(|_| format!("`{code}` is not a gRPC code"))?;
+    let rate = rate.parse::<f64>().map_err(|_| format!("`{rate}` is not a rate"))?;
+    Ok((code, rate))
+}
+
+impl MyGreeter {
+    pub fn new(args: &Args) -> Self {
+        let capacity = Capacity::new(args.capacity, args.speed);
+        Self {
+            server_id: args.id.clone(),
+            latency_model: args.latency,
+            start_epoch_ms: Arc::new(AtomicU64::new(epoch_millis())),
+            max_concurrency: match args.max_concurrency {
+                0 => capacity.concurrency(),
+                max_concurrency => max_concurrency,
+            },
+            capacity: Arc::new(capacity),
+            faults: Arc::new(FaultInjector::new(args.faults())),
+            ..Default::default()
+        }
+    }
+}
+
+impl Args {
+    /**
+    Faults injected from the start, the control RPC changes them later
+    */
+    pub fn faults(&self) -> Faults {
+        Faults {
+            error_rates: self.error_rates.iter().copied().collect::<HashMap<i32, f64>>(),
+            spike_rate: self.spike_rate,
+            spike_ms: self.spike_ms,
+            stall: false,
+            metrics_delay_ms: self.metrics_delay_ms,
+            report_zero_rif: self.report_zero_rif,
+        }
+    }
+}
+
+/**
+Draws the work of a request from the latency model and waits until the capacity model served it.
+Returns how long that took, queueing included.
+*/
+async fn simulate_delay(latency_model: LatencyModel, capacity: &Capacity) -> Duration {
+    let start = Instant::now();
+    let work = latency_model.sample(&mut rand::thread_rng());
+    capacity.serve(work).await;
+    start.elapsed()
+}
+
+#[tonic::async_trait]
+impl Greeter for MyGreeter {
+    async fn say_hello(&self, request: Request<HelloRequest>) -> Result<Response<HelloReply>, Status> {
+        let _guard = InFlightGuard::new(self.rif.clone());
+        self.faults.before_request().await?;
+        tracing::debug!("Got a request: {:?}", request);
+        let macro_response = measure_time!({
+            let delay = simulate_delay(self.latency_model, &self.capacity).await;
+
+            tracing::info!("Simulating delay of {:?} for {}", delay, self.server_id);
+            let reply = HelloReply {
+                message: format!("Hello {}! from {}", request.into_inner().name, self.server_id),
+            };
+            reply
+        });
+        self.latencies.clone().lock().unwrap().add_latency("SayHello", macro_response.1.as_nanos());
+        // tracing::info!("Added the latency {:?}", self.latencies.clone());
+        tracing::info!("Time taken for processing the request is {:?}", macro_response.1);
+        Ok(Response::new(macro_response.0))
+    }
+    async fn get_metrics(&self, _request: Request<Empty>) -> Result<Response<Metric>, Status> {
+        tracing::debug!("Got a request for metrics");
+        self.faults.before_metrics().await?;
+        let rif = match self.faults.reports_zero_rif() {
+            true => 0,
+            false => self.rif.load(Ordering::SeqCst),
+        };
+        let latencies = self.latencies.lock().unwrap();
+        let latency = latencies.find_median().unwrap_or(0);
+        let reply = Metric {
+            rif,
+            latency: latency as u64,
+            server_id: self.server_id.clone(),
+            start_epoch_ms: self.start_epoch_ms.load(Ordering::SeqCst),
+            timestamp_ms: epoch_millis(),
+            queue_length: self.capacity.queue_length(),
+            cpu_utilization: self.cpu.lock().unwrap().sample(),
+            max_concurrency: self.max_concurrency,
+            draining: self.draining.load(Ordering::SeqCst),
+            method_latency: latencies
+                .method_medians()
+                .into_iter()
+                .map(|(method, latency)| (method, latency as u64))
+                .collect(),
+            ..Default::default()
+        };
+        Ok(Response::new(reply))
+    }
+
+    type LotsOfRepliesStream = ReceiverStream<Result<HelloReply, Status>>;
+
+    async fn lots_of_replies(
+        &self,
+        request: Request<HelloRequest>,
+    ) -> Result<Response<Self::LotsOfRepliesStream>, Status> {
+        // The guard moves into the task, the stream is in flight until its last reply is sent
+        let guard = InFlightGuard::new(self.rif.clone());
+        self.faults.before_request().await?;
+        let latencies = self.latencies.clone();
+        let (latency_model, capacity, server_id) = (self.latency_model, self.capacity.clone(), self.server_id.clone());
+        let name = request.into_inner().name;
+        let (tx, rx) = mpsc::channel(STREAM_REPLIES);
+        tokio::spawn(async move {
+            let _guard = guard;
+            for i in 1..=STREAM_REPLIES {
+                let start = Instant::now();
+                simulate_delay(latency_model, &capacity).await;
+                latencies.lock().unwrap().add_latency("LotsOfReplies", start.elapsed().as_nanos());
+                let reply = HelloReply {
+                    message: format!("Hello {}! from {} ({}/{})", name, server_id, i, STREAM_REPLIES),
+                };
+                if tx.send(Ok(reply)).await.is_err() {
+                    // The client went away
+                    break;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn lots_of_greetings(
+        &self,
+        request: Request<Streaming<HelloRequest>>,
+    ) -> Result<Response<HelloReply>, Status> {
+        let _guard = InFlightGuard::new(self.rif.clone());
+        self.faults.before_request().await?;
+        let start = Instant::now();
+        let mut inbound = request.into_inner();
+        let mut names = vec![];
+        while let Some(request) = inbound.message().await? {
+            simulate_delay(self.latency_model, &self.capacity).await;
+            names.push(request.name);
+        }
+        self.latencies.lock().unwrap().add_latency("LotsOfGreetings", start.elapsed().as_nanos());
+        Ok(Response::new(HelloReply {
+            message: format!("Hello {}! from {}", names.join(", "), self.server_id),
+        }))
+    }
+
+    type BidiHelloStream = ReceiverStream<Result<HelloReply, Status>>;
+
+    async fn bidi_hello(
+        &self,
+        request: Request<Streaming<HelloRequest>>,
+    ) -> Result<Response<Self::BidiHelloStream>, Status> {
+        // The guard moves into the task, the stream is in flight until either side closes it
+        let guard = InFlightGuard::new(self.rif.clone());
+        self.faults.before_request().await?;
+        let latencies = self.latencies.clone();
+        let (latency_model, capacity, server_id) = (self.latency_model, self.capacity.clone(), self.server_id.clone());
+        let mut inbound = request.into_inner();
+        let (tx, rx) = mpsc::channel(STREAM_REPLIES);
+        tokio::spawn(async move {
+            let _guard = guard;
+            loop {
+                let reply = match inbound.message().await {
+                    Ok(Some(request)) => {
+                        let start = Instant::now();
+                        simulate_delay(latency_model, &capacity).await;
+                        latencies.lock().unwrap().add_latency("BidiHello", start.elapsed().as_nanos());
+                        Ok(HelloReply {
+                            message: format!("Hello {}! from {}", request.name, server_id),
+                        })
+                    }
+                    Ok(None) => break,
+                    Err(status) => Err(status),
+                };
+                let failed = reply.is_err();
+                if tx.send(reply).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+
+/**
+Serves the simulated backend on the listener until the task is dropped
+*/
+pub async fn serve(args: Args, listener: TcpListener) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    faults::validate(&args.faults())?;
+    let greeter = MyGreeter::new(&args);
+    let load_reporter = LoadReporter::new(args.load_report_trailers, greeter.rif.clone(), greeter.latencies.clone());
+    let control = SimControlService {
+        faults: greeter.faults.clone(),
+        latencies: greeter.latencies.clone(),
+        start_epoch_ms: greeter.start_epoch_ms.clone(),
+    };
+    let faults = greeter.faults.clone();
+
+    let mut builder = Server::builder();
+    if let Some(tls) = tls_config(&args)? {
+        tracing::info!("Serving over TLS");
+        builder = builder.tls_config(tls)?;
+    }
+    tracing::info!("{} listening on {} with latency {}", args.id, listener.local_addr()?, args.latency);
+    builder
+        .layer(MapResponseLayer::new(move |response| load_reporter.attach(response)))
+        .add_service(GreeterServer::new(greeter))
+        .add_service(SimControlServer::new(control))
+        // A crashed backend refuses new connections until it restarts
+        .serve_with_incoming(TcpListenerStream::new(listener).filter(move |_| !faults.is_crashed()))
+        .await?;
+
+    Ok(())
+}
+
+/**
+Serves over TLS when a certificate and key are given.
+A client CA additionally requires clients to present a certificate signed by it.
+*/
+fn tls_config(args: &Args) -> Result<Option<ServerTlsConfig>, Box<dyn std::error::Error + Send + Sync>> {
+    let (Some(cert_path), Some(key_path)) = (&args.tls_cert_path, &args.tls_key_path) else {
+        return Ok(None);
+    };
+    let identity = Identity::from_pem(fs::read(cert_path)?, fs::read(key_path)?);
+    let mut tls = ServerTlsConfig::new().identity(identity);
+    if let Some(client_ca_path) = &args.tls_client_ca_path {
+        tls = tls.client_ca_root(Certificate::from_pem(fs::read(client_ca_path)?));
+    }
+    Ok(Some(tls))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hello_world::greeter_client::GreeterClient;
+    use hello_world::{HelloRequest, Empty};
+    use tonic::transport::Channel;
+    use std::time::Duration;
+    use tokio::time::sleep;
+
+    fn greeter() -> MyGreeter {
+        MyGreeter {
+            server_id: "server 1".to_string(),
+            ..Default::default()
+        }
+    }
+
+    // Serves the greeter on a free port, the listener is bound before returning so clients can connect right away
+    async fn spawn_greeter(greeter: MyGreeter) -> (String, tokio::task::JoinHandle<Result<(), tonic::transport::Error>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = format!("http://{}", listener.local_addr().unwrap());
+        let server = Server::builder()
+            .add_service(GreeterServer::new(greeter))
+            .serve_with_incoming(TcpListenerStream::new(listener));
+        (addr, tokio::spawn(server))
+    }
+
+    // Helper function to create a gRPC client
+    async fn create_client(addr: &str) -> GreeterClient<Channel> {
+        GreeterClient::connect(addr.to_string()).await.unwrap()
+    }
+
+    // The flags pick the id in the replies and the latency model
+    #[tokio::test]
+    async fn test_serve_with_args() {
+        let args = Args::parse_from(["backend-sim", "--id", "server 7", "--latency", "fixed:1"]);
+        assert_eq!(args.latency, LatencyModel::Fixed(Duration::from_millis(1)));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = format!("http://{}", listener.local_addr().unwrap());
+        let server_handle = tokio::spawn(serve(args, listener));
+        let mut client = create_client(&addr).await;
+
+        let request = tonic::Request::new(HelloRequest {
+            name: "world".to_string(),
+        });
+        let response = client.say_hello(request).await.unwrap();
+        assert_eq!(response.into_inner().message, "Hello world! from server 7");
+        let metrics = client.get_metrics(tonic::Request::new(Empty {})).await.unwrap().into_inner();
+        assert_eq!(metrics.server_id, "server 7");
+        assert!(metrics.latency >= 1_000_000);
+
+        server_handle.abort();
+    }
+
+    // With a single worker, requests in flight queue up and the latency grows with the RIF
+    #[tokio::test]
+    async fn test_latency_grows_with_rif() {
+        let args = Args::parse_from(["backend-sim", "--latency", "fixed:20", "--capacity", "workers:1", "--speed", "2"]);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = format!("http://{}", listener.local_addr().unwrap());
+        let server_handle = tokio::spawn(serve(args, listener));
+        let client = create_client(&addr).await;
+
+        let requests = (0..4).map(|i| {
+            let mut client = client.clone();
+            tokio::spawn(async move {
+                let start = Instant::now();
+                let request = tonic::Request::new(HelloRequest { name: format!("client{i}") });
+                client.say_hello(request).await.unwrap();
+                start.elapsed()
+            })
+        });
+        let requests = requests.collect::<Vec<_>>();
+        sleep(Duration::from_millis(5)).await;
+        let metrics = client.clone().get_metrics(tonic::Request::new(Empty {})).await.unwrap().into_inner();
+        assert_eq!((metrics.rif, metrics.queue_length, metrics.max_concurrency), (4, 3, 1));
+        let mut latencies = vec![];
+        for request in requests {
+            latencies.push(request.await.unwrap());
+        }
+        latencies.sort();
+        // 10 ms each at twice the speed, served one after the other
+        assert!(latencies[0] >= Duration::from_millis(10), "{latencies:?}");
+        assert!(latencies[3] >= Duration::from_millis(40), "{latencies:?}");
+
+        server_handle.abort();
+    }
+
+    // Test the `say_hello` method
+    #[tokio::test]
+    async fn test_say_hello() {
+        let (addr, server_handle) = spawn_greeter(greeter()).await;
+
+        // Create a client
+        let mut client = create_client(&addr).await;
+
+        // Test a valid request
+        let request = tonic::Request::new(HelloRequest {
+            name: "world".to_string(),
+        });
+        let response = client.say_hello(request).await.unwrap();
+        assert_eq!(response.into_inner().message, "Hello world! from server 1");
+
+        // Test another request
+        let request = tonic::Request::new(HelloRequest {
+            name: "tonic".to_string(),
+        });
+        let response = client.say_hello(request).await.unwrap();
+        assert_eq!(response.into_inner().message, "Hello tonic! from server 1");
+
+        // Shutdown the server
+        server_handle.abort();
+    }
+
+    // Test the `get_metrics` method
+    #[tokio::test]
+    async fn test_get_metrics() {
+        let (addr, server_handle) = spawn_greeter(greeter()).await;
+
+        // Create a client
+        let mut client = create_client(&addr).await;
+
+        // Send a few requests to populate metrics
+        for _ in 0..5 {
+            let request = tonic::Request::new(HelloRequest {
+                name: "world".to_string(),
+            });
+            client.say_hello(request).await.unwrap();
+        }
+
+        // Fetch metrics
+        let request = tonic::Request::new(Empty {});
+        let response = client.get_metrics(request).await.unwrap();
+        let metrics = response.into_inner();
+
+        // Validate metrics
+        assert_eq!(metrics.rif, 0); // All requests should be processed by now
+        assert!(metrics.latency > 0); // Latency should be recorded
+
+        // Shutdown the server
+        server_handle.abort();
+    }
+
+    // Test concurrent requests
+    #[tokio::test]
+    async fn test_concurrent_requests() {
+        let (addr, server_handle) = spawn_greeter(greeter()).await;
+
+        // Create multiple clients to simulate concurrent requests
+        let mut client1 = create_client(&addr).await;
+        let mut client2 = create_client(&addr).await;
+
+        // Send requests concurrently
+        let handle1 = tokio::spawn(async move {
+            let request = tonic::Request::new(HelloRequest {
+                name: "client1".to_string(),
+            });
+            client1.say_hello(request).await.unwrap()
+        });
+
+        let handle2 = tokio::spawn(async move {
+            let request = tonic::Request::new(HelloRequest {
+                name: "client2".to_string(),
+            });
+            client2.say_hello(request).await.unwrap()
+        });
+
+        // Wait for both requests to complete
+        let response1 = handle1.await.unwrap();
+        let response2 = handle2.await.unwrap();
+
+        // Validate responses
+        assert_eq!(response1.into_inner().message, "Hello client1! from server 1");
+        assert_eq!(response2.into_inner().message, "Hello client2! from server 1");
+
+        // Shutdown the server
+        server_handle.abort();
+    }
+
+    // Test metrics after multiple requests
+    #[tokio::test]
+    async fn test_metrics_after_multiple_requests() {
+        let (addr, server_handle) = spawn_greeter(greeter()).await;
+
+        // Create a client
+        let mut client = create_client(&addr).await;
+
+        // Send multiple requests
+        for i in 0..10 {
+            let request = tonic::Request::new(HelloRequest {
+                name: format!("world{}", i),
+            });
+            client.say_hello(request).await.unwrap();
+        }
+
+        // Fetch metrics
+        let request = tonic::Request::new(Empty {});
+        let response = client.get_metrics(request).await.unwrap();
+        let metrics = response.into_inner();
+
+        // Validate metrics
+        assert_eq!(metrics.rif, 0); // All requests should be processed by now
+        assert!(metrics.latency > 0); // Latency should be recorded
+
+        // Shutdown the server
+        server_handle.abort();
+    }
+
+    // Test server behavior under high load
+    #[tokio::test]
+    async fn test_high_load() {
+        let (addr, server_handle) = spawn_greeter(greeter()).await;
+
+        // Create multiple clients to simulate high load
+        let mut handles = vec![];
+        for i in 0..50 {
+            let mut client = create_client(&addr).await;
+            let handle = tokio::spawn(async move {
+                let request = tonic::Request::new(HelloRequest {
+                    name: format!("client{}", i),
+                });
+                client.say_hello(request).await.unwrap()
+            });
+            handles.push(handle);
+        }
+
+        // Wait for all requests to complete
+        for handle in handles {
+            handle.await.unwrap();
+        }
+
+        // Fetch metrics
+        let mut client = create_client(&addr).await;
+        let request = tonic::Request::new(Empty {});
+        let response = client.get_metrics(request).await.unwrap();
+        let metrics = response.into_inner();
+
+        // Validate metrics
+        assert_eq!(metrics.rif, 0); // All requests should be processed by now
+        assert!(metrics.latency > 0); // Latency should be recorded
+
+        // Shutdown the server
+        server_handle.abort();
+    }
+
+    // Test the server streaming `lots_of_replies` method
+    #[tokio::test]
+    async fn test_lots_of_replies() {
+        let (addr, server_handle) = spawn_greeter(greeter()).await;
+        let mut client = create_client(&addr).await;
+
+        let request = tonic::Request::new(HelloRequest {
+            name: "world".to_string(),
+        });
+        let mut replies = client.lots_of_replies(request).await.unwrap().into_inner();
+        let mut messages = vec![];
+        while let Some(reply) = replies.message().await.unwrap() {
+            messages.push(reply.message);
+        }
+        assert_eq!(messages.len(), STREAM_REPLIES);
+        assert_eq!(messages[0], "Hello world! from server 1 (1/5)");
+
+        let metrics = client.get_metrics(tonic::Request::new(Empty {})).await.unwrap().into_inner();
+        assert_eq!(metrics.rif, 0);
+        assert!(metrics.latency > 0);
+
+        server_handle.abort();
+    }
+
+    // Test the client streaming `lots_of_greetings` method
+    #[tokio::test]
+    async fn test_lots_of_greetings() {
+        let (addr, server_handle) = spawn_greeter(greeter()).await;
+        let mut client = create_client(&addr).await;
+
+        let requests = ["alice", "bob", "carol"].map(|name| HelloRequest {
+            name: name.to_string(),
+        });
+        let response = client.lots_of_greetings(tokio_stream::iter(requests)).await.unwrap();
+        assert_eq!(response.into_inner().message, "Hello alice, bob, carol! from server 1");
+
+        server_handle.abort();
+    }
+
+    // An open bidirectional stream counts as one request in flight until it is closed
+    #[tokio::test]
+    async fn test_bidi_hello_counts_rif_for_stream_lifetime() {
+        let (addr, server_handle) = spawn_greeter(greeter()).await;
+        let mut client = create_client(&addr).await;
+
+        let (tx, rx) = mpsc::channel(4);
+        let mut replies = client.bidi_hello(ReceiverStream::new(rx)).await.unwrap().into_inner();
+        for name in ["alice", "bob"] {
+            tx.send(HelloRequest { name: name.to_string() }).await.unwrap();
+            let reply = replies.message().await.unwrap().unwrap();
+            assert_eq!(reply.message, format!("Hello {}! from server 1", name));
+        }
+
+        let metrics = client.get_metrics(tonic::Request::new(Empty {})).await.unwrap().into_inner();
+        assert_eq!(metrics.rif, 1);
+
+        drop(tx);
+        assert!(replies.message().await.unwrap().is_none());
+        sleep(Duration::from_millis(50)).await;
+        let metrics = client.get_metrics(tonic::Request::new(Empty {})).await.unwrap().into_inner();
+        assert_eq!(metrics.rif, 0);
+
+        server_handle.abort();
+    }
+
+    // Test the load signals reported next to rif and latency
+    #[tokio::test]
+    async fn test_get_metrics_load_signals() {
+        let greeter = MyGreeter {
+            server_id: "server 1".to_string(),
+            start_epoch_ms: Arc::new(AtomicU64::new(1_700_000_000_000)),
+            max_concurrency: 8,
+            ..Default::default()
+        };
+        let (addr, server_handle) = spawn_greeter(greeter).await;
+        let mut client = create_client(&addr).await;
+
+        let request = tonic::Request::new(HelloRequest {
+            name: "world".to_string(),
+        });
+        client.say_hello(request).await.unwrap();
+
+        let metrics = client.get_metrics(tonic::Request::new(Empty {})).await.unwrap().into_inner();
+        assert_eq!(metrics.server_id, "server 1");
+        assert_eq!(metrics.start_epoch_ms, 1_700_000_000_000);
+        assert!(metrics.timestamp_ms > metrics.start_epoch_ms);
+        assert_eq!(metrics.max_concurrency, 8);
+        assert!(!metrics.draining);
+        assert!((0.0..=1.0).contains(&metrics.cpu_utilization));
+        assert_eq!(metrics.method_latency.len(), 1);
+        assert!(metrics.method_latency["SayHello"] > 0);
+
+        server_handle.abort();
+    }
+
+    // Test the load report attached to the response trailers
+    #[tokio::test]
+    async fn test_load_report_trailers() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = format!("http://{}", listener.local_addr().unwrap());
+        let greeter = greeter();
+        let load_reporter = LoadReporter::new(true, greeter.rif.clone(), greeter.latencies.clone());
+        let server_handle = tokio::spawn(
+            Server::builder()
+                .layer(MapResponseLayer::new(move |response| load_reporter.attach(response)))
+                .add_service(GreeterServer::new(greeter))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+        let mut client = create_client(&addr).await;
+
+        let request = tonic::Request::new(HelloRequest {
+            name: "world".to_string(),
+        });
+        let response = client.say_hello(request).await.unwrap();
+        // Unary responses merge the trailers into the response metadata
+        assert_eq!(response.metadata().get(utils::loadreport::RIF_TRAILER).unwrap(), "0");
+        let latency = response.metadata().get(utils::loadreport::LATENCY_TRAILER).unwrap();
+        assert!(latency.to_str().unwrap().parse::<u64>().unwrap() > 0);
+
+        server_handle.abort();
+    }
+
+    // Faults set through the control RPC apply to the requests and probes, a crash restarts the backend
+    #[tokio::test]
+    async fn test_fault_injection_through_the_control_rpc() {
+        use sim_control::sim_control_client::SimControlClient;
+        use sim_control::{CrashRequest, Empty as ControlEmpty};
+
+        let args = Args::parse_from(["backend-sim", "--latency", "fixed:1", "--error-rate", "14=1"]);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = format!("http://{}", listener.local_addr().unwrap());
+        let server_handle = tokio::spawn(serve(args, listener));
+        let mut client = create_client(&addr).await;
+        let mut control = SimControlClient::connect(addr.clone()).await.unwrap();
+
+        let request = || tonic::Request::new(HelloRequest { name: "world".to_string() });
+        assert_eq!(client.say_hello(request()).await.unwrap_err().code(), tonic::Code::Unavailable);
+        let faults = control.get_faults(ControlEmpty {}).await.unwrap().into_inner();
+        assert_eq!(faults.error_rates[&14], 1.0);
+
+        let invalid = Faults { spike_rate: 2.0, ..Default::default() };
+        assert_eq!(control.set_faults(invalid).await.unwrap_err().code(), tonic::Code::InvalidArgument);
+        let faults = Faults { report_zero_rif: true, ..Default::default() };
+        control.set_faults(faults).await.unwrap();
+        client.say_hello(request()).await.unwrap();
+        let metrics = client.get_metrics(tonic::Request::new(Empty {})).await.unwrap().into_inner();
+        assert_eq!(metrics.rif, 0);
+        let started = metrics.start_epoch_ms;
+
+        control.crash(CrashRequest { down_ms: 100 }).await.unwrap();
+        let status = client.get_metrics(tonic::Request::new(Empty {})).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+        sleep(Duration::from_millis(150)).await;
+        let metrics = client.get_metrics(tonic::Request::new(Empty {})).await.unwrap().into_inner();
+        assert!(metrics.start_epoch_ms > started);
+        assert_eq!(metrics.latency, 0);
+
+        server_handle.abort();
+    }
+}
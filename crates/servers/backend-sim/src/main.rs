@@ -0,0 +1,13 @@
+use backend_sim::Args;
+use clap::Parser;
+use tokio::net::TcpListener;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let args = Args::parse();
+    let subscriber = tracing_subscriber::FmtSubscriber::new();
+    tracing::subscriber::set_global_default(subscriber)?;
+
+    let listener = TcpListener::bind(args.listen_addr).await?;
+    backend_sim::serve(args, listener).await
+}
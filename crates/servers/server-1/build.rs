@@ -1,4 +0,0 @@
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tonic_build::compile_protos("../../../proto/helloworld.proto")?;
-    Ok(())
-}
\ No newline at end of file
@@ -0,0 +1,220 @@
+use backend_sim::capacity::CapacityModel;
+use backend_sim::latency::LatencyModel;
+use prequal::hello_world::Metric;
+use rand::rngs::StdRng;
+use std::collections::VecDeque;
+use std::time::Duration;
+use utils::latencies::LatencyTracker;
+
+/// Work left below this many nanoseconds is done, it absorbs the rounding of the virtual clock
+const DONE_NANOS: f64 = 0.5;
+
+/**
+A backend-sim replica in virtual time, with the same latency and capacity models.
+Every request brings work drawn from the latency model, the capacity model decides how fast it progresses:
+all at once when unlimited, the first `n` in arrival order with `workers:n`,
+and all at `min(1, cores / rif)` with `ps:cores`.
+*/
+#[derive(Debug)]
+pub struct Backend {
+    pub id: String,
+    pub speed: f64,
+    latency: LatencyModel,
+    capacity: CapacityModel,
+    max_concurrency: u32,
+    rng: StdRng,
+    /// Accepted requests in arrival order, the ones in service come first
+    jobs: VecDeque<Job>,
+    /// When the work left of the jobs was last brought up to date
+    updated_at: Duration,
+    latencies: LatencyTracker,
+    /// Bumped whenever the next completion may have moved, completions scheduled before are stale
+    pub generation: u64,
+}
+
+#[derive(Debug)]
+struct Job {
+    request: u64,
+    arrived_at: Duration,
+    /// Nanoseconds of service left at full rate
+    remaining: f64,
+}
+
+impl Backend {
+    pub fn new(args: &backend_sim::Args, rng: StdRng) -> Self {
+        let concurrency = match args.capacity {
+            CapacityModel::Unlimited => 0,
+            CapacityModel::Workers(count) | CapacityModel::ProcessorSharing(count) => count,
+        };
+        Self {
+            id: args.id.clone(),
+            speed: args.speed,
+            latency: args.latency,
+            capacity: args.capacity,
+            max_concurrency: match args.max_concurrency {
+                0 => concurrency,
+                max_concurrency => max_concurrency,
+            },
+            rng,
+            jobs: VecDeque::new(),
+            updated_at: Duration::ZERO,
+            latencies: LatencyTracker::default(),
+            generation: 0,
+        }
+    }
+    pub fn rif(&self) -> u32 {
+        self.jobs.len() as u32
+    }
+    /**
+    Jobs being served, the others wait for a worker
+    */
+    fn serving(&self) -> usize {
+        match self.capacity {
+            CapacityModel::Workers(workers) => self.jobs.len().min(workers as usize),
+            CapacityModel::Unlimited | CapacityModel::ProcessorSharing(_) => self.jobs.len(),
+        }
+    }
+    /**
+    Share of a full rate every job in service progresses at
+    */
+    fn rate(&self) -> f64 {
+        match self.capacity {
+            CapacityModel::ProcessorSharing(cores) => (cores as f64 / self.jobs.len().max(1) as f64).min(1.0),
+            CapacityModel::Unlimited | CapacityModel::Workers(_) => 1.0,
+        }
+    }
+    fn advance(&mut self, now: Duration) {
+        let progress = now.saturating_sub(self.updated_at).as_nanos() as f64 * self.rate();
+        let serving = self.serving();
+        for job in self.jobs.iter_mut().take(serving) {
+            job.remaining -= progress;
+        }
+        self.updated_at = now;
+    }
+    /**
+    Accepts a request, its work is drawn from the latency model and scaled by the speed of the replica
+    */
+    pub fn admit(&mut self, now: Duration, request: u64) {
+        self.advance(now);
+        let work = self.latency.sample(&mut self.rng).div_f64(self.speed);
+        self.jobs.push_back(Job {
+            request,
+            arrived_at: now,
+            remaining: work.as_nanos() as f64,
+        });
+        self.generation += 1;
+    }
+    /**
+    Takes the requests whose work is done by now, queued requests move up to the freed workers
+    */
+    pub fn complete(&mut self, now: Duration) -> Vec<u64> {
+        self.advance(now);
+        let serving = self.serving();
+        let mut done = vec![];
+        let mut index = 0;
+        for _ in 0..serving {
+            if self.jobs[index].remaining < DONE_NANOS {
+                let job = self.jobs.remove(index).expect("the job is in service");
+                self.latencies
+                    .add_latency("SayHello", now.saturating_sub(job.arrived_at).as_nanos());
+                done.push(job.request);
+            } else {
+                index += 1;
+            }
+        }
+        self.generation += 1;
+        done
+    }
+    /**
+    When the next request in service is done unless other requests arrive meanwhile
+    */
+    pub fn next_completion(&self) -> Option<Duration> {
+        let remaining = self
+            .jobs
+            .iter()
+            .take(self.serving())
+            .map(|job| job.remaining.max(0.0))
+            .min_by(f64::total_cmp)?;
+        Some(self.updated_at + Duration::from_nanos((remaining / self.rate()).ceil() as u64))
+    }
+    /**
+    The answer of backend-sim to GetMetrics at this time
+    */
+    pub fn metric(&self, now: Duration) -> Metric {
+        Metric {
+            server_id: self.id.clone(),
+            rif: self.rif(),
+            latency: self.latencies.find_median().unwrap_or_default() as u64,
+            timestamp_ms: now.as_millis() as u64,
+            queue_length: (self.jobs.len() - self.serving()) as u32,
+            max_concurrency: self.max_concurrency,
+            method_latency: self
+                .latencies
+                .method_medians()
+                .into_iter()
+                .map(|(method, latency)| (method, latency as u64))
+                .collect(),
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use rand::SeedableRng;
+
+    fn backend(flags: &[&str]) -> Backend {
+        let args = backend_sim::Args::parse_from(["backend-sim", "--latency", "fixed:10"].iter().chain(flags));
+        Backend::new(&args, StdRng::seed_from_u64(1))
+    }
+
+    /// Completes the requests of the backend one completion after another
+    fn drain(backend: &mut Backend) -> Vec<(u64, Duration)> {
+        let mut done = vec![];
+        while let Some(at) = backend.next_completion() {
+            done.extend(backend.complete(at).into_iter().map(|request| (request, at)));
+        }
+        done
+    }
+
+    #[test]
+    fn test_unlimited_requests_do_not_wait() {
+        let mut backend = backend(&["--speed", "0.5"]);
+        backend.admit(Duration::ZERO, 1);
+        backend.admit(Duration::from_millis(5), 2);
+        assert_eq!(
+            drain(&mut backend),
+            [(1, Duration::from_millis(20)), (2, Duration::from_millis(25))]
+        );
+    }
+
+    #[test]
+    fn test_workers_queue_the_requests() {
+        let mut backend = backend(&["--capacity", "workers:1"]);
+        backend.admit(Duration::ZERO, 1);
+        backend.admit(Duration::ZERO, 2);
+        let metric = backend.metric(Duration::ZERO);
+        assert_eq!((metric.rif, metric.queue_length, metric.max_concurrency), (2, 1, 1));
+        assert_eq!(
+            drain(&mut backend),
+            [(1, Duration::from_millis(10)), (2, Duration::from_millis(20))]
+        );
+        // Median of 10ms and 20ms in nanoseconds
+        assert_eq!(backend.metric(Duration::from_millis(20)).latency, 15_000_000);
+    }
+
+    #[test]
+    fn test_processor_sharing_slows_every_request_down() {
+        let mut backend = backend(&["--capacity", "ps:1"]);
+        backend.admit(Duration::ZERO, 1);
+        backend.admit(Duration::from_millis(5), 2);
+        // 5ms alone, 10ms at half rate each, then the last 5ms of the second one alone
+        assert_eq!(
+            drain(&mut backend),
+            [(1, Duration::from_millis(15)), (2, Duration::from_millis(20))]
+        );
+        assert_eq!(backend.rif(), 0);
+    }
+}
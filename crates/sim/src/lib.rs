@@ -0,0 +1,525 @@
+/*!
+Deterministic discrete-event simulation of the load balancer policies over a virtual clock.
+
+A bench scenario runs here without sockets or sleeping: backends follow the latency and capacity
+models of backend-sim, the workload and antagonists follow the arrivals of loadgen, and Prequal
+selects from a [`ProbePool`] filled by the probing rounds of the load balancer.
+The rounds are timed by a [`ProbeScheduler`] with the default probe rate settings of the load balancer,
+so their delays are jittered the same way and adapt to the usage of the probes in virtual time.
+Every random draw comes from the scenario seed, the same seed gives the same results.
+*/
+pub mod backend;
+
+use backend::Backend;
+use bench::{BackendRun, Comparison, PolicyRun, RifStats, Scenario, RIF_SAMPLE_INTERVAL};
+use hdrhistogram::Histogram;
+use load_balancer::{Config, Policy, TuningUpdate};
+use loadgen::profile::Profile;
+use loadgen::report::Recorder;
+use loadgen::Arrivals;
+use prequal::hello_world::Metric;
+use prequal::prober::{ProbeRateConfig, ProbeScheduler, ProbeUsage};
+use prequal::{prober, ProbePool};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::sync::atomic::Ordering::Acquire;
+use std::time::Duration;
+use thiserror::Error;
+use tonic::Code;
+
+#[derive(Error, Debug)]
+pub enum SimError {
+    #[error("Invalid scenario `{0}`")]
+    InvalidScenario(String),
+    #[error("Invalid workload `{0}`")]
+    Workload(#[from] loadgen::LoadgenError),
+}
+
+/**
+What the simulation models on top of the scenario, the network and the client
+*/
+#[derive(Debug, Clone)]
+pub struct SimConfig {
+    /// One way delay between the client, the load balancer and the backends
+    pub network_delay: Duration,
+    /// Requests taking longer fail with DeadlineExceeded, as with `--timeout` of loadgen
+    pub timeout: Duration,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self {
+            network_delay: Duration::from_micros(100),
+            timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+/**
+Sends requests, the workload through the load balancer or an antagonist straight to its backend
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+enum Source {
+    Workload,
+    Antagonist(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Event {
+    /// The next request of the source is sent
+    Arrival(Source),
+    /// A request reaches its backend
+    Deliver { backend: usize, request: u64 },
+    /// The next request in service on the backend is done, unless the generation moved on
+    Completion { backend: usize, generation: u64 },
+    /// The reply of a request reaches its client
+    Reply { request: u64 },
+    Timeout { request: u64 },
+    ProbeRound,
+    /// A probe reaches its backend, which answers with its current load
+    Probe { backend: usize },
+    /// The answer of a probe reaches the load balancer
+    ProbeReply { probe: u64 },
+    SampleRif,
+}
+
+#[derive(Debug)]
+struct Request {
+    source: Source,
+    backend: usize,
+    sent_at: Duration,
+    /// Sent after the warmup
+    measured: bool,
+}
+
+impl Source {
+    /**
+    Network hops between the source and a backend
+    */
+    fn hops(&self) -> u32 {
+        match self {
+            Source::Workload => 2,
+            Source::Antagonist(_) => 1,
+        }
+    }
+}
+
+/**
+The probing settings of the load balancer, its defaults where the scenario leaves them out
+*/
+fn tuning(scenario: &Scenario) -> (f32, Duration, usize) {
+    let defaults = TuningUpdate::from_config(&Config::default());
+    (
+        scenario.q_rif.or(defaults.q_rif).unwrap_or_default(),
+        scenario
+            .probe_interval
+            .or(defaults.probe_interval_ms.map(Duration::from_millis))
+            .unwrap_or_default(),
+        scenario.probe_pool_size.or(defaults.probe_pool_size).unwrap_or_default(),
+    )
+}
+
+struct Simulation<'a> {
+    now: Duration,
+    /// Orders the events at the same time by when they were scheduled
+    seq: u64,
+    events: BinaryHeap<Reverse<(Duration, u64, Event)>>,
+    backends: Vec<Backend>,
+    policy: Policy,
+    pool: ProbePool,
+    scheduler: ProbeScheduler,
+    /// How the workload used the probes since the last round
+    probe_usage: ProbeUsage,
+    last_round: Duration,
+    /// When the probe of every backend in the pool was received
+    probe_received: HashMap<usize, Duration>,
+    probe_pool_size: usize,
+    /// Position of the round robin policy
+    next_backend: usize,
+    policy_rng: StdRng,
+    probe_rng: StdRng,
+    config: SimConfig,
+    workload: Box<dyn Iterator<Item = Duration> + 'a>,
+    antagonists: Vec<(usize, Arrivals)>,
+    concurrency: u32,
+    /// Requests in flight of the workload, then of every antagonist
+    in_flight: HashMap<Source, u32>,
+    requests: HashMap<u64, Request>,
+    next_request: u64,
+    /// Answers of the probes on their way back
+    probes: HashMap<u64, (usize, Metric)>,
+    next_probe: u64,
+    recorder: Recorder,
+    rif_samples: Vec<Histogram<u64>>,
+    measured_from: Duration,
+    end: Duration,
+}
+
+impl Simulation<'_> {
+    fn schedule(&mut self, at: Duration, event: Event) {
+        self.seq += 1;
+        self.events.push(Reverse((at, self.seq, event)));
+    }
+    fn schedule_completion(&mut self, backend: usize) {
+        if let Some(at) = self.backends[backend].next_completion() {
+            let generation = self.backends[backend].generation;
+            self.schedule(at, Event::Completion { backend, generation });
+        }
+    }
+    fn schedule_arrival(&mut self, source: Source) {
+        let next = match source {
+            Source::Workload => self.workload.next(),
+            Source::Antagonist(index) => self.antagonists[index].1.next(),
+        };
+        if let Some(at) = next.filter(|at| *at < self.end) {
+            self.schedule(at, Event::Arrival(source));
+        }
+    }
+    fn run(&mut self) {
+        self.schedule_arrival(Source::Workload);
+        for index in 0..self.antagonists.len() {
+            self.schedule_arrival(Source::Antagonist(index));
+        }
+        self.schedule(Duration::ZERO, Event::ProbeRound);
+        self.schedule(self.measured_from, Event::SampleRif);
+        while let Some(Reverse((at, _, event))) = self.events.pop() {
+            self.now = at;
+            self.handle(event);
+        }
+    }
+    fn handle(&mut self, event: Event) {
+        let delay = self.config.network_delay;
+        match event {
+            Event::Arrival(source) => {
+                self.schedule_arrival(source);
+                self.send(source);
+            }
+            Event::Deliver { backend, request } => {
+                self.backends[backend].admit(self.now, request);
+                self.schedule_completion(backend);
+            }
+            Event::Completion { backend, generation } => {
+                if generation != self.backends[backend].generation {
+                    return;
+                }
+                for request in self.backends[backend].complete(self.now) {
+                    // Requests which timed out are answered to nobody
+                    if let Some(hops) = self.requests.get(&request).map(|request| request.source.hops()) {
+                        self.schedule(self.now + delay * hops, Event::Reply { request });
+                    }
+                }
+                self.schedule_completion(backend);
+            }
+            Event::Reply { request } => {
+                if let Some(request) = self.finish(request) {
+                    let id = &self.backends[request.backend].id;
+                    if request.measured && request.source == Source::Workload {
+                        self.recorder.record(self.now - request.sent_at, Ok(id));
+                    }
+                }
+            }
+            Event::Timeout { request } => {
+                if let Some(request) = self.finish(request) {
+                    if request.measured && request.source == Source::Workload {
+                        self.recorder.record(self.now - request.sent_at, Err(Code::DeadlineExceeded));
+                    }
+                }
+            }
+            Event::ProbeRound => {
+                // As `background_process` runs the rounds of the load balancer
+                self.scheduler.adapt(&std::mem::take(&mut self.probe_usage), self.now - self.last_round);
+                self.last_round = self.now;
+                let allowed = self.scheduler.allow(self.probe_pool_size);
+                let candidates = (0..self.backends.len()).collect::<Vec<usize>>();
+                for backend in prober::choose_targets_with(&mut self.probe_rng, &candidates, allowed) {
+                    self.schedule(self.now + delay, Event::Probe { backend });
+                }
+                let next_round = self.now + self.scheduler.next_delay(&mut self.probe_rng);
+                if next_round < self.end {
+                    self.schedule(next_round, Event::ProbeRound);
+                }
+            }
+            Event::Probe { backend } => {
+                let probe = self.next_probe;
+                self.next_probe += 1;
+                self.probes.insert(probe, (backend, self.backends[backend].metric(self.now)));
+                self.schedule(self.now + delay, Event::ProbeReply { probe });
+            }
+            Event::ProbeReply { probe } => {
+                if let Some((backend, metric)) = self.probes.remove(&probe) {
+                    self.pool.record(&self.backends[backend].id, &metric);
+                    self.probe_received.insert(backend, self.now);
+                }
+            }
+            Event::SampleRif => {
+                for (samples, backend) in self.rif_samples.iter_mut().zip(&self.backends) {
+                    let _ = samples.record(backend.rif() as u64);
+                }
+                if self.now + RIF_SAMPLE_INTERVAL < self.end {
+                    self.schedule(self.now + RIF_SAMPLE_INTERVAL, Event::SampleRif);
+                }
+            }
+        }
+    }
+    /**
+    Sends a request of the source unless too many are in flight, the workload goes through the policy
+    */
+    fn send(&mut self, source: Source) {
+        let measured = self.now >= self.measured_from;
+        let in_flight = self.in_flight.entry(source).or_default();
+        if *in_flight >= self.concurrency {
+            if measured && source == Source::Workload {
+                self.recorder.record_dropped();
+            }
+            return;
+        }
+        let backend = match source {
+            Source::Workload => self.select(),
+            Source::Antagonist(index) => Some(self.antagonists[index].0),
+        };
+        let Some(backend) = backend else {
+            // The load balancer answers right away when it has no server to pick
+            if measured {
+                self.recorder.record(self.config.network_delay * 2, Err(Code::Internal));
+            }
+            return;
+        };
+        *self.in_flight.entry(source).or_default() += 1;
+        let request = self.next_request;
+        self.next_request += 1;
+        self.requests.insert(
+            request,
+            Request {
+                source,
+                backend,
+                sent_at: self.now,
+                measured,
+            },
+        );
+        self.schedule(self.now + self.config.network_delay * source.hops(), Event::Deliver { backend, request });
+        self.schedule(self.now + self.config.timeout, Event::Timeout { request });
+    }
+    /**
+    Picks the backend of a workload request, as `LoadBalancer::get_server` does with the policy.
+    Prequal bootstraps with round robin until the first probe arrives, the default bootstrap policy.
+    */
+    fn select(&mut self) -> Option<usize> {
+        self.probe_usage.requests += 1;
+        match self.policy {
+            Policy::Prequal => match self.pool.select(|_| true) {
+                Some(probe) => {
+                    let times_used = probe.times_used.fetch_add(1, Acquire) + 1;
+                    let backend = self.backends.iter().position(|backend| backend.id == probe.server)?;
+                    let age = self.probe_received.get(&backend).map(|received| self.now - *received);
+                    self.probe_usage.record_selection(age.unwrap_or_default(), times_used);
+                    Some(backend)
+                }
+                None => self.round_robin(),
+            },
+            Policy::RoundRobin => self.round_robin(),
+            Policy::Random => Some(self.policy_rng.gen_range(0..self.backends.len())),
+        }
+    }
+    fn round_robin(&mut self) -> Option<usize> {
+        self.next_backend = self.next_backend.wrapping_add(1);
+        Some(self.next_backend % self.backends.len())
+    }
+    /**
+    Takes the request out of flight, None when it was answered or timed out already
+    */
+    fn finish(&mut self, request: u64) -> Option<Request> {
+        let request = self.requests.remove(&request)?;
+        if let Some(in_flight) = self.in_flight.get_mut(&request.source) {
+            *in_flight -= 1;
+        }
+        Some(request)
+    }
+}
+
+/**
+Simulates the scenario once per policy, every policy sees the same arrivals
+*/
+pub fn run(scenario: &Scenario, config: &SimConfig) -> Result<Comparison, SimError> {
+    let runs = scenario
+        .policies
+        .iter()
+        .map(|policy| run_policy(scenario, *policy, config))
+        .collect::<Result<Vec<PolicyRun>, SimError>>()?;
+    Ok(Comparison {
+        seed: scenario.seed,
+        runs,
+    })
+}
+
+/**
+Simulates the workload of the scenario through a load balancer with the policy
+*/
+pub fn run_policy(scenario: &Scenario, policy: Policy, config: &SimConfig) -> Result<PolicyRun, SimError> {
+    let profile = match &scenario.profile {
+        Some(path) => Profile::from_file(path)?,
+        None => Profile::constant(scenario.qps, scenario.warmup + scenario.duration),
+    };
+    if profile.duration() <= scenario.warmup {
+        return Err(SimError::InvalidScenario(format!(
+            "the profile lasts {:?}, not longer than the warmup",
+            profile.duration()
+        )));
+    }
+    let (duration, target_qps) = (profile.duration() - scenario.warmup, profile.mean_rate(scenario.warmup));
+    let backend_args = scenario.backend_args().map_err(SimError::InvalidScenario)?;
+    let (q_rif, probe_interval, probe_pool_size) = tuning(scenario);
+
+    // Draws every generator from the seed in a fixed order, so they do not depend on the policy
+    let mut seeds = StdRng::seed_from_u64(scenario.seed);
+    let backends = backend_args
+        .iter()
+        .map(|args| Backend::new(args, StdRng::seed_from_u64(seeds.gen())))
+        .collect::<Vec<Backend>>();
+    let (policy_rng, probe_rng) = (StdRng::seed_from_u64(seeds.gen()), StdRng::seed_from_u64(seeds.gen()));
+    // The workload and the antagonists arrive as they do in the bench
+    let antagonists = scenario
+        .antagonists
+        .iter()
+        .enumerate()
+        .map(|(index, antagonist)| {
+            let seed = scenario.seed.wrapping_add(1 + index as u64);
+            (antagonist.backend, Arrivals::new(antagonist.qps, Some(seed)))
+        })
+        .collect();
+
+    let mut simulation = Simulation {
+        now: Duration::ZERO,
+        seq: 0,
+        events: BinaryHeap::new(),
+        rif_samples: backends
+            .iter()
+            .map(|_| Histogram::new(3).expect("3 significant figures are supported"))
+            .collect(),
+        backends,
+        policy,
+        pool: ProbePool::new(q_rif),
+        scheduler: ProbeScheduler::new(ProbeRateConfig::default(), probe_interval),
+        probe_usage: ProbeUsage::default(),
+        last_round: Duration::ZERO,
+        probe_received: HashMap::new(),
+        probe_pool_size,
+        next_backend: 0,
+        policy_rng,
+        probe_rng,
+        config: config.clone(),
+        workload: Box::new(profile.arrivals(Some(scenario.seed))),
+        antagonists,
+        concurrency: scenario.concurrency,
+        in_flight: HashMap::new(),
+        requests: HashMap::new(),
+        next_request: 0,
+        probes: HashMap::new(),
+        next_probe: 0,
+        recorder: Recorder::default(),
+        measured_from: scenario.warmup,
+        end: profile.duration(),
+    };
+    simulation.run();
+
+    let report = simulation
+        .recorder
+        .report(&bench::policy_name(policy), target_qps, duration);
+    let backends = simulation
+        .backends
+        .iter()
+        .zip(&simulation.rif_samples)
+        .enumerate()
+        .map(|(index, (backend, samples))| BackendRun {
+            id: backend.id.clone(),
+            speed: backend.speed,
+            antagonist_qps: scenario.antagonist_qps(index),
+            requests: report.backends.get(&backend.id).copied().unwrap_or_default(),
+            rif: RifStats::from_samples(samples),
+        })
+        .collect();
+    Ok(PolicyRun { policy, report, backends })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn scenario(contents: &str) -> Scenario {
+        Scenario::parse(contents, Path::new(".")).unwrap()
+    }
+
+    const SLOW_REPLICA: &str = r#"
+        policies = ["prequal", "round_robin"]
+        warmup = "5s"
+        duration = "60s"
+        qps = 900
+
+        [[backends]]
+        count = 3
+        latency = "exp:5"
+        capacity = "workers:4"
+
+        [[backends]]
+        latency = "exp:5"
+        capacity = "workers:4"
+        speed = 0.25
+    "#;
+
+    #[test]
+    fn test_same_seed_gives_the_same_results() {
+        let scenario = scenario(SLOW_REPLICA);
+        let config = SimConfig::default();
+        let first = serde_json::to_string(&run(&scenario, &config).unwrap()).unwrap();
+        assert_eq!(first, serde_json::to_string(&run(&scenario, &config).unwrap()).unwrap());
+
+        let mut reseeded = scenario.clone();
+        reseeded.seed += 1;
+        assert_ne!(first, serde_json::to_string(&run(&reseeded, &config).unwrap()).unwrap());
+    }
+
+    #[test]
+    fn test_prequal_avoids_the_slow_replica() {
+        let comparison = run(&scenario(SLOW_REPLICA), &SimConfig::default()).unwrap();
+        let (prequal, round_robin) = (&comparison.runs[0], &comparison.runs[1]);
+        // About 54,000 requests after the warmup
+        assert!((52_000..56_000).contains(&prequal.report.requests), "{comparison}");
+        // Round robin sends a quarter of the load to a replica which serves about 200 qps
+        assert!(round_robin.report.failed() > 0, "{comparison}");
+        assert!(prequal.backends[3].requests < round_robin.backends[3].requests / 2, "{comparison}");
+        assert!(prequal.report.latency_ms.p99 < round_robin.report.latency_ms.p99, "{comparison}");
+    }
+
+    #[test]
+    fn test_antagonists_load_their_backend() {
+        let comparison = run(
+            &scenario(
+                r#"
+                policies = ["random"]
+                warmup = "1s"
+                duration = "20s"
+                qps = 100
+
+                [[backends]]
+                count = 2
+                latency = "fixed:10"
+
+                [[antagonists]]
+                backend = 1
+                qps = 400
+                "#,
+            ),
+            &SimConfig::default(),
+        )
+        .unwrap();
+        let backends = &comparison.runs[0].backends;
+        // Little's law, 10ms of work at 50 and 450 qps
+        assert!((0.3..0.7).contains(&backends[0].rif.mean), "{comparison}");
+        assert!((4.0..5.0).contains(&backends[1].rif.mean), "{comparison}");
+        assert_eq!(comparison.runs[0].report.failed(), 0);
+        // The replies take 10ms of work and 4 hops of 100us
+        assert!((10.3..10.5).contains(&comparison.runs[0].report.latency_ms.p50), "{comparison}");
+    }
+}
@@ -0,0 +1,74 @@
+use bench::{Scenario, DEFAULT_SCENARIO};
+use clap::Parser;
+use sim::SimConfig;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/**
+Compares the policies of the load balancer in virtual time, a deterministic and fast take on the bench
+*/
+#[derive(Parser, Debug)]
+#[command(name = "sim", version)]
+struct Cli {
+    /// Bench scenario file, the scenario.example.toml of the bench when not given
+    #[arg(long)]
+    scenario: Option<PathBuf>,
+    /// Overrides the seed of the scenario
+    #[arg(long)]
+    seed: Option<u64>,
+    /// Overrides the probing settings of the scenario
+    #[arg(long)]
+    q_rif: Option<f32>,
+    #[arg(long, value_parser = humantime::parse_duration)]
+    probe_interval: Option<Duration>,
+    #[arg(long)]
+    probe_pool_size: Option<usize>,
+    /// One way delay between the client, the load balancer and the backends
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "100us")]
+    network_delay: Duration,
+    /// Requests taking longer fail with DeadlineExceeded
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "1s")]
+    timeout: Duration,
+    /// Writes the comparison as JSON
+    #[arg(long)]
+    json: Option<PathBuf>,
+    /// Appends the workload results of every policy as CSV rows
+    #[arg(long)]
+    csv: Option<PathBuf>,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let cli = Cli::parse();
+    let subscriber = tracing_subscriber::FmtSubscriber::new();
+    tracing::subscriber::set_global_default(subscriber)?;
+
+    let mut scenario = match &cli.scenario {
+        Some(path) => Scenario::from_file(path)?,
+        None => Scenario::parse(DEFAULT_SCENARIO, &Path::new(env!("CARGO_MANIFEST_DIR")).join("../bench"))?,
+    };
+    scenario.seed = cli.seed.unwrap_or(scenario.seed);
+    scenario.q_rif = cli.q_rif.or(scenario.q_rif);
+    scenario.probe_interval = cli.probe_interval.or(scenario.probe_interval);
+    scenario.probe_pool_size = cli.probe_pool_size.or(scenario.probe_pool_size);
+    let config = SimConfig {
+        network_delay: cli.network_delay,
+        timeout: cli.timeout,
+    };
+
+    let start = Instant::now();
+    let comparison = sim::run(&scenario, &config)?;
+    tracing::info!(
+        "Simulated {} policies for {:?} each in {:?}",
+        scenario.policies.len(),
+        scenario.warmup + scenario.duration,
+        start.elapsed()
+    );
+    print!("{comparison}");
+    if let Some(path) = &cli.json {
+        comparison.write_json(path)?;
+    }
+    if let Some(path) = &cli.csv {
+        comparison.append_csv(path)?;
+    }
+    Ok(())
+}
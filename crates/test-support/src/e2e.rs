@@ -0,0 +1,365 @@
+//! End-to-end tests of the load balancer in front of backend-sim backends, all over real connections
+
+use crate::{wait_until, TestBackend, TestCluster, TestLoadBalancer};
+use backend_sim::hello_world::greeter_client::GreeterClient;
+use backend_sim::hello_world::{Empty, HelloRequest};
+use load_balancer::routing::DEFAULT_CLUSTER;
+use load_balancer::{LoadBalancerBuilder, LoadBalancerError, Policy};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio_stream::wrappers::ReceiverStream;
+use utils::loadreport::RIF_TRAILER;
+
+fn builder(policy: Policy) -> LoadBalancerBuilder {
+    LoadBalancerBuilder::new()
+        .policy(policy)
+        .probe_interval(Duration::from_millis(20))
+        .grace_period(Duration::from_secs(1))
+}
+
+/// Sends the requests one after the other and counts the backends which served them
+async fn served_by(cluster: &TestCluster, requests: usize) -> HashMap<String, usize> {
+    let mut served = HashMap::new();
+    for i in 0..requests {
+        let backend = cluster.say_hello(&format!("request {i}")).await.unwrap();
+        *served.entry(backend).or_insert(0) += 1;
+    }
+    served
+}
+
+// Once both are probed, every request goes to the cold backend with the lower latency
+#[tokio::test]
+async fn test_prequal_prefers_the_faster_backend() {
+    let cluster = TestCluster::spawn(
+        &[&["--latency", "fixed:1"], &["--latency", "fixed:40"]],
+        builder(Policy::Prequal).probe_pool_size(2),
+    )
+    .await
+    .unwrap();
+    // Gives both backends a latency to report
+    let (fast, slow) = (&cluster.backends[0], &cluster.backends[1]);
+    fast.client().await.unwrap().say_hello(HelloRequest::default()).await.unwrap();
+    slow.client().await.unwrap().say_hello(HelloRequest::default()).await.unwrap();
+    // As if 4 requests were once seen in flight, so the fast backend stays cold while it serves a request
+    let cluster_state = cluster.load_balancer.cluster();
+    cluster_state.lock().await.probe_pool.max_rif = 4;
+    let urls = cluster.urls();
+    wait_until("probes of the idle backends with latencies", || {
+        let cluster_state = cluster_state.clone();
+        let urls = urls.clone();
+        async move {
+            let load_balancer = cluster_state.lock().await;
+            urls.iter().all(|url| {
+                load_balancer
+                    .probe_pool
+                    .get(url)
+                    .is_some_and(|probe| probe.rif == 0 && probe.latency > 0)
+            })
+        }
+    })
+    .await
+    .unwrap();
+
+    assert_eq!(served_by(&cluster, 10).await, HashMap::from([("backend-0".to_string(), 10)]));
+    let load_balancer = cluster_state.lock().await;
+    assert_eq!(load_balancer.decisions.len(), 10);
+    assert!(load_balancer.decisions.iter().all(|decision| decision.server == urls[0]));
+}
+
+#[tokio::test]
+async fn test_round_robin_spreads_the_requests_evenly() {
+    let cluster = TestCluster::spawn(
+        &[&["--latency", "fixed:1"], &["--latency", "fixed:1"], &["--latency", "fixed:1"]],
+        builder(Policy::RoundRobin),
+    )
+    .await
+    .unwrap();
+    let served = served_by(&cluster, 30).await;
+    assert_eq!(served.len(), 3, "{served:?}");
+    assert!(served.values().all(|count| *count == 10), "{served:?}");
+}
+
+// A crashed backend fails its probe, it is taken out and the others serve every request
+#[tokio::test]
+async fn test_failover_when_a_backend_crashes() {
+    let cluster = TestCluster::spawn(
+        &[&["--latency", "fixed:1"], &["--latency", "fixed:1"], &["--latency", "fixed:1"]],
+        builder(Policy::Prequal).probe_pool_size(3),
+    )
+    .await
+    .unwrap();
+    cluster.load_balancer.wait_for_probes(&cluster.urls()).await.unwrap();
+
+    let crashed = &cluster.backends[0];
+    crashed.crash(Duration::from_secs(60)).await.unwrap();
+    cluster.load_balancer.wait_for_unavailable(crashed.url()).await.unwrap();
+    assert!(cluster.load_balancer.cluster().lock().await.probe_pool.get(crashed.url()).is_none());
+
+    let served = served_by(&cluster, 20).await;
+    assert_eq!(served.values().sum::<usize>(), 20);
+    assert!(!served.contains_key(crashed.id()), "{served:?}");
+}
+
+// A backend stopping for good fails its requests until the next probe notices
+#[tokio::test]
+async fn test_failover_when_a_backend_goes_away() {
+    let cluster = TestCluster::spawn(
+        &[&["--latency", "fixed:1"], &["--latency", "fixed:1"]],
+        builder(Policy::Prequal).probe_pool_size(2),
+    )
+    .await
+    .unwrap();
+    cluster.load_balancer.wait_for_probes(&cluster.urls()).await.unwrap();
+
+    cluster.backends[1].stop();
+    cluster.load_balancer.wait_for_unavailable(cluster.backends[1].url()).await.unwrap();
+    assert_eq!(served_by(&cluster, 10).await, HashMap::from([("backend-0".to_string(), 10)]));
+}
+
+// The probes follow the requests in flight and the latency of the backend
+#[tokio::test]
+async fn test_probes_track_the_load_of_the_backends() {
+    let cluster = TestCluster::spawn(&[&["--latency", "fixed:300", "--capacity", "workers:1"]], builder(Policy::Prequal))
+        .await
+        .unwrap();
+    let backend = &cluster.backends[0];
+    let requests = (0..3)
+        .map(|i| {
+            let backend = backend.url().to_string();
+            tokio::spawn(async move {
+                let mut client = backend_sim::hello_world::greeter_client::GreeterClient::connect(backend).await.unwrap();
+                client.say_hello(HelloRequest { name: format!("direct {i}") }).await.unwrap();
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let cluster_state = cluster.load_balancer.cluster();
+    let probe = |check: fn(u32, u64) -> bool| {
+        let cluster_state = cluster_state.clone();
+        let url = backend.url().to_string();
+        move || {
+            let cluster_state = cluster_state.clone();
+            let url = url.clone();
+            async move {
+                let load_balancer = cluster_state.lock().await;
+                load_balancer
+                    .probe_pool
+                    .get(&url)
+                    .is_some_and(|probe| check(probe.rif, probe.latency))
+            }
+        }
+    };
+    wait_until("the requests in flight to be probed", probe(|rif, _| rif == 3))
+        .await
+        .unwrap();
+    for request in requests {
+        request.await.unwrap();
+    }
+    // Served one after the other, the median waited for the one before it
+    wait_until("the idle backend to be probed", probe(|rif, latency| rif == 0 && latency >= 600_000_000))
+        .await
+        .unwrap();
+}
+
+// Building the load balancer runs the first probing round, before anything is served
+#[tokio::test]
+async fn test_wait_for_the_first_probe() {
+    let backends = [TestBackend::spawn(&[]).await.unwrap(), TestBackend::spawn(&[]).await.unwrap()];
+    let handle = builder(Policy::Prequal)
+        .backends(backends.iter().map(|backend| backend.url().to_string()))
+        .probe_pool_size(2)
+        .listen_addr("127.0.0.1:0".parse().unwrap())
+        .wait_for_first_probe(true)
+        .build()
+        .await
+        .unwrap();
+    let load_balancer = handle.router().clusters[DEFAULT_CLUSTER].lock().await;
+    assert!(backends.iter().all(|backend| load_balancer.probe_pool.get(backend.url()).is_some()));
+}
+
+// An upstream load balancer probes the load balancers of two zones like backends and prefers the faster zone
+#[tokio::test]
+async fn test_two_tier_load_balancing() {
+    let fast = TestCluster::spawn(
+        &[&["--latency", "fixed:1", "--id", "fast", "--load-report-trailers"]],
+        builder(Policy::Prequal),
+    )
+    .await
+    .unwrap();
+    let slow = TestCluster::spawn(&[&["--latency", "fixed:200", "--id", "slow"]], builder(Policy::Prequal))
+        .await
+        .unwrap();
+    let upstream = TestLoadBalancer::spawn(
+        builder(Policy::Prequal)
+            .backends([fast.load_balancer.url(), slow.load_balancer.url()])
+            .probe_pool_size(2),
+    )
+    .await
+    .unwrap();
+
+    // The load report of the backend stops at the load balancer of its zone
+    let response = fast.load_balancer.client().await.unwrap().say_hello(HelloRequest::default()).await.unwrap();
+    assert!(response.metadata().get(RIF_TRAILER).is_none());
+
+    // The requests in flight in the slow zone are its RIF and, once probed, the RIF of its fleet
+    let slow_client = slow.load_balancer.client().await.unwrap();
+    let in_flight = (0..4)
+        .map(|_| {
+            let mut client = slow_client.clone();
+            tokio::spawn(async move { client.say_hello(HelloRequest::default()).await.unwrap() })
+        })
+        .collect::<Vec<_>>();
+    wait_until("the zone to report its requests in flight", || {
+        let mut client = slow_client.clone();
+        async move {
+            let metric = client.get_metrics(Empty {}).await.unwrap().into_inner();
+            metric.rif == 4 && metric.fleet_rif == 4
+        }
+    })
+    .await
+    .unwrap();
+    let upstream_state = upstream.cluster();
+    wait_until("the upstream to probe the loaded zone", || {
+        let upstream_state = upstream_state.clone();
+        async move { upstream_state.lock().await.probe_pool.max_rif == 4 }
+    })
+    .await
+    .unwrap();
+    for request in in_flight {
+        request.await.unwrap();
+    }
+
+    let zones = [fast.load_balancer.url(), slow.load_balancer.url()];
+    wait_until("probes of the idle zones with latencies", || {
+        let upstream_state = upstream_state.clone();
+        let zones = zones.clone();
+        async move {
+            let load_balancer = upstream_state.lock().await;
+            zones.iter().all(|zone| {
+                load_balancer
+                    .probe_pool
+                    .get(zone)
+                    .is_some_and(|probe| probe.rif == 0 && probe.latency > 0)
+            })
+        }
+    })
+    .await
+    .unwrap();
+    {
+        let load_balancer = upstream_state.lock().await;
+        let slow_probe = load_balancer.probe_pool.get(&zones[1]).unwrap();
+        assert!(slow_probe.latency >= 200_000_000, "{slow_probe:?}");
+    }
+    for i in 0..10 {
+        assert_eq!(upstream.say_hello(&format!("request {i}")).await.unwrap(), "fast");
+    }
+}
+
+// A backend too slow to answer its probes is taken as hot but stays in, the others get the requests
+#[tokio::test]
+async fn test_probe_timeout_marks_the_backend_hot() {
+    let cluster = TestCluster::spawn(
+        &[&["--latency", "fixed:1"], &["--latency", "fixed:1", "--metrics-delay-ms", "1000"]],
+        builder(Policy::Prequal)
+            .probe_pool_size(2)
+            .probe_timeout(Duration::from_millis(50)),
+    )
+    .await
+    .unwrap();
+    let (fast, stuck) = (cluster.backends[0].url().to_string(), cluster.backends[1].url().to_string());
+    let cluster_state = cluster.load_balancer.cluster();
+    wait_until("the probe to time out", || {
+        let cluster_state = cluster_state.clone();
+        let (fast, stuck) = (fast.clone(), stuck.clone());
+        async move {
+            let load_balancer = cluster_state.lock().await;
+            let answered = load_balancer.probe_pool.get(&fast).is_some_and(|probe| probe.rtt.is_some());
+            let timed_out = load_balancer.probe_pool.get(&stuck).is_some_and(|probe| probe.timed_out);
+            answered && timed_out
+        }
+    })
+    .await
+    .unwrap();
+    {
+        let mut load_balancer = cluster_state.lock().await;
+        let probe = load_balancer.probe_pool.get(&stuck).unwrap();
+        assert!(load_balancer.is_probe_hot(probe) && probe.rtt.is_none());
+        assert!(load_balancer.clients.iter().all(|client| client.is_available()));
+        // A high RIF seen before keeps the fast backend cold while it serves a request
+        load_balancer.probe_pool.max_rif = 10;
+    }
+
+    assert_eq!(served_by(&cluster, 10).await, HashMap::from([("backend-0".to_string(), 10)]));
+}
+
+// The balancer is not held while a probe is waited for, requests go through meanwhile
+#[tokio::test]
+async fn test_slow_probes_do_not_hold_up_requests() {
+    let cluster = TestCluster::spawn(
+        &[&["--latency", "fixed:1", "--metrics-delay-ms", "300"]],
+        builder(Policy::Prequal).probe_timeout(Duration::from_secs(1)),
+    )
+    .await
+    .unwrap();
+    cluster.load_balancer.wait_for_probes(&cluster.urls()).await.unwrap();
+    for i in 0..5 {
+        let sent_at = std::time::Instant::now();
+        cluster.say_hello(&format!("request {i}")).await.unwrap();
+        assert!(sent_at.elapsed() < Duration::from_millis(150), "{:?}", sent_at.elapsed());
+    }
+}
+
+// A caller going away in the middle of a client stream must not complete the stream on the backend
+#[tokio::test]
+async fn test_a_broken_client_stream_is_not_completed_on_the_backend() {
+    let cluster = TestCluster::spawn(&[&["--latency", "fixed:1"]], builder(Policy::RoundRobin)).await.unwrap();
+    // The caller connects through a relay, so its connection can be broken like when it crashes
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let relay_url = format!("http://{}", listener.local_addr().unwrap());
+    let load_balancer = cluster.load_balancer.handle().local_addr();
+    let relay = tokio::spawn(async move {
+        let (mut caller, _) = listener.accept().await.unwrap();
+        let mut upstream = tokio::net::TcpStream::connect(load_balancer).await.unwrap();
+        let _ = tokio::io::copy_bidirectional(&mut caller, &mut upstream).await;
+    });
+    let mut client = GreeterClient::connect(relay_url).await.unwrap();
+    let (requests, outbound) = tokio::sync::mpsc::channel(1);
+    requests.send(HelloRequest { name: "first".to_string() }).await.unwrap();
+    let call = tokio::spawn(async move { client.lots_of_greetings(ReceiverStream::new(outbound)).await });
+    let backend = cluster.backends[0].client().await.unwrap();
+    let rif = |expected: u32| {
+        let mut backend = backend.clone();
+        async move { backend.get_metrics(Empty {}).await.is_ok_and(|metric| metric.into_inner().rif == expected) }
+    };
+    wait_until("the stream to reach the backend", || rif(1)).await.unwrap();
+
+    // The sender stays open, only the call and its connection go away
+    call.abort();
+    relay.abort();
+    wait_until("the backend to give the stream up", || rif(0)).await.unwrap();
+    let metric = backend.clone().get_metrics(Empty {}).await.unwrap().into_inner();
+    assert!(!metric.method_latency.contains_key("LotsOfGreetings"), "{:?}", metric.method_latency);
+    drop(requests);
+}
+
+// Requests still in flight when the grace period runs out are cut off with their connection
+#[tokio::test]
+async fn test_requests_are_cut_off_after_the_grace_period() {
+    let builder = builder(Policy::RoundRobin).grace_period(Duration::from_millis(200));
+    let cluster = TestCluster::spawn(&[&["--latency", "fixed:10000"]], builder).await.unwrap();
+    let mut client = cluster.load_balancer.client().await.unwrap();
+    let call = tokio::spawn(async move { client.say_hello(HelloRequest { name: "slow".to_string() }).await });
+    let backend = cluster.backends[0].client().await.unwrap();
+    wait_until("the request to reach the backend", || {
+        let mut backend = backend.clone();
+        async move { backend.get_metrics(Empty {}).await.is_ok_and(|metric| metric.into_inner().rif == 1) }
+    })
+    .await
+    .unwrap();
+
+    let TestCluster { load_balancer, backends: _backends } = cluster;
+    let shutdown = tokio::time::timeout(Duration::from_secs(5), load_balancer.shutdown()).await.unwrap();
+    assert!(matches!(shutdown, Err(LoadBalancerError::GracePeriodElapsed)), "{shutdown:?}");
+    let reply = tokio::time::timeout(Duration::from_secs(5), call).await.unwrap().unwrap();
+    assert!(reply.is_err(), "{reply:?}");
+}
@@ -0,0 +1,371 @@
+/*!
+Runs a load balancer and backend-sim backends inside the test process, every one on a free port of 127.0.0.1.
+
+Servers are only handed out once they answer, so tests need no fixed ports and no sleeping,
+and they are torn down when their handle is dropped.
+
+```ignore
+let cluster = TestCluster::spawn(&[&["--latency", "fixed:1"], &["--latency", "fixed:50"]], LoadBalancerBuilder::new()).await?;
+cluster.load_balancer.wait_for_probes(&cluster.urls()).await?;
+let served_by = cluster.say_hello("world").await?;
+```
+*/
+#[cfg(test)]
+mod e2e;
+
+use backend_sim::hello_world::greeter_client::GreeterClient;
+use backend_sim::hello_world::{Empty, HelloRequest};
+use backend_sim::sim_control::sim_control_client::SimControlClient;
+use backend_sim::sim_control::CrashRequest;
+use clap::Parser;
+use load_balancer::routing::DEFAULT_CLUSTER;
+use load_balancer::{LoadBalancer, LoadBalancerBuilder, LoadBalancerError, LoadBalancerHandle};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::net::TcpListener;
+use tokio::runtime;
+use tokio::sync::{oneshot, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, Instant};
+use tonic::transport::{Channel, Endpoint};
+use tonic::Status;
+
+/// How long the harness waits for a server to answer or for a condition to hold
+pub const READY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Time between two checks of a condition
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Error, Debug)]
+pub enum HarnessError {
+    #[error("Invalid backend flags `{0}`")]
+    InvalidFlags(String),
+    #[error("Unable to start `{0}`")]
+    Start(String),
+    #[error("Timed out waiting for {0}")]
+    Timeout(String),
+}
+
+/**
+Polls the condition until it holds, for at most [`READY_TIMEOUT`]
+*/
+pub async fn wait_until<F, Fut>(what: &str, mut condition: F) -> Result<(), HarnessError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = bool>,
+{
+    let deadline = Instant::now() + READY_TIMEOUT;
+    while !condition().await {
+        if Instant::now() >= deadline {
+            return Err(HarnessError::Timeout(what.to_string()));
+        }
+        sleep(POLL_INTERVAL).await;
+    }
+    Ok(())
+}
+
+/**
+Connects to the server, retrying until it accepts the connection
+*/
+async fn connect(url: &str) -> Result<Channel, HarnessError> {
+    let endpoint = Endpoint::from_shared(url.to_string()).map_err(|error| HarnessError::Start(format!("{url}: {error}")))?;
+    let deadline = Instant::now() + READY_TIMEOUT;
+    loop {
+        match endpoint.connect().await {
+            Ok(channel) => return Ok(channel),
+            Err(_) if Instant::now() < deadline => sleep(POLL_INTERVAL).await,
+            Err(error) => return Err(HarnessError::Timeout(format!("{url} to accept connections: {error}"))),
+        }
+    }
+}
+
+/**
+A backend-sim serving on a runtime of its own, stopped when dropped.
+Stopping drops the runtime with every connection of the backend, as if its process went away.
+*/
+#[derive(Debug)]
+pub struct TestBackend {
+    id: String,
+    url: String,
+    stop: std::sync::Mutex<Option<oneshot::Sender<()>>>,
+}
+
+impl TestBackend {
+    /**
+    Starts a backend with the backend-sim command line flags and waits until it answers probes
+    */
+    pub async fn spawn(flags: &[&str]) -> Result<Self, HarnessError> {
+        let args = backend_sim::Args::try_parse_from(["backend-sim"].iter().chain(flags))
+            .map_err(|error| HarnessError::InvalidFlags(error.to_string()))?;
+        let start = |error: std::io::Error| HarnessError::Start(error.to_string());
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").map_err(start)?;
+        listener.set_nonblocking(true).map_err(start)?;
+        let addr = listener.local_addr().map_err(start)?;
+        let runtime = runtime::Builder::new_current_thread().enable_all().build().map_err(start)?;
+        let (stop_tx, stop_rx) = oneshot::channel::<()>();
+        let backend = Self {
+            id: args.id.clone(),
+            url: format!("http://{addr}"),
+            stop: std::sync::Mutex::new(Some(stop_tx)),
+        };
+        std::thread::spawn(move || {
+            runtime.block_on(async move {
+                let id = args.id.clone();
+                let serve = async move { backend_sim::serve(args, TcpListener::from_std(listener)?).await };
+                tokio::select! {
+                    Err(error) = serve => tracing::error!(%error, "The backend {} stopped", id),
+                    _ = stop_rx => {}
+                }
+            })
+        });
+        let client = backend.client().await?;
+        wait_until(&format!("{} to answer probes", backend.url), || {
+            let mut client = client.clone();
+            async move { client.get_metrics(Empty {}).await.is_ok() }
+        })
+        .await?;
+        Ok(backend)
+    }
+    /**
+    The id in its replies and probes
+    */
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+    pub async fn client(&self) -> Result<GreeterClient<Channel>, HarnessError> {
+        Ok(GreeterClient::new(connect(&self.url).await?))
+    }
+    /**
+    Client of the fault injection service of the backend
+    */
+    pub async fn control(&self) -> Result<SimControlClient<Channel>, HarnessError> {
+        Ok(SimControlClient::new(connect(&self.url).await?))
+    }
+    /**
+    Fails every request and probe and refuses new connections for the given time
+    */
+    pub async fn crash(&self, down: Duration) -> Result<(), Status> {
+        let mut control = self.control().await.map_err(|error| Status::unavailable(error.to_string()))?;
+        control
+            .crash(CrashRequest {
+                down_ms: down.as_millis() as u64,
+            })
+            .await
+            .map(|_| ())
+    }
+    /**
+    Stops serving, established connections break with it
+    */
+    pub fn stop(&self) {
+        if let Some(stop) = self.stop.lock().unwrap().take() {
+            let _ = stop.send(());
+        }
+    }
+}
+
+impl Drop for TestBackend {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/**
+A load balancer serving in a task of the test, shut down when dropped
+*/
+#[derive(Debug)]
+pub struct TestLoadBalancer {
+    handle: LoadBalancerHandle,
+    task: Option<JoinHandle<Result<(), LoadBalancerError>>>,
+}
+
+impl TestLoadBalancer {
+    /**
+    Builds the load balancer on a free port, serves it and waits until it accepts connections.
+    The backends are connected by then, probing starts with serving.
+    */
+    pub async fn spawn(builder: LoadBalancerBuilder) -> Result<Self, HarnessError> {
+        let handle = builder
+            .listen_addr(([127, 0, 0, 1], 0).into())
+            .build()
+            .await
+            .map_err(|error| HarnessError::Start(error.to_string()))?;
+        let task = tokio::spawn({
+            let handle = handle.clone();
+            async move { handle.serve().await }
+        });
+        let load_balancer = Self {
+            handle,
+            task: Some(task),
+        };
+        connect(&load_balancer.url()).await?;
+        Ok(load_balancer)
+    }
+    pub fn url(&self) -> String {
+        format!("http://{}", self.handle.local_addr())
+    }
+    pub fn handle(&self) -> &LoadBalancerHandle {
+        &self.handle
+    }
+    pub async fn client(&self) -> Result<GreeterClient<Channel>, HarnessError> {
+        Ok(GreeterClient::new(connect(&self.url()).await?))
+    }
+    /**
+    Sends a request through the load balancer and returns the id of the backend which served it
+    */
+    pub async fn say_hello(&self, name: &str) -> Result<String, Status> {
+        let mut client = self.client().await.map_err(|error| Status::unavailable(error.to_string()))?;
+        let reply = client
+            .say_hello(HelloRequest { name: name.to_string() })
+            .await?
+            .into_inner();
+        // backend-sim answers "Hello <name>! from <id>"
+        Ok(reply
+            .message
+            .rsplit_once(" from ")
+            .map(|(_, id)| id.to_string())
+            .unwrap_or(reply.message))
+    }
+    /**
+    The default cluster, with its backends and probe pool
+    */
+    pub fn cluster(&self) -> Arc<Mutex<LoadBalancer>> {
+        self.handle.router().clusters[DEFAULT_CLUSTER].clone()
+    }
+    /**
+    Waits until the probe pool of the default cluster holds a probe of every given backend
+    */
+    pub async fn wait_for_probes(&self, urls: &[&str]) -> Result<(), HarnessError> {
+        let cluster = self.cluster();
+        wait_until(&format!("probes of {urls:?}"), || {
+            let cluster = cluster.clone();
+            async move {
+                let load_balancer = cluster.lock().await;
+                urls.iter().all(|url| load_balancer.probe_pool.get(url).is_some())
+            }
+        })
+        .await
+    }
+    /**
+    Waits until the default cluster sends no new requests to the backend
+    */
+    pub async fn wait_for_unavailable(&self, url: &str) -> Result<(), HarnessError> {
+        let cluster = self.cluster();
+        wait_until(&format!("{url} to be taken out"), || {
+            let cluster = cluster.clone();
+            async move {
+                let load_balancer = cluster.lock().await;
+                load_balancer
+                    .clients
+                    .iter()
+                    .any(|client| client.client_add == url && !client.is_available())
+            }
+        })
+        .await
+    }
+    /**
+    Stops accepting connections and waits for the in-flight requests and the probing to end
+    */
+    pub async fn shutdown(mut self) -> Result<(), LoadBalancerError> {
+        self.handle.shutdown();
+        match self.task.take() {
+            Some(task) => task
+                .await
+                .map_err(|error| LoadBalancerError::ServerFailed(error.to_string()))?,
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for TestLoadBalancer {
+    fn drop(&mut self) {
+        self.handle.shutdown();
+        if let Some(task) = &self.task {
+            task.abort();
+        }
+    }
+}
+
+/**
+Backends and a load balancer in front of all of them
+*/
+#[derive(Debug)]
+pub struct TestCluster {
+    pub load_balancer: TestLoadBalancer,
+    pub backends: Vec<TestBackend>,
+}
+
+impl TestCluster {
+    /**
+    Starts a backend for every set of flags, named `backend-<index>` unless the flags give an `--id`,
+    then a load balancer configured by the builder over all of them
+    */
+    pub async fn spawn(backends: &[&[&str]], builder: LoadBalancerBuilder) -> Result<Self, HarnessError> {
+        let mut started = vec![];
+        for (index, flags) in backends.iter().enumerate() {
+            let id = format!("backend-{index}");
+            let mut flags = flags.to_vec();
+            if !flags.contains(&"--id") {
+                flags.extend(["--id", &id]);
+            }
+            started.push(TestBackend::spawn(&flags).await?);
+        }
+        let urls = started.iter().map(|backend| backend.url.clone()).collect::<Vec<String>>();
+        let load_balancer = TestLoadBalancer::spawn(builder.backends(urls)).await?;
+        Ok(Self {
+            load_balancer,
+            backends: started,
+        })
+    }
+    pub fn urls(&self) -> Vec<&str> {
+        self.backends.iter().map(TestBackend::url).collect()
+    }
+    /**
+    Sends a request through the load balancer and returns the id of the backend which served it
+    */
+    pub async fn say_hello(&self, name: &str) -> Result<String, Status> {
+        self.load_balancer.say_hello(name).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_backends_get_their_own_ports_and_stop_when_dropped() {
+        let first = TestBackend::spawn(&["--id", "first"]).await.unwrap();
+        let second = TestBackend::spawn(&[]).await.unwrap();
+        assert_ne!(first.url(), second.url());
+        assert_eq!((first.id(), second.id()), ("first", "server 1"));
+
+        let url = first.url().to_string();
+        drop(first);
+        let endpoint = Endpoint::from_shared(url).unwrap();
+        wait_until("the backend to stop", || {
+            let endpoint = endpoint.clone();
+            async move { endpoint.connect().await.is_err() }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_invalid_flags_are_reported() {
+        let error = TestBackend::spawn(&["--capacity", "lots"]).await.unwrap_err();
+        assert!(matches!(error, HarnessError::InvalidFlags(_)), "{error}");
+    }
+
+    #[tokio::test]
+    async fn test_cluster_names_its_backends() {
+        let cluster = TestCluster::spawn(&[&[], &["--id", "named"]], LoadBalancerBuilder::new()).await.unwrap();
+        let ids = cluster.backends.iter().map(TestBackend::id).collect::<Vec<&str>>();
+        assert_eq!(ids, ["backend-0", "named"]);
+        assert_eq!(cluster.load_balancer.cluster().lock().await.clients.len(), 2);
+        cluster.load_balancer.shutdown().await.unwrap();
+    }
+}
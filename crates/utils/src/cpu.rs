@@ -0,0 +1,73 @@
+use std::time::Instant;
+
+/// Kernel clock ticks per second, 100 on practically every Linux build
+const CLOCK_TICKS_PER_SEC: f64 = 100.0;
+
+/**
+Estimates the CPU utilization of this process between two samples, as a fraction of all cores.
+Reads /proc/self/stat so it only reports on Linux and returns 0 elsewhere.
+*/
+#[derive(Debug, Default)]
+pub struct CpuSampler {
+    last: Option<(Instant, u64)>,
+}
+
+impl CpuSampler {
+    pub fn sample(&mut self) -> f32 {
+        let Some(ticks) = process_cpu_ticks() else {
+            return 0.0;
+        };
+        let now = Instant::now();
+        let utilization = match self.last {
+            Some((last_at, last_ticks)) => {
+                let elapsed = now.duration_since(last_at).as_secs_f64();
+                let cores = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1) as f64;
+                if elapsed > 0.0 {
+                    (ticks.saturating_sub(last_ticks) as f64 / CLOCK_TICKS_PER_SEC / elapsed / cores).clamp(0.0, 1.0)
+                } else {
+                    0.0
+                }
+            }
+            None => 0.0,
+        };
+        self.last = Some((now, ticks));
+        utilization as f32
+    }
+}
+
+/**
+User plus system time of this process in clock ticks
+*/
+fn process_cpu_ticks() -> Option<u64> {
+    let stat = std::fs::read_to_string("/proc/self/stat").ok()?;
+    // The command name can contain spaces, the fields we need come after its closing parenthesis
+    let fields = stat.rsplit_once(')')?.1.split_whitespace().collect::<Vec<&str>>();
+    // utime and stime are fields 14 and 15 of the whole line, 12 and 13 after the command name
+    let utime = fields.get(11)?.parse::<u64>().ok()?;
+    let stime = fields.get(12)?.parse::<u64>().ok()?;
+    Some(utime + stime)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CpuSampler;
+
+    #[test]
+    fn test_first_sample_is_zero() {
+        assert_eq!(CpuSampler::default().sample(), 0.0);
+    }
+
+    #[test]
+    fn test_utilization_is_a_fraction() {
+        let mut sampler = CpuSampler::default();
+        sampler.sample();
+        // Burn a little CPU so there is something to measure
+        let mut sum = 0u64;
+        for i in 0..5_000_000u64 {
+            sum = sum.wrapping_add(i * i);
+        }
+        assert!(sum > 0);
+        let utilization = sampler.sample();
+        assert!((0.0..=1.0).contains(&utilization));
+    }
+}
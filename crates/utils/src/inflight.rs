@@ -0,0 +1,56 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+/**
+Counts a request in flight for as long as the guard is alive.
+The count goes down when the guard is dropped, so requests which are cancelled midway
+and streams which are held open are both counted correctly.
+*/
+#[derive(Debug)]
+pub struct InFlightGuard {
+    counter: Arc<AtomicU32>,
+}
+
+impl InFlightGuard {
+    pub fn new(counter: Arc<AtomicU32>) -> Self {
+        counter.fetch_add(1, Ordering::SeqCst);
+        Self { counter }
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.counter.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::InFlightGuard;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_counts_while_alive() {
+        let counter = Arc::new(AtomicU32::new(0));
+        let first = InFlightGuard::new(counter.clone());
+        let second = InFlightGuard::new(counter.clone());
+        assert_eq!(counter.load(Ordering::SeqCst), 2);
+        drop(first);
+        assert_eq!(counter.load(Ordering::SeqCst), 1);
+        drop(second);
+        assert_eq!(counter.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_released_on_unwind() {
+        let counter = Arc::new(AtomicU32::new(0));
+        let cloned = counter.clone();
+        let result = std::panic::catch_unwind(move || {
+            let _guard = InFlightGuard::new(cloned);
+            panic!("request failed midway");
+        });
+        assert!(result.is_err());
+        assert_eq!(counter.load(Ordering::SeqCst), 0);
+    }
+}
@@ -0,0 +1,144 @@
+use crate::medianfinder::MedianFinder;
+use std::collections::{HashMap, VecDeque};
+
+/**
+Keeps the median latency across all requests and per method
+*/
+#[derive(Debug, Default)]
+pub struct LatencyTracker {
+    overall: MedianFinder,
+    methods: HashMap<String, MedianFinder>,
+}
+
+impl LatencyTracker {
+    pub fn add_latency(&mut self, method: &str, latency: u128) {
+        self.overall.add_latency(latency);
+        self.methods.entry(method.to_string()).or_default().add_latency(latency);
+    }
+
+    pub fn find_median(&self) -> Option<u128> {
+        self.overall.find_median()
+    }
+
+    /**
+    Median latency of every method which has served at least one request
+    */
+    pub fn method_medians(&self) -> HashMap<String, u128> {
+        self.methods
+            .iter()
+            .filter_map(|(method, latencies)| Some((method.clone(), latencies.find_median()?)))
+            .collect()
+    }
+}
+
+/// Requests the medians of [`RecentLatencies`] are taken over unless another window is given
+pub const DEFAULT_WINDOW: usize = 1000;
+
+/**
+Keeps the median latency of the last `window` requests, across all requests and per method.
+Older latencies are forgotten, so the medians follow the current load and the memory stays bounded.
+*/
+#[derive(Debug)]
+pub struct RecentLatencies {
+    window: usize,
+    overall: VecDeque<u128>,
+    methods: HashMap<String, VecDeque<u128>>,
+}
+
+impl RecentLatencies {
+    pub fn new(window: usize) -> Self {
+        Self {
+            window,
+            overall: VecDeque::with_capacity(window),
+            methods: HashMap::new(),
+        }
+    }
+
+    pub fn add_latency(&mut self, method: &str, latency: u128) {
+        push_recent(&mut self.overall, self.window, latency);
+        push_recent(self.methods.entry(method.to_string()).or_default(), self.window, latency);
+    }
+
+    pub fn find_median(&self) -> Option<u128> {
+        median(&self.overall)
+    }
+
+    /**
+    Median latency of every method among its last `window` requests
+    */
+    pub fn method_medians(&self) -> HashMap<String, u128> {
+        self.methods
+            .iter()
+            .filter_map(|(method, latencies)| Some((method.clone(), median(latencies)?)))
+            .collect()
+    }
+}
+
+impl Default for RecentLatencies {
+    fn default() -> Self {
+        Self::new(DEFAULT_WINDOW)
+    }
+}
+
+fn push_recent(latencies: &mut VecDeque<u128>, window: usize, latency: u128) {
+    if latencies.len() == window {
+        latencies.pop_front();
+    }
+    latencies.push_back(latency);
+}
+
+/**
+The middle latency, or the mean of the two middle ones like [`MedianFinder`]
+*/
+fn median(latencies: &VecDeque<u128>) -> Option<u128> {
+    let mut sorted = latencies.iter().copied().collect::<Vec<u128>>();
+    sorted.sort_unstable();
+    let middle = sorted.len() / 2;
+    match sorted.len() {
+        0 => None,
+        len if len % 2 == 1 => Some(sorted[middle]),
+        _ => Some((sorted[middle - 1] + sorted[middle]) / 2),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LatencyTracker, RecentLatencies};
+
+    #[test]
+    fn test_empty_tracker() {
+        let tracker = LatencyTracker::default();
+        assert_eq!(tracker.find_median(), None);
+        assert!(tracker.method_medians().is_empty());
+    }
+
+    #[test]
+    fn test_overall_and_per_method_medians() {
+        let mut tracker = LatencyTracker::default();
+        tracker.add_latency("SayHello", 10);
+        tracker.add_latency("SayHello", 20);
+        tracker.add_latency("SayHello", 30);
+        tracker.add_latency("BidiHello", 100);
+        assert_eq!(tracker.find_median(), Some(25)); // (20 + 30) / 2
+        let medians = tracker.method_medians();
+        assert_eq!(medians["SayHello"], 20);
+        assert_eq!(medians["BidiHello"], 100);
+    }
+
+    #[test]
+    fn test_recent_latencies_forget_the_oldest() {
+        let mut latencies = RecentLatencies::new(3);
+        assert_eq!(latencies.find_median(), None);
+        for latency in [1000, 1000, 1000, 10, 20] {
+            latencies.add_latency("SayHello", latency);
+        }
+        latencies.add_latency("BidiHello", 30);
+        // Only 10, 20 and 30 are left overall, and 1000, 10 and 20 for SayHello
+        assert_eq!(latencies.find_median(), Some(20));
+        let medians = latencies.method_medians();
+        assert_eq!(medians["SayHello"], 20);
+        assert_eq!(medians["BidiHello"], 30);
+        latencies.add_latency("BidiHello", 50);
+        assert_eq!(latencies.method_medians()["BidiHello"], 40);
+    }
+}
@@ -1,3 +1,7 @@
+pub mod clock;
+pub mod cpu;
+pub mod inflight;
+pub mod latencies;
+pub mod loadreport;
 pub mod medianfinder;
 mod macros;
-
@@ -0,0 +1,103 @@
+use crate::latencies::LatencyTracker;
+use http::{HeaderMap, HeaderValue, Response};
+use http_body::{Body, Frame};
+use http_body_util::combinators::MapFrame;
+use http_body_util::BodyExt;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Trailer carrying the requests in flight on the server when the response finished
+pub const RIF_TRAILER: &str = "prequal-rif";
+/// Trailer carrying the median latency of the server in nanoseconds
+pub const LATENCY_TRAILER: &str = "prequal-latency";
+
+/// A response whose trailers get the load of the server, see [`LoadReporter::attach`]
+pub type ReportingResponse<B, F> = Response<MapFrame<B, F>>;
+/// A frame of the body `B`
+pub type BodyFrame<B> = Frame<<B as Body>::Data>;
+
+/**
+Attaches the current load of a server to the trailers of every response it sends,
+so the load balancer can refresh its probe of the server without sending a probe.
+Similar to ORCA load reports in gRPC.
+*/
+#[derive(Debug, Clone)]
+pub struct LoadReporter {
+    enabled: bool,
+    rif: Arc<AtomicU32>,
+    latencies: Arc<Mutex<LatencyTracker>>,
+}
+
+impl LoadReporter {
+    pub fn new(enabled: bool, rif: Arc<AtomicU32>, latencies: Arc<Mutex<LatencyTracker>>) -> Self {
+        Self {
+            enabled,
+            rif,
+            latencies,
+        }
+    }
+
+    /**
+    Wraps the response body so the load is read and written once the trailers are sent,
+    which is after the request itself stopped counting as in flight
+    */
+    pub fn attach<B: Body>(&self, response: Response<B>) -> ReportingResponse<B, impl FnMut(BodyFrame<B>) -> BodyFrame<B>> {
+        let reporter = self.clone();
+        response.map(|body| {
+            body.map_frame(move |mut frame| {
+                if let Some(trailers) = frame.trailers_mut() {
+                    reporter.write(trailers);
+                }
+                frame
+            })
+        })
+    }
+
+    fn write(&self, trailers: &mut HeaderMap) {
+        if !self.enabled {
+            return;
+        }
+        trailers.insert(RIF_TRAILER, HeaderValue::from(self.rif.load(Ordering::SeqCst)));
+        if let Some(latency) = self.latencies.lock().unwrap().find_median() {
+            trailers.insert(LATENCY_TRAILER, HeaderValue::from(latency as u64));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::StreamBody;
+    use std::convert::Infallible;
+
+    fn response() -> Response<impl Body<Data = &'static [u8], Error = Infallible>> {
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", HeaderValue::from(0));
+        let frames = vec![
+            Ok::<_, Infallible>(Frame::data(&b"reply"[..])),
+            Ok(Frame::trailers(trailers)),
+        ];
+        Response::new(StreamBody::new(tokio_stream::iter(frames)))
+    }
+
+    fn reporter(enabled: bool) -> LoadReporter {
+        let latencies = Arc::new(Mutex::new(LatencyTracker::default()));
+        latencies.lock().unwrap().add_latency("SayHello", 1_500);
+        LoadReporter::new(enabled, Arc::new(AtomicU32::new(3)), latencies)
+    }
+
+    #[tokio::test]
+    async fn test_load_is_added_to_trailers() {
+        let body = reporter(true).attach(response()).into_body().collect().await.unwrap();
+        let trailers = body.trailers().unwrap();
+        assert_eq!(trailers["grpc-status"], "0");
+        assert_eq!(trailers[RIF_TRAILER], "3");
+        assert_eq!(trailers[LATENCY_TRAILER], "1500");
+    }
+
+    #[tokio::test]
+    async fn test_disabled_reporter_leaves_trailers_alone() {
+        let body = reporter(false).attach(response()).into_body().collect().await.unwrap();
+        assert_eq!(body.trailers().unwrap().len(), 1);
+    }
+}
@@ -4,7 +4,7 @@ macro_rules! measure_time {
         let start = std::time::Instant::now();
         let result = { $block }; // Execute the block and capture the result
         let duration = start.elapsed();
-        println!("Execution time: {:?}", duration);
+        tracing::debug!("Execution time: {:?}", duration);
         (result, duration)
     }};
 }
\ No newline at end of file
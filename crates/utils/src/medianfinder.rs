@@ -8,7 +8,7 @@ pub struct MedianFinder {
 }
 
 impl MedianFinder {
-    fn new() -> Self {
+    pub fn new() -> Self {
         Self {
             max_heap: BinaryHeap::new(),
             min_heap: BinaryHeap::new(),